@@ -4,22 +4,46 @@
 //! These messages may be:
 //!   * updates containing new telemetry to advance state.
 //!   * queries for state information.
+//!   * queries for the track of the twin's locations.
+//!   * updates and queries of the twin's shadow.
+//!   * commands for the twin's device, their delivery and acks.
 //!   * queries for journal records.
 
 extern crate env_logger;
 extern crate log;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
 
+use chrono::Utc;
 use log::{debug, error};
 use riker::actors::*;
 
+use crate::au::command::{AuCommand, AuCommands, CommandConfig};
+use crate::au::geo::AuGeoIndex;
+use crate::au::load::AuLoad;
+use crate::au::metrics::{ACTORS, STATE_UPDATE};
+use crate::au::model::AuOperator;
 use crate::au::model::AuOperator::*;
-use crate::au::model::{AuMsg, AuState, AuTelemetry};
+use crate::au::model::{AuMsg, AuState, AuTelemetry, AuValue};
+use crate::au::shadow::AuShadow;
 use std::borrow::Borrow;
 
 pub struct AugieActor {
     state: AuState,
+    load: Arc<AuLoad>,
+    geo: Arc<AuGeoIndex>,
+    /// the latest locations of the twin, oldest first
+    track: VecDeque<AuTelemetry>,
+    /// the desired and reported properties of the twin
+    shadow: AuShadow,
+    /// the commands queued for the twin's device
+    commands: AuCommands,
+    /// the limits of the commands of the twin and its descendants
+    command_config: CommandConfig,
+    /// the actor's path below `/actor`, ie: `person/mary`
+    key: String,
 }
 
 impl AugieActor {
@@ -29,6 +53,7 @@ impl AugieActor {
         msg: AuMsg<Vec<AuTelemetry>>,
         sender: Sender,
     ) {
+        let op = msg.op.clone();
         let fmsg = AuMsg {
             path: msg.path.clone().split_off(1),
             ..msg
@@ -50,9 +75,9 @@ impl AugieActor {
                             ctx.myself.name(),
                             next_id
                         );
-                        match sel.try_tell(fmsg, sender) {
-                            Ok(_) => {}
-                            _ => error!("not sent"),
+                        if sel.try_tell(fmsg, sender).is_err() {
+                            error!("{} not sent to child {}", ctx.myself.name(), next_id);
+                            self.release(&op, &msg.path);
                         }
                     }
                     _ => {
@@ -61,9 +86,24 @@ impl AugieActor {
                             ctx.myself.name(),
                             next_id
                         );
-                        let props = AugieActor::props();
-                        let new_actor = ctx.actor_of(props, next_id).unwrap();
-                        new_actor.tell(fmsg, sender);
+                        let props = AugieActor::props(
+                            self.load.clone(),
+                            self.geo.clone(),
+                            self.command_config,
+                            format!("{}/{}", self.key, next_id),
+                        );
+                        match ctx.actor_of(props, next_id) {
+                            Ok(new_actor) => new_actor.tell(fmsg, sender),
+                            Err(e) => {
+                                error!(
+                                    "{} cannot create child {}: {:?}",
+                                    ctx.myself.name(),
+                                    next_id,
+                                    e
+                                );
+                                self.release(&op, &msg.path);
+                            }
+                        }
                     }
                 };
             }
@@ -71,6 +111,14 @@ impl AugieActor {
         }
     }
 
+    /// give back the slot a Tell to the descendant at `path` was admitted with once it cannot be
+    /// delivered
+    fn release(&self, op: &AuOperator, path: &[String]) {
+        if *op == Tell {
+            self.load.done(&format!("{}/{}", self.key, path.join("/")));
+        }
+    }
+
     fn report_children(&mut self, ctx: &Context<AuMsg<Vec<AuTelemetry>>>, sender: Sender) {
         let mut child_names: Vec<String> = Vec::new();
         for x in ctx.myself.borrow().children() {
@@ -111,17 +159,128 @@ impl AugieActor {
         }
     }
 
+    fn report_track(
+        &mut self,
+        ctx: &Context<AuMsg<Vec<AuTelemetry>>>,
+        msg: AuMsg<Vec<AuTelemetry>>,
+        sender: Sender,
+    ) {
+        let response = AuMsg {
+            data: Some(self.track.iter().cloned().collect()),
+            ..msg
+        };
+        let result = sender
+            .unwrap()
+            .try_tell(response, Some(ctx.myself().into()));
+        match result {
+            Ok(_) => debug!("{} sent track in reply to Track", ctx.myself.name()),
+            Err(_) => error!("track NOT sent"),
+        }
+    }
+
+    /// apply a shadow update and answer the shadow
+    fn report_shadow(
+        &mut self,
+        ctx: &Context<AuMsg<Vec<AuTelemetry>>>,
+        msg: AuMsg<Vec<AuTelemetry>>,
+        sender: Sender,
+    ) {
+        let records = msg.data.clone().unwrap_or_default();
+        match msg.op {
+            Desire => self.shadow.desire(&records),
+            Report => self.shadow.report(&records),
+            Ack => {
+                if let Some(version) = AuShadow::acked_version(&records) {
+                    self.shadow.ack(version);
+                }
+            }
+            _ => {}
+        }
+        let op = msg.op.clone();
+        let response = AuMsg {
+            data: Some(self.shadow.records()),
+            ..msg
+        };
+        let result = sender
+            .unwrap()
+            .try_tell(response, Some(ctx.myself().into()));
+        match result {
+            Ok(_) => debug!("{} sent shadow in reply to {}", ctx.myself.name(), op),
+            Err(_) => error!("shadow NOT sent"),
+        }
+    }
+
+    /// apply a command operation and answer the commands concerned
+    fn report_commands(
+        &mut self,
+        ctx: &Context<AuMsg<Vec<AuTelemetry>>>,
+        msg: AuMsg<Vec<AuTelemetry>>,
+        sender: Sender,
+    ) {
+        let given = AuCommand::from_records(msg.data.as_deref().unwrap_or_default());
+        let now = Utc::now();
+        let commands = match msg.op {
+            Command => given
+                .into_iter()
+                .filter_map(|c| self.commands.enqueue(c, now))
+                .collect(),
+            Poll => self.commands.poll(now),
+            Complete => given
+                .iter()
+                .filter_map(|c| self.commands.complete(c, now))
+                .collect(),
+            _ => self.commands.list(now),
+        };
+        let op = msg.op.clone();
+        let response = AuMsg {
+            data: Some(commands.iter().flat_map(AuCommand::records).collect()),
+            ..msg
+        };
+        let result = sender
+            .unwrap()
+            .try_tell(response, Some(ctx.myself().into()));
+        match result {
+            Ok(_) => debug!("{} sent commands in reply to {}", ctx.myself.name(), op),
+            Err(_) => error!("commands NOT sent"),
+        }
+    }
+
     fn update(&mut self, ctx: &Context<AuMsg<Vec<AuTelemetry>>>, msg: AuMsg<Vec<AuTelemetry>>) {
-        for t in msg.data.unwrap().iter() {
-            self.state.state.insert(t.name.clone(), t.clone());
+        let started = Instant::now();
+        for t in msg.data.unwrap_or_default().iter() {
+            self.state.state.insert(t.key(), t.clone());
+            if let AuValue::Location(_) = t.value {
+                self.geo.update(&self.key, t);
+                self.track.push_back(t.clone());
+                if self.track.len() > self.geo.track_points() {
+                    self.track.pop_front();
+                }
+            }
             debug!("{} updated state", ctx.myself.name());
         }
+        STATE_UPDATE
+            .with_label_values(&[self.root_type()])
+            .observe(started.elapsed().as_secs_f64());
+        self.load.done(&self.key);
+    }
+
+    /// the root type of the actor, ie: `person`
+    fn root_type(&self) -> &str {
+        self.key.split('/').next().unwrap_or_default()
     }
 }
 
 impl Actor for AugieActor {
     type Msg = AuMsg<Vec<AuTelemetry>>;
 
+    fn pre_start(&mut self, _ctx: &Context<AuMsg<Vec<AuTelemetry>>>) {
+        ACTORS.with_label_values(&[self.root_type()]).inc();
+    }
+
+    fn post_stop(&mut self) {
+        ACTORS.with_label_values(&[self.root_type()]).dec();
+    }
+
     fn recv(
         &mut self,
         ctx: &Context<AuMsg<Vec<AuTelemetry>>>,
@@ -139,20 +298,37 @@ impl Actor for AugieActor {
                 Ask => self.report_state(ctx, msg, sender),
                 Tell => self.update(ctx, msg),
                 Ls => self.report_children(ctx, sender),
+                Track => self.report_track(ctx, msg, sender),
+                Desire | Report | Ack | Shadow => self.report_shadow(ctx, msg, sender),
+                Command | Poll | Complete | Commands => self.report_commands(ctx, msg, sender),
             }
         }
     }
 }
 
 impl AugieActor {
-    fn actor() -> Self {
+    fn actor(
+        (load, geo, command_config, key): (Arc<AuLoad>, Arc<AuGeoIndex>, CommandConfig, String),
+    ) -> Self {
         AugieActor {
             state: AuState {
                 state: HashMap::new(),
             },
+            load,
+            geo,
+            track: VecDeque::new(),
+            shadow: AuShadow::default(),
+            commands: AuCommands::new(&command_config),
+            command_config,
+            key,
         }
     }
-    pub fn props() -> BoxActorProd<AugieActor> {
-        Props::new(AugieActor::actor)
+    pub fn props(
+        load: Arc<AuLoad>,
+        geo: Arc<AuGeoIndex>,
+        command_config: CommandConfig,
+        key: String,
+    ) -> BoxActorProd<AugieActor> {
+        Props::new_args(AugieActor::actor, (load, geo, command_config, key))
     }
 }
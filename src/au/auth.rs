@@ -0,0 +1,331 @@
+//! Bearer token authentication and per-path authorization of `/actor` requests.
+//!
+//! A request is authenticated by its `Authorization: Bearer <token>` header.  The token is either
+//! one of the static tokens listed in the config, mapped to a principal, or a JWT signed with the
+//! configured local key, naming a principal in its `sub` claim.  The principal is then authorized
+//! by any rule granting the requested operation on a prefix of the actor path.
+//!
+//! Authentication is disabled - every request is accepted - when the config lists neither tokens
+//! nor a jwt key.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use log::debug;
+use serde::Deserialize;
+use warp::http::Method;
+use warp::path::FullPath;
+use warp::{Filter, Rejection};
+
+use crate::au::tls::AuClientIdentity;
+
+/// The operations a rule may grant.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuPermission {
+    Tell,
+    Ask,
+    Ls,
+    Delete,
+    /// set the desired properties of a twin's shadow and enqueue commands for its device
+    Operate,
+}
+
+/// Grants a principal operations on every actor under a path prefix.
+#[derive(Clone, Deserialize)]
+pub struct AuRule {
+    /// principal the rule applies to, `*` for any authenticated principal
+    pub principal: String,
+    /// actor path prefix, ie: `/actor/person/*` - a `*` segment matches any single segment
+    pub path: String,
+    pub ops: Vec<AuPermission>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct JwtConfig {
+    /// signing algorithm, `HS256` by default
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Algorithm,
+    /// shared secret for the `HS*` algorithms
+    pub secret: Option<String>,
+    /// pem file holding the public key for the `RS*`, `PS*`, `ES*` and `EdDSA` algorithms
+    pub key_file: Option<String>,
+    /// required `iss` claim
+    pub issuer: Option<String>,
+    /// required `aud` claim
+    pub audience: Option<String>,
+}
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::HS256
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// static bearer tokens mapped to the principal they authenticate
+    pub tokens: HashMap<String, String>,
+    pub jwt: Option<JwtConfig>,
+    pub rules: Vec<AuRule>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuAuthError {
+    /// no or an unknown bearer token - 401
+    Unauthorized,
+    /// no rule grants the operation - 403
+    Forbidden,
+}
+
+impl warp::reject::Reject for AuAuthError {}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+pub struct AuAuth {
+    tokens: HashMap<String, String>,
+    jwt: Option<(DecodingKey, Validation)>,
+    rules: Vec<AuRule>,
+}
+
+impl AuAuth {
+    pub fn new(config: &AuthConfig) -> Result<AuAuth, String> {
+        let jwt = match &config.jwt {
+            Some(jwt) => Some(jwt_key(jwt)?),
+            None => None,
+        };
+        Ok(AuAuth {
+            tokens: config.tokens.clone(),
+            jwt,
+            rules: config.rules.clone(),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty() || self.jwt.is_some()
+    }
+
+    /// resolve the principal named by the value of an `Authorization` header
+    pub fn authenticate(&self, header: Option<&str>) -> Result<String, AuAuthError> {
+        let token = header
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or(AuAuthError::Unauthorized)?;
+        if let Some(principal) = self.tokens.get(token) {
+            return Ok(principal.clone());
+        }
+        match &self.jwt {
+            Some((key, validation)) => decode::<Claims>(token, key, validation)
+                .map(|t| t.claims.sub)
+                .map_err(|e| {
+                    debug!("jwt rejected: {}", e);
+                    AuAuthError::Unauthorized
+                }),
+            None => Err(AuAuthError::Unauthorized),
+        }
+    }
+
+    /// check that a rule grants the principal the operation on an actor path, ie: `[person, mary]`
+    pub fn authorize(
+        &self,
+        principal: &str,
+        perm: AuPermission,
+        path: &[String],
+    ) -> Result<(), AuAuthError> {
+        let granted = self.rules.iter().any(|r| {
+            (r.principal == "*" || r.principal == principal)
+                && r.ops.contains(&perm)
+                && prefix_matches(&r.path, path)
+        });
+        if granted {
+            Ok(())
+        } else {
+            debug!("{} denied {:?} on {:?}", principal, perm, path);
+            Err(AuAuthError::Forbidden)
+        }
+    }
+
+    /// authenticate and authorize a single request
+    pub fn check(
+        &self,
+        header: Option<&str>,
+        perm: AuPermission,
+        path: &[String],
+    ) -> Result<(), AuAuthError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let principal = self.authenticate(header)?;
+        self.authorize(&principal, perm, path)
+    }
+}
+
+fn jwt_key(config: &JwtConfig) -> Result<(DecodingKey, Validation), String> {
+    let key = match (&config.secret, &config.key_file) {
+        (Some(secret), _) => DecodingKey::from_secret(secret.as_bytes()),
+        (None, Some(file)) => {
+            let pem =
+                fs::read(file).map_err(|e| format!("can not read jwt key {}: {}", file, e))?;
+            match config.algorithm {
+                Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                _ => DecodingKey::from_rsa_pem(&pem),
+            }
+            .map_err(|e| format!("invalid jwt key {}: {}", file, e))?
+        }
+        (None, None) => return Err("jwt config needs a secret or a key_file".to_string()),
+    };
+    let mut validation = Validation::new(config.algorithm);
+    if let Some(issuer) = &config.issuer {
+        validation.set_issuer(&[issuer]);
+    }
+    if let Some(audience) = &config.audience {
+        validation.set_audience(&[audience]);
+    }
+    Ok((key, validation))
+}
+
+/// a rule path matches when each of its segments equals, or is a `*` for, the corresponding
+/// leading segment of the actor path
+pub(crate) fn prefix_matches(pattern: &str, path: &[String]) -> bool {
+    let mut segments: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.first() == Some(&"actor") {
+        segments.remove(0);
+    }
+    segments.len() <= path.len()
+        && segments
+            .iter()
+            .zip(path)
+            .all(|(p, s)| *p == "*" || p.eq_ignore_ascii_case(s))
+}
+
+/// map a request onto the operation it performs and the actor path it addresses
+fn request_permission(method: &Method, path: &str) -> Option<(AuPermission, Vec<String>)> {
+    let mut segments: Vec<String> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect();
+    if segments.first().map(String::as_str) != Some("actor") {
+        return None;
+    }
+    segments.remove(0);
+    let perm = match *method {
+        Method::POST => AuPermission::Tell,
+        Method::DELETE => AuPermission::Delete,
+        _ if segments.last().map(String::as_str) == Some("children") => {
+            segments.pop();
+            AuPermission::Ls
+        }
+        _ => AuPermission::Ask,
+    };
+    Some((perm, segments))
+}
+
+/// rejects `/actor` requests the principal behind the bearer token is not allowed to make
+pub fn guard(auth: Arc<AuAuth>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::ext::optional::<AuClientIdentity>())
+        .and_then(
+            move |method: Method,
+                  path: FullPath,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let auth = auth.clone();
+                async move {
+                    match request_permission(&method, path.as_str()) {
+                        // device Tells are authorized by their certificate binding
+                        Some((AuPermission::Tell, _)) if identity.is_some() => Ok(()),
+                        Some((perm, segments)) => auth
+                            .check(header.as_deref(), perm, &segments)
+                            .map_err(warp::reject::custom),
+                        None => Ok(()),
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::auth::*;
+    use crate::au::path;
+
+    fn auth() -> AuAuth {
+        let config: AuthConfig = serde_json::from_str(
+            r#"{
+                "tokens": {"t1": "ops", "t2": "device"},
+                "rules": [
+                    {"principal": "ops", "path": "/actor", "ops": ["ask", "ls"]},
+                    {"principal": "device", "path": "/actor/person/*", "ops": ["tell"]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        AuAuth::new(&config).unwrap()
+    }
+
+    #[test]
+    fn prefix_match_works() {
+        assert!(prefix_matches(
+            "/actor/person/*",
+            &path(&["person", "mary"])
+        ));
+        assert!(prefix_matches(
+            "/actor/person/*",
+            &path(&["person", "mary", "pet", "spot"])
+        ));
+        assert!(!prefix_matches("/actor/person/*", &path(&["person"])));
+        assert!(!prefix_matches("/actor/person/*", &path(&["pet", "spot"])));
+    }
+
+    #[test]
+    fn authenticate_works() {
+        let a = auth();
+        assert_eq!(a.authenticate(Some("Bearer t1")), Ok("ops".to_string()));
+        assert_eq!(
+            a.authenticate(Some("Bearer nope")),
+            Err(AuAuthError::Unauthorized)
+        );
+        assert_eq!(a.authenticate(None), Err(AuAuthError::Unauthorized));
+    }
+
+    #[test]
+    fn authorize_works() {
+        let a = auth();
+        let mary = path(&["person", "mary"]);
+        assert_eq!(
+            a.check(Some("Bearer t2"), AuPermission::Tell, &mary),
+            Ok(())
+        );
+        assert_eq!(
+            a.check(Some("Bearer t2"), AuPermission::Ask, &mary),
+            Err(AuAuthError::Forbidden)
+        );
+        assert_eq!(
+            a.check(Some("Bearer t1"), AuPermission::Tell, &mary),
+            Err(AuAuthError::Forbidden)
+        );
+        assert_eq!(a.check(Some("Bearer t1"), AuPermission::Ls, &mary), Ok(()));
+    }
+
+    #[test]
+    fn request_permission_works() {
+        assert_eq!(
+            request_permission(&Method::GET, "/actor/person/mary/children"),
+            Some((AuPermission::Ls, path(&["person", "mary"])))
+        );
+        assert_eq!(
+            request_permission(&Method::POST, "/actor/person/mary"),
+            Some((AuPermission::Tell, path(&["person", "mary"])))
+        );
+        assert_eq!(request_permission(&Method::GET, "/hiya/ahmed"), None);
+    }
+}
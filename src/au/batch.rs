@@ -0,0 +1,237 @@
+//! Telemetry for many twins in one request, ie: `POST /batch`.
+//!
+//! The body is a map of twin paths to their telemetry:
+//!
+//!   `{"/actor/device/p1": [...], "/actor/device/p2": [...]}`
+//!
+//! or a list of `{"path", "telemetry"}` entries.  Entries for the same twin are merged so each
+//! actor receives a single Tell, and the twins are Told root by root.  Every path is authorized and
+//! Told on its own - the reply carries the status of each path rather than failing the batch,
+//! unlike the bodies of the other formats, which are Told whole or not at all.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::auth::AuAuthError;
+use crate::au::body::AuAddressed;
+use crate::au::config::AuConfig;
+use crate::au::ingest::{authorize_path, credentials, twin_path, AuTellError};
+use crate::au::model::{AuTelemetry, AuTwin};
+use crate::au::tls::AuClientIdentity;
+use crate::au::twins::AuTwins;
+
+#[derive(Deserialize)]
+struct AuBatchEntry {
+    path: String,
+    telemetry: Vec<AuTelemetry>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AuBatch {
+    Map(BTreeMap<String, Vec<AuTelemetry>>),
+    List(Vec<AuBatchEntry>),
+}
+
+/// The outcome of one path of a batch.
+#[derive(Debug, Serialize)]
+pub struct AuPathResult {
+    pub path: String,
+    /// the status a POST of the path alone would have been answered with
+    pub status: u16,
+    /// telemetry Told to the twin
+    pub accepted: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuPathResult {
+    fn failed(path: String, status: StatusCode, error: String) -> AuPathResult {
+        AuPathResult {
+            path,
+            status: status.as_u16(),
+            accepted: 0,
+            error: Some(error),
+        }
+    }
+}
+
+/// the paths, as posted, and telemetry of a batch
+pub type AuEntries = Vec<(String, Vec<AuTelemetry>)>;
+
+impl AuAddressed for AuEntries {
+    /// the valid paths, the others are reported rather than Told
+    fn twin_paths(&self) -> Vec<Vec<String>> {
+        self.iter()
+            .filter_map(|(path, _)| twin_path(path))
+            .collect()
+    }
+
+    fn per_path(&self) -> bool {
+        true
+    }
+}
+
+/// the entries of a batch body
+pub fn entries(body: &[u8]) -> Result<AuEntries, String> {
+    let batch: AuBatch = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    Ok(match batch {
+        AuBatch::Map(map) => map.into_iter().collect(),
+        AuBatch::List(list) => list.into_iter().map(|e| (e.path, e.telemetry)).collect(),
+    })
+}
+
+/// authorize and Tell each twin of a batch once, reporting the outcome of every path
+pub fn dispatch<A, T>(entries: AuEntries, authorize: A, mut tell: T) -> Vec<AuPathResult>
+where
+    A: Fn(&[String]) -> Result<(), AuAuthError>,
+    T: FnMut(AuTwin) -> Result<String, AuTellError>,
+{
+    let mut results = Vec::new();
+    // ordered by path so the twins of a root are Told together
+    let mut twins: BTreeMap<Vec<String>, Vec<AuTelemetry>> = BTreeMap::new();
+    for (path, telemetry) in entries {
+        match twin_path(&path) {
+            Some(p) => twins.entry(p).or_default().extend(telemetry),
+            None => results.push(AuPathResult::failed(
+                path,
+                StatusCode::BAD_REQUEST,
+                "invalid twin path".to_string(),
+            )),
+        }
+    }
+    for (path, mut telemetry) in twins {
+        let name = format!("/actor/{}", path.join("/"));
+        if let Err(e) = authorize(&path) {
+            let status = match e {
+                AuAuthError::Unauthorized => StatusCode::UNAUTHORIZED,
+                AuAuthError::Forbidden => StatusCode::FORBIDDEN,
+            };
+            results.push(AuPathResult::failed(name, status, format!("{:?}", e)));
+            continue;
+        }
+        telemetry.sort_by_key(|t| t.datetime);
+        let accepted = telemetry.len();
+        match tell((path, telemetry)) {
+            Ok(_) => results.push(AuPathResult {
+                path: name,
+                status: StatusCode::ACCEPTED.as_u16(),
+                accepted,
+                error: None,
+            }),
+            Err(e) => {
+                let status = match e {
+                    AuTellError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+                    AuTellError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                    AuTellError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                results.push(AuPathResult::failed(name, status, e.to_string()))
+            }
+        }
+    }
+    results
+}
+
+/// `POST /batch`
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let twins = twins.clone();
+    warp::path("batch")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(crate::au::body::twins(
+            &twins,
+            crate::au::body::no_context(),
+            crate::au::compression::body(config.compression),
+            |_: &(), body: &[u8]| entries(body),
+        ))
+        .and(credentials())
+        .map(
+            move |_,
+                  entries: AuEntries,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let twins = &twins;
+                let results = dispatch(
+                    entries,
+                    |path| {
+                        authorize_path(
+                            &twins.auth,
+                            &twins.bindings,
+                            header.as_deref(),
+                            identity.as_ref(),
+                            path,
+                        )
+                    },
+                    |twin| twins.tell_twins(vec![twin]),
+                );
+                warp::reply::json(&results).into_response()
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::batch::*;
+
+    #[test]
+    fn entries_works() {
+        let telemetry = r#"[{"datetime": "2019-01-01T00:00:00Z", "name": "t", "value": 1}]"#;
+        let map = format!(
+            r#"{{"/actor/device/p1": {0}, "/actor/device/p2": {0}}}"#,
+            telemetry
+        );
+        assert_eq!(entries(map.as_bytes()).unwrap().len(), 2);
+        let list = format!(
+            r#"[{{"path": "/actor/device/p1", "telemetry": {}}}]"#,
+            telemetry
+        );
+        assert_eq!(entries(list.as_bytes()).unwrap()[0].0, "/actor/device/p1");
+        assert!(entries(b"[1]").is_err());
+    }
+
+    #[test]
+    fn dispatch_works() {
+        let telemetry = r#"[{"datetime": "2019-01-01T00:00:00Z", "name": "t", "value": 1}]"#;
+        let body = format!(
+            r#"[{{"path": "/actor/device/p1", "telemetry": {0}}},
+                {{"path": "/actor/DEVICE/p1", "telemetry": {0}}},
+                {{"path": "/actor/secret/s1", "telemetry": {0}}},
+                {{"path": "/actor/device", "telemetry": {0}}}]"#,
+            telemetry
+        );
+        let mut told = Vec::new();
+        let results = dispatch(
+            entries(body.as_bytes()).unwrap(),
+            |path| match path[0].as_str() {
+                "secret" => Err(AuAuthError::Forbidden),
+                _ => Ok(()),
+            },
+            |twin| {
+                told.push(twin);
+                Ok("Accepted".to_string())
+            },
+        );
+        assert_eq!(told.len(), 1);
+        assert_eq!(told[0].1.len(), 2);
+        let statuses: Vec<(&str, u16)> = results
+            .iter()
+            .map(|r| (r.path.as_str(), r.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("/actor/device", 400),
+                ("/actor/device/p1", 202),
+                ("/actor/secret/s1", 403)
+            ]
+        );
+        assert_eq!(results[1].accepted, 2);
+    }
+}
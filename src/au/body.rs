@@ -0,0 +1,228 @@
+//! Decoding of posted telemetry.
+//!
+//! The body is read whole so its signature can be checked before any of it is Told - no telemetry
+//! reaches an actor unless the payload is verified.  A body posted to an `/actor` path is CBOR or
+//! MessagePack when its `Content-Type` says so and json otherwise.  The bodies of the other
+//! ingestion formats are decoded by their modules and verified against every twin they address.
+
+use std::sync::Arc;
+
+use warp::hyper::body::Bytes;
+use warp::{Filter, Rejection};
+
+use crate::au::compression::CompressionConfig;
+use crate::au::format::AuFormat;
+use crate::au::ingest::{authorize_path, credentials};
+use crate::au::model::{AuTelemetry, AuTwin};
+use crate::au::ratelimit::client;
+use crate::au::signing::{signature, AuSignature, AuSigning};
+use crate::au::tls::AuClientIdentity;
+use crate::au::twins::AuTwins;
+
+/// The body could not be parsed as telemetry.
+#[derive(Debug)]
+pub struct AuBodyError(pub String);
+
+impl warp::reject::Reject for AuBodyError {}
+
+/// Decoded telemetry and the twins it addresses, whose secrets its signature must satisfy.
+pub trait AuAddressed {
+    /// the actor paths of the twins, ie: `[device, pump-1]`
+    fn twin_paths(&self) -> Vec<Vec<String>>;
+
+    /// whether the twins are Told path by path, a path the client may not Tell reported rather
+    /// than refusing the request, ie: `/batch`
+    fn per_path(&self) -> bool {
+        false
+    }
+}
+
+impl AuAddressed for Vec<AuTwin> {
+    fn twin_paths(&self) -> Vec<Vec<String>> {
+        self.iter().map(|(path, _)| path.clone()).collect()
+    }
+}
+
+/// verify and parse a posted `Vec<AuTelemetry>`
+pub fn telemetry(
+    signing: Arc<AuSigning>,
+    compression: CompressionConfig,
+) -> impl Filter<Extract = (Vec<AuTelemetry>,), Error = Rejection> + Clone {
+    signature()
+        .and(warp::header::optional::<String>("content-type"))
+        .and(crate::au::compression::body(compression))
+        .and_then(
+            move |signature: AuSignature, content_type: Option<String>, body: Bytes| {
+                let signing = signing.clone();
+                async move {
+                    let twin: Vec<String> = signature
+                        .path
+                        .split('/')
+                        .filter(|s| !s.is_empty())
+                        .skip(1)
+                        .map(|s| s.to_string())
+                        .collect();
+                    signing
+                        .verify(&signature, &[twin], &body)
+                        .map_err(warp::reject::custom)?;
+                    AuFormat::from_content_type(content_type.as_deref())
+                        .decode::<Vec<AuTelemetry>>(&body)
+                        .map_err(|e| warp::reject::custom(AuBodyError(e)))
+                }
+            },
+        )
+}
+
+/// A posted body and its signature, verified once the twin it writes to is known, ie: the
+/// reported properties of `PUT /shadow/reported` and the outcome of `POST /commands/ack`.
+pub struct AuSigned {
+    pub signature: AuSignature,
+    pub content_type: Option<String>,
+    pub body: Bytes,
+}
+
+impl AuSigned {
+    /// verify the body against the secret of the twin at an actor path, ie: `[device, pump-1]`
+    pub fn verify(&self, signing: &AuSigning, path: &[String]) -> Result<(), Rejection> {
+        signing
+            .verify(&self.signature, &[path.to_vec()], &self.body)
+            .map_err(warp::reject::custom)
+    }
+
+    /// decode the body in the format of its `Content-Type`
+    pub fn decode<T: serde::de::DeserializeOwned>(&self) -> Result<T, String> {
+        AuFormat::from_content_type(self.content_type.as_deref()).decode::<T>(&self.body)
+    }
+}
+
+/// the signature, `Content-Type` and decoded body of a device's write to a twin
+pub fn signed(
+    compression: CompressionConfig,
+) -> impl Filter<Extract = (AuSigned,), Error = Rejection> + Clone {
+    signature()
+        .and(warp::header::optional::<String>("content-type"))
+        .and(crate::au::compression::body(compression))
+        .map(
+            |signature: AuSignature, content_type: Option<String>, body: Bytes| AuSigned {
+                signature,
+                content_type,
+                body,
+            },
+        )
+}
+
+/// the context of a body decoded without one
+pub fn no_context() -> impl Filter<Extract = ((),), Error = Rejection> + Clone {
+    warp::any().and_then(|| async { Ok::<_, Rejection>(()) })
+}
+
+/// read a body with `body`, decode it with what `context` extracts, and once the client is
+/// allowed to Tell the twins it addresses verify its signature against them and take their rate
+/// limit tokens - the ingestion of `/batch`, `/csv`, `/write` and the other formats.  a twin the
+/// client may not Tell neither uses up the nonce nor the tokens of its limit.
+pub fn twins<C, T, X, B, D>(
+    twins: &AuTwins,
+    context: X,
+    body: B,
+    decode: D,
+) -> impl Filter<Extract = (C, T), Error = Rejection> + Clone
+where
+    C: Send + 'static,
+    T: AuAddressed + Send + 'static,
+    X: Filter<Extract = (C,), Error = Rejection> + Clone + Send + Sync + 'static,
+    B: Filter<Extract = (Bytes,), Error = Rejection> + Clone + Send + Sync + 'static,
+    D: Fn(&C, &[u8]) -> Result<T, String> + Clone + Send + Sync + 'static,
+{
+    let signing = twins.signing.clone();
+    let limiter = twins.limiter.clone();
+    let auth = twins.auth.clone();
+    let bindings = twins.bindings.clone();
+    context
+        .and(signature())
+        .and(client(twins.auth.clone()))
+        .and(credentials())
+        .and(body)
+        .and_then(
+            move |context: C,
+                  signature: AuSignature,
+                  client: String,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>,
+                  body: Bytes| {
+                let result = decode(&context, &body)
+                    .map_err(|e| warp::reject::custom(AuBodyError(e)))
+                    .and_then(|decoded| {
+                        let mut paths = Vec::new();
+                        for path in decoded.twin_paths() {
+                            match authorize_path(
+                                &auth,
+                                &bindings,
+                                header.as_deref(),
+                                identity.as_ref(),
+                                &path,
+                            ) {
+                                Ok(()) => paths.push(path),
+                                Err(_) if decoded.per_path() => {}
+                                Err(e) => return Err(warp::reject::custom(e)),
+                            }
+                        }
+                        signing
+                            .verify(&signature, &paths, &body)
+                            .map_err(warp::reject::custom)?;
+                        limiter
+                            .check_all(&client, &paths)
+                            .map_err(warp::reject::custom)?;
+                        Ok((context, decoded))
+                    });
+                async move { result }
+            },
+        )
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use crate::au::body::*;
+    use crate::au::signing::{
+        sign, AuSecret, SigningConfig, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    };
+
+    #[tokio::test]
+    async fn signed_gzip_works() {
+        let signing = Arc::new(AuSigning::new(&SigningConfig {
+            secrets: vec![AuSecret {
+                path: "/actor/device".to_string(),
+                secret: "s".to_string(),
+            }],
+            ..Default::default()
+        }));
+        let filter = telemetry(signing, CompressionConfig::default());
+        let path = "/actor/device/p1";
+        let body = br#"[{"name": "temp", "value": 21.5}]"#;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let post = |nonce: &str, signature: String| {
+            warp::test::request()
+                .method("POST")
+                .path(path)
+                .header("content-encoding", "gzip")
+                .header(TIMESTAMP_HEADER, &timestamp)
+                .header(NONCE_HEADER, nonce)
+                .header(SIGNATURE_HEADER, signature)
+                .body(gzipped.clone())
+        };
+        // the signature covers the body as decoded
+        let signature = sign("s", &timestamp, "n1", path, body);
+        let telemetry = post("n1", signature).filter(&filter).await.unwrap();
+        assert_eq!(telemetry[0].name, "temp");
+        let signature = sign("s", &timestamp, "n2", path, &gzipped);
+        assert!(post("n2", signature).filter(&filter).await.is_err());
+    }
+}
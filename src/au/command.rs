@@ -0,0 +1,833 @@
+//! Commands queued for the devices of twins - a reboot, a set-point change.
+//!
+//! Operators enqueue a command against a twin, `POST /commands?path=thermostat/t1`
+//! `{"name": "setpoint", "params": {"target": 21}}`, and its device fetches the pending commands
+//! by long-polling `GET /commands/pending?path=thermostat/t1&wait=30` or subscribing to the
+//! server-sent events of `GET /commands/events?path=thermostat/t1`.  Fetching delivers them.
+//! The device acknowledges each with its outcome and any result,
+//! `POST /commands/ack?path=thermostat/t1&id=1` `{"status": "succeeded", "result": {"took": 3}}`.
+//! For a twin with a secret the ack is signed like the device's telemetry, the query naming the
+//! twin and command included.
+//!
+//! A delivered command unacknowledged after `timeout_ms` is delivered again, up to `retries`
+//! times, and then timed out.  A twin holds at most `max_pending` unfinished commands, more are
+//! refused with a 429.  `GET /commands?path=thermostat/t1` Asks for the commands of a twin
+//! and their status, the latest finished ones kept.
+//!
+//! Actors exchange telemetry, so a command travels as a record of its name and status followed by
+//! the records of its params and result, all labelled with its id.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use chrono::{DateTime, Duration, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::time::error::Elapsed;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::body::AuSigned;
+use crate::au::compression::CompressionConfig;
+use crate::au::config::AuConfig;
+use crate::au::format::AuFormat;
+use crate::au::ingest::credentials;
+use crate::au::model::AuOperator::*;
+use crate::au::model::{AuOperator, AuTelemetry, AuValue};
+use crate::au::shadow::twin;
+use crate::au::tls::AuClientIdentity;
+use crate::au::twins::{twin_reply, AuTwins};
+
+/// A command or an ack is not understood, or names no command id - 400.
+#[derive(Debug)]
+pub struct AuBadCommand(pub String);
+
+impl warp::reject::Reject for AuBadCommand {}
+
+/// An ack names a command already finished - 409.
+#[derive(Debug)]
+pub struct AuCommandConflict(pub String);
+
+impl warp::reject::Reject for AuCommandConflict {}
+
+/// finished commands kept per twin, the oldest dropped first
+const FINISHED_KEPT: usize = 100;
+
+/// the shortest `poll_interval_ms` - long-polls and subscriptions ask the twin's actor this often
+const MIN_POLL_INTERVAL_MS: u64 = 10;
+
+/// the label naming the command of a record
+const COMMAND: &str = "command";
+/// the label naming the part of a command a record belongs to, `params` or `result`
+const PART: &str = "part";
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct CommandConfig {
+    /// milliseconds a delivered command waits for its ack before it is delivered again
+    pub timeout_ms: u64,
+    /// deliveries of a command after the first before it times out
+    pub retries: u32,
+    /// the longest timeout a command may ask for
+    pub max_timeout_ms: u64,
+    /// the most retries a command may ask for
+    pub max_retries: u32,
+    /// the longest a device may long-poll for pending commands
+    pub max_wait_ms: u64,
+    /// milliseconds between the checks for pending commands of a long-poll or subscription
+    pub poll_interval_ms: u64,
+    /// the most commands of a twin that are pending or delivered, awaiting their ack
+    pub max_pending: usize,
+}
+
+impl Default for CommandConfig {
+    fn default() -> Self {
+        CommandConfig {
+            timeout_ms: 30_000,
+            retries: 2,
+            max_timeout_ms: 3_600_000,
+            max_retries: 10,
+            max_wait_ms: 30_000,
+            poll_interval_ms: 250,
+            max_pending: 100,
+        }
+    }
+}
+
+impl CommandConfig {
+    /// refuse settings the commands can not run with
+    pub fn validate(&self) -> Result<(), String> {
+        if self.poll_interval_ms < MIN_POLL_INTERVAL_MS {
+            return Err(format!(
+                "invalid config: commands poll_interval_ms must be at least {}",
+                MIN_POLL_INTERVAL_MS
+            ));
+        }
+        if self.max_pending == 0 {
+            return Err("invalid config: commands max_pending must be above 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuCommandStatus {
+    /// waiting for the device to fetch it
+    Pending,
+    /// fetched and waiting for the device's ack
+    Delivered,
+    Succeeded,
+    Failed,
+    /// delivered more often than it may be without an ack
+    TimedOut,
+}
+
+impl AuCommandStatus {
+    pub fn finished(&self) -> bool {
+        matches!(
+            self,
+            AuCommandStatus::Succeeded | AuCommandStatus::Failed | AuCommandStatus::TimedOut
+        )
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuCommandStatus::Pending => "pending",
+            AuCommandStatus::Delivered => "delivered",
+            AuCommandStatus::Succeeded => "succeeded",
+            AuCommandStatus::Failed => "failed",
+            AuCommandStatus::TimedOut => "timedout",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<AuCommandStatus> {
+        match name {
+            "pending" => Some(AuCommandStatus::Pending),
+            "delivered" => Some(AuCommandStatus::Delivered),
+            "succeeded" => Some(AuCommandStatus::Succeeded),
+            "failed" => Some(AuCommandStatus::Failed),
+            "timedout" => Some(AuCommandStatus::TimedOut),
+            _ => None,
+        }
+    }
+}
+
+/// A command enqueued by an operator.
+#[derive(Deserialize)]
+pub struct AuCommandRequest {
+    pub name: String,
+    #[serde(default)]
+    pub params: BTreeMap<String, AuValue>,
+    pub timeout_ms: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+/// The outcome of a command acknowledged by a device.
+#[derive(Deserialize)]
+pub struct AuCommandAck {
+    pub status: AuCommandStatus,
+    #[serde(default)]
+    pub result: BTreeMap<String, AuValue>,
+}
+
+/// A command of a twin and its status.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuCommand {
+    /// numbered from 1 per twin
+    pub id: u64,
+    pub name: String,
+    pub params: BTreeMap<String, AuValue>,
+    pub status: AuCommandStatus,
+    /// the deliveries so far
+    pub attempts: u32,
+    pub retries: u32,
+    pub timeout_ms: u64,
+    pub created: DateTime<Utc>,
+    /// when the status last changed
+    pub updated: DateTime<Utc>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub result: BTreeMap<String, AuValue>,
+}
+
+impl AuCommand {
+    /// a pending command, numbered by the actor it is enqueued at.  its timeout and retries may
+    /// not exceed the configured maxima.
+    pub fn new(request: AuCommandRequest, config: &CommandConfig) -> Result<AuCommand, String> {
+        if request.name.is_empty() {
+            return Err("a command without a name".to_string());
+        }
+        let timeout_ms = request.timeout_ms.unwrap_or(config.timeout_ms);
+        if timeout_ms > config.max_timeout_ms {
+            return Err(format!(
+                "timeout_ms {} exceeds {}",
+                timeout_ms, config.max_timeout_ms
+            ));
+        }
+        let retries = request.retries.unwrap_or(config.retries);
+        if retries > config.max_retries {
+            return Err(format!(
+                "retries {} exceeds {}",
+                retries, config.max_retries
+            ));
+        }
+        let now = Utc::now();
+        Ok(AuCommand {
+            id: 0,
+            name: request.name,
+            params: request.params,
+            status: AuCommandStatus::Pending,
+            attempts: 0,
+            retries,
+            timeout_ms,
+            created: now,
+            updated: now,
+            result: BTreeMap::new(),
+        })
+    }
+
+    /// the ack of a command by its device, ie: a record of status `succeeded` and its result
+    pub fn ack(id: u64, ack: AuCommandAck) -> Result<AuCommand, String> {
+        if !ack.status.finished() {
+            return Err(format!(
+                "a command is not acknowledged {}",
+                ack.status.name()
+            ));
+        }
+        let now = Utc::now();
+        Ok(AuCommand {
+            id,
+            name: String::new(),
+            params: BTreeMap::new(),
+            status: ack.status,
+            attempts: 0,
+            retries: 0,
+            timeout_ms: 0,
+            created: now,
+            updated: now,
+            result: ack.result,
+        })
+    }
+
+    /// the command as records labelled with its id, its name and status first
+    pub fn records(&self) -> Vec<AuTelemetry> {
+        let label = |part: Option<&str>| -> BTreeMap<String, String> {
+            let mut labels = BTreeMap::new();
+            labels.insert(COMMAND.to_string(), self.id.to_string());
+            if let Some(part) = part {
+                labels.insert(PART.to_string(), part.to_string());
+            }
+            labels
+        };
+        let mut header = label(None);
+        header.insert("attempts".to_string(), self.attempts.to_string());
+        header.insert("retries".to_string(), self.retries.to_string());
+        header.insert("timeout_ms".to_string(), self.timeout_ms.to_string());
+        header.insert("updated".to_string(), self.updated.to_rfc3339());
+        let mut records = vec![AuTelemetry {
+            datetime: self.created,
+            name: self.name.clone(),
+            value: AuValue::Enum(self.status.name().to_string()),
+            labels: header,
+            ..Default::default()
+        }];
+        for (part, values) in [("params", &self.params), ("result", &self.result)] {
+            records.extend(values.iter().map(|(name, value)| AuTelemetry {
+                datetime: self.created,
+                name: name.clone(),
+                value: value.clone(),
+                labels: label(Some(part)),
+                ..Default::default()
+            }));
+        }
+        records
+    }
+
+    /// the commands of the records an actor exchanged, in order
+    pub fn from_records(records: &[AuTelemetry]) -> Vec<AuCommand> {
+        let mut commands: Vec<AuCommand> = Vec::new();
+        for t in records {
+            let id = match t.labels.get(COMMAND).and_then(|id| id.parse().ok()) {
+                Some(id) => id,
+                None => continue,
+            };
+            let label = |name: &str| t.labels.get(name).and_then(|v| v.parse::<u64>().ok());
+            match t.labels.get(PART).map(String::as_str) {
+                None => {
+                    let status = match &t.value {
+                        AuValue::Enum(status) => AuCommandStatus::from_name(status),
+                        _ => None,
+                    };
+                    let updated = t
+                        .labels
+                        .get("updated")
+                        .and_then(|u| DateTime::parse_from_rfc3339(u).ok())
+                        .map(|u| u.with_timezone(&Utc));
+                    commands.push(AuCommand {
+                        id,
+                        name: t.name.clone(),
+                        params: BTreeMap::new(),
+                        status: status.unwrap_or(AuCommandStatus::Pending),
+                        attempts: label("attempts").unwrap_or_default() as u32,
+                        retries: label("retries").unwrap_or_default() as u32,
+                        timeout_ms: label("timeout_ms").unwrap_or_default(),
+                        created: t.datetime,
+                        updated: updated.unwrap_or(t.datetime),
+                        result: BTreeMap::new(),
+                    });
+                }
+                Some(part) => {
+                    if let Some(command) = commands.iter_mut().rev().find(|c| c.id == id) {
+                        let values = match part {
+                            "params" => &mut command.params,
+                            _ => &mut command.result,
+                        };
+                        values.insert(t.name.clone(), t.value.clone());
+                    }
+                }
+            }
+        }
+        commands
+    }
+}
+
+/// The commands of a twin, oldest first.
+pub struct AuCommands {
+    last_id: u64,
+    max_pending: usize,
+    commands: VecDeque<AuCommand>,
+}
+
+impl AuCommands {
+    pub fn new(config: &CommandConfig) -> AuCommands {
+        AuCommands {
+            last_id: 0,
+            max_pending: config.max_pending,
+            commands: VecDeque::new(),
+        }
+    }
+
+    /// enqueue a command, numbering it, or `None` while the twin has `max_pending` unfinished
+    pub fn enqueue(&mut self, mut command: AuCommand, now: DateTime<Utc>) -> Option<AuCommand> {
+        self.expire(now);
+        let unfinished = self
+            .commands
+            .iter()
+            .filter(|c| !c.status.finished())
+            .count();
+        if unfinished >= self.max_pending {
+            return None;
+        }
+        self.last_id += 1;
+        command.id = self.last_id;
+        self.commands.push_back(command.clone());
+        Some(command)
+    }
+
+    /// deliver the pending commands
+    pub fn poll(&mut self, now: DateTime<Utc>) -> Vec<AuCommand> {
+        self.expire(now);
+        let mut delivered = Vec::new();
+        for command in self.commands.iter_mut() {
+            if command.status == AuCommandStatus::Pending {
+                command.status = AuCommandStatus::Delivered;
+                command.attempts += 1;
+                command.updated = now;
+                delivered.push(command.clone());
+            }
+        }
+        delivered
+    }
+
+    /// finish a command with the outcome of its ack, answering the command or `None` if there
+    /// is no such command.  a finished command keeps its outcome.
+    pub fn complete(&mut self, ack: &AuCommand, now: DateTime<Utc>) -> Option<AuCommand> {
+        self.expire(now);
+        let command = self.commands.iter_mut().find(|c| c.id == ack.id)?;
+        // an ack arriving once a delivery timed out still counts
+        if !command.status.finished() {
+            command.status = ack.status;
+            command.result = ack.result.clone();
+            command.updated = now;
+        }
+        let command = command.clone();
+        self.prune();
+        Some(command)
+    }
+
+    /// the commands and their status
+    pub fn list(&mut self, now: DateTime<Utc>) -> Vec<AuCommand> {
+        self.expire(now);
+        self.commands.iter().cloned().collect()
+    }
+
+    /// deliver again the commands unacknowledged in time, timing out those delivered too often
+    fn expire(&mut self, now: DateTime<Utc>) {
+        for command in self.commands.iter_mut() {
+            // a deadline beyond the calendar never passes
+            let deadline = i64::try_from(command.timeout_ms).ok().and_then(|ms| {
+                command
+                    .updated
+                    .checked_add_signed(Duration::milliseconds(ms))
+            });
+            let expired = deadline.is_some_and(|deadline| now >= deadline);
+            if command.status == AuCommandStatus::Delivered && expired {
+                command.status = if command.attempts > command.retries {
+                    AuCommandStatus::TimedOut
+                } else {
+                    AuCommandStatus::Pending
+                };
+                command.updated = now;
+            }
+        }
+        self.prune();
+    }
+
+    /// drop the oldest finished commands beyond those kept
+    fn prune(&mut self) {
+        let mut finished = self.commands.iter().filter(|c| c.status.finished()).count();
+        self.commands.retain(|c| {
+            let drop = finished > FINISHED_KEPT && c.status.finished();
+            if drop {
+                finished -= 1;
+            }
+            !drop
+        });
+    }
+}
+
+/// the command of a request body, json, CBOR or MessagePack
+pub fn request(
+    compression: CompressionConfig,
+    config: CommandConfig,
+) -> impl Filter<Extract = (AuCommand,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and(crate::au::compression::body(compression))
+        .and_then(
+            move |content_type: Option<String>, body: Bytes| async move {
+                AuFormat::from_content_type(content_type.as_deref())
+                    .decode::<AuCommandRequest>(&body)
+                    .and_then(|request| AuCommand::new(request, &config))
+                    .map_err(|e| warp::reject::custom(AuBadCommand(e)))
+            },
+        )
+}
+
+/// the command id of the `id` query parameter of an ack
+pub fn id() -> impl Filter<Extract = (u64,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().and_then(|query: HashMap<String, String>| async move {
+        let id = query.get("id").map(String::as_str).unwrap_or_default();
+        id.parse::<u64>()
+            .map_err(|_| warp::reject::custom(AuBadCommand(format!("invalid command id {:?}", id))))
+    })
+}
+
+/// the ack of a command, its body json, CBOR or MessagePack
+pub fn ack(id: u64, body: &AuSigned) -> Result<AuCommand, Rejection> {
+    body.decode::<AuCommandAck>()
+        .and_then(|ack| AuCommand::ack(id, ack))
+        .map_err(|e| warp::reject::custom(AuBadCommand(e)))
+}
+
+/// the milliseconds a long-poll waits, the `wait` query parameter in seconds
+pub fn wait(config: CommandConfig) -> impl Filter<Extract = (u64,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().and_then(
+        move |query: HashMap<String, String>| async move {
+            match query.get("wait") {
+                None => Ok(0),
+                Some(wait) => wait
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|w| *w >= 0.0)
+                    .map(|w| ((w * 1_000.0) as u64).min(config.max_wait_ms))
+                    .ok_or_else(|| {
+                        warp::reject::custom(AuBadCommand(format!("invalid wait {:?}", wait)))
+                    }),
+            }
+        },
+    )
+}
+
+/// apply a command operation once the client is allowed to and answer the commands concerned
+fn ask(
+    twins: &AuTwins,
+    path: Vec<String>,
+    op: AuOperator,
+    data: Option<Vec<AuTelemetry>>,
+    credentials: (Option<String>, Option<AuClientIdentity>),
+) -> Result<Result<Vec<AuCommand>, Elapsed>, Rejection> {
+    twins.authorize(&op, &path, &credentials)?;
+    let answer = twins.ask(path, op, data);
+    Ok(answer.map(|records| AuCommand::from_records(&records)))
+}
+
+/// deliver the pending commands of a twin, waiting up to `wait` for one to be enqueued
+async fn poll(
+    twins: &AuTwins,
+    path: Vec<String>,
+    credentials: (Option<String>, Option<AuClientIdentity>),
+    wait: std::time::Duration,
+    config: CommandConfig,
+) -> Result<Result<Vec<AuCommand>, Elapsed>, Rejection> {
+    twins.authorize(&Poll, &path, &credentials)?;
+    let deadline = tokio::time::Instant::now() + wait;
+    let interval = std::time::Duration::from_millis(config.poll_interval_ms);
+    loop {
+        let answer = twins.ask(path.clone(), Poll, None);
+        let now = tokio::time::Instant::now();
+        match answer.map(|records| AuCommand::from_records(&records)) {
+            Ok(commands) if commands.is_empty() && now < deadline => {
+                tokio::time::sleep(interval.min(deadline - now)).await;
+            }
+            answer => return Ok(answer),
+        }
+    }
+}
+
+/// the server-sent events of the pending commands of a twin as they are enqueued, until the
+/// device hangs up
+fn events(twins: AuTwins, path: Vec<String>, config: CommandConfig) -> Response {
+    let interval = std::time::Duration::from_millis(config.poll_interval_ms);
+    let commands = futures_util::stream::unfold((), move |_| {
+        let twins = twins.clone();
+        let path = path.clone();
+        async move {
+            loop {
+                if let Ok(records) = twins.ask(path.clone(), Poll, None) {
+                    if !records.is_empty() {
+                        return Some((AuCommand::from_records(&records), ()));
+                    }
+                }
+                tokio::time::sleep(interval).await;
+            }
+        }
+    });
+    let events = commands
+        .flat_map(futures_util::stream::iter)
+        .map(|command| {
+            warp::sse::Event::default()
+                .event("command")
+                .id(command.id.to_string())
+                .json_data(&command)
+        });
+    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+}
+
+/// `GET /commands`, `POST /commands`, `GET /commands/pending`, `GET /commands/events` and
+/// `POST /commands/ack`
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let compression = config.compression;
+    let config = config.commands;
+
+    let twins_list = twins.clone();
+    let list = warp::path("commands")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(twin())
+        .and(credentials())
+        .and_then(
+            move |path: Vec<String>, header: Option<String>, identity: Option<AuClientIdentity>| {
+                let result = ask(&twins_list, path, Commands, None, (header, identity));
+                async move { result }
+            },
+        )
+        .and(crate::au::format::accept())
+        .map(twin_reply);
+
+    let twins_enqueue = twins.clone();
+    let enqueue = warp::path("commands")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(twin())
+        .and(request(compression, config))
+        .and(credentials())
+        .and_then(
+            move |path: Vec<String>,
+                  command: AuCommand,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let records = Some(command.records());
+                let result = ask(&twins_enqueue, path, Command, records, (header, identity));
+                async move { result }
+            },
+        )
+        .and(crate::au::format::accept())
+        .map(
+            |result: Result<Vec<AuCommand>, Elapsed>, format: AuFormat| match result {
+                Ok(mut commands) if !commands.is_empty() => {
+                    let reply = format.reply(&commands.remove(0));
+                    warp::reply::with_status(reply, StatusCode::CREATED).into_response()
+                }
+                // the twin's actor enqueues nothing while it holds `max_pending` commands
+                Ok(_) => warp::reply::with_status(
+                    "Too many pending commands",
+                    StatusCode::TOO_MANY_REQUESTS,
+                )
+                .into_response(),
+                result => twin_reply(result, format),
+            },
+        );
+
+    let twins_pending = twins.clone();
+    let pending = warp::path("commands")
+        .and(warp::path("pending"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(twin())
+        .and(wait(config))
+        .and(credentials())
+        .and_then(
+            move |path: Vec<String>,
+                  wait: u64,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let twins = twins_pending.clone();
+                async move {
+                    let wait = std::time::Duration::from_millis(wait);
+                    poll(&twins, path, (header, identity), wait, config).await
+                }
+            },
+        )
+        .and(crate::au::format::accept())
+        .map(twin_reply);
+
+    let twins_events = twins.clone();
+    let subscribe = warp::path("commands")
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(twin())
+        .and(credentials())
+        .and_then(
+            move |path: Vec<String>, header: Option<String>, identity: Option<AuClientIdentity>| {
+                let twins = twins_events.clone();
+                let result = twins
+                    .authorize(&Poll, &path, &(header, identity))
+                    .map(|_| events(twins, path, config));
+                async move { result }
+            },
+        );
+
+    let twins_complete = twins.clone();
+    let complete = warp::path("commands")
+        .and(warp::path("ack"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(twin())
+        .and(id())
+        .and(crate::au::body::signed(compression))
+        .and(credentials())
+        .and_then(
+            move |path: Vec<String>,
+                  id: u64,
+                  body: AuSigned,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let twins = &twins_complete;
+                let result = twins
+                    .authorize(&Complete, &path, &(header, identity))
+                    // the outcome is the device's, signed like its telemetry
+                    .and_then(|_| body.verify(&twins.signing, &path))
+                    .and_then(|_| ack(id, &body))
+                    .map(|ack| {
+                        let answer = twins.ask(path, Complete, Some(ack.records()));
+                        (ack, answer.map(|records| AuCommand::from_records(&records)))
+                    })
+                    .and_then(|(ack, answer)| match answer {
+                        Ok(commands) => match commands.into_iter().next() {
+                            None => Err(warp::reject::not_found()),
+                            // a finished command keeps the outcome it was first acknowledged with
+                            Some(command) if command.status != ack.status => {
+                                Err(warp::reject::custom(AuCommandConflict(format!(
+                                    "command {} is {}",
+                                    command.id,
+                                    command.status.name()
+                                ))))
+                            }
+                            Some(command) => Ok(Ok(command)),
+                        },
+                        Err(e) => Ok(Err(e)),
+                    });
+                async move { result }
+            },
+        )
+        .and(crate::au::format::accept())
+        .map(twin_reply);
+
+    pending
+        .or(subscribe)
+        .unify()
+        .or(complete)
+        .unify()
+        .or(enqueue)
+        .unify()
+        .or(list)
+        .unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::command::*;
+
+    fn command(name: &str) -> AuCommand {
+        let request: AuCommandRequest = serde_json::from_str(&format!(
+            r#"{{"name": "{}", "params": {{"target": 21}}, "timeout_ms": 1000, "retries": 1}}"#,
+            name
+        ))
+        .unwrap();
+        AuCommand::new(request, &CommandConfig::default()).unwrap()
+    }
+
+    fn ack(id: u64, json: &str) -> AuCommand {
+        AuCommand::ack(id, serde_json::from_str(json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn queue_works() {
+        let mut commands = AuCommands::new(&CommandConfig::default());
+        let now = Utc::now();
+        assert_eq!(commands.enqueue(command("reboot"), now).unwrap().id, 1);
+        assert_eq!(commands.enqueue(command("setpoint"), now).unwrap().id, 2);
+        assert_eq!(commands.poll(now).len(), 2);
+        assert!(commands.poll(now).is_empty());
+        let done = commands
+            .complete(
+                &ack(2, r#"{"status": "succeeded", "result": {"took": 3}}"#),
+                now,
+            )
+            .unwrap();
+        assert_eq!(done.status, AuCommandStatus::Succeeded);
+        assert_eq!(done.result["took"], 3.0);
+        // a finished command keeps its outcome
+        let again = commands
+            .complete(&ack(2, r#"{"status": "failed"}"#), now)
+            .unwrap();
+        assert_eq!(again.status, AuCommandStatus::Succeeded);
+        assert!(commands
+            .complete(&ack(9, r#"{"status": "failed"}"#), now)
+            .is_none());
+        assert!(
+            AuCommand::ack(1, serde_json::from_str(r#"{"status": "pending"}"#).unwrap()).is_err()
+        );
+    }
+
+    #[test]
+    fn timeout_works() {
+        let mut commands = AuCommands::new(&CommandConfig::default());
+        let now = Utc::now();
+        commands.enqueue(command("reboot"), now);
+        assert_eq!(commands.poll(now)[0].attempts, 1);
+        // unacknowledged in time it is delivered again, once
+        let later = now + Duration::milliseconds(1_000);
+        assert_eq!(commands.poll(later)[0].attempts, 2);
+        let latest = later + Duration::milliseconds(1_000);
+        assert!(commands.poll(latest).is_empty());
+        assert_eq!(commands.list(latest)[0].status, AuCommandStatus::TimedOut);
+    }
+
+    #[test]
+    fn limits_work() {
+        let config = CommandConfig::default();
+        let request = |json: &str| serde_json::from_str::<AuCommandRequest>(json).unwrap();
+        assert!(AuCommand::new(
+            request(r#"{"name": "reboot", "timeout_ms": 18446744073709551615}"#),
+            &config
+        )
+        .is_err());
+        assert!(AuCommand::new(request(r#"{"name": "reboot", "retries": 11}"#), &config).is_err());
+        // a deadline out of range never expires the command
+        let mut commands = AuCommands::new(&CommandConfig::default());
+        let mut reboot = command("reboot");
+        reboot.timeout_ms = u64::MAX;
+        let now = Utc::now();
+        commands.enqueue(reboot, now);
+        commands.poll(now);
+        assert_eq!(commands.list(now)[0].status, AuCommandStatus::Delivered);
+    }
+
+    #[test]
+    fn max_pending_works() {
+        let config: CommandConfig = serde_json::from_str(r#"{"max_pending": 2}"#).unwrap();
+        let mut commands = AuCommands::new(&config);
+        let now = Utc::now();
+        assert!(commands.enqueue(command("reboot"), now).is_some());
+        commands.poll(now);
+        // a delivered command still awaits its ack
+        assert!(commands.enqueue(command("setpoint"), now).is_some());
+        assert!(commands.enqueue(command("setpoint"), now).is_none());
+        commands.complete(&ack(1, r#"{"status": "succeeded"}"#), now);
+        assert_eq!(commands.enqueue(command("setpoint"), now).unwrap().id, 3);
+    }
+
+    #[test]
+    fn validate_works() {
+        assert!(CommandConfig::default().validate().is_ok());
+        let config = |json: &str| serde_json::from_str::<CommandConfig>(json).unwrap();
+        assert!(config(r#"{"poll_interval_ms": 0}"#).validate().is_err());
+        assert!(config(r#"{"max_pending": 0}"#).validate().is_err());
+        assert!(
+            crate::au::config::AuConfig::from_json(r#"{"commands": {"poll_interval_ms": 0}}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn records_work() {
+        let mut commands = AuCommands::new(&CommandConfig::default());
+        let now = Utc::now();
+        commands.enqueue(command("reboot"), now);
+        commands.enqueue(command("setpoint"), now);
+        commands.poll(now);
+        commands.complete(
+            &ack(1, r#"{"status": "failed", "result": {"error": "busy"}}"#),
+            now,
+        );
+        let listed = commands.list(now);
+        let records: Vec<AuTelemetry> = listed.iter().flat_map(AuCommand::records).collect();
+        assert_eq!(AuCommand::from_records(&records), listed);
+    }
+}
@@ -0,0 +1,435 @@
+//! Compressed request and response bodies.
+//!
+//! Telemetry may be posted with a `Content-Encoding` of `gzip` or `zstd`.  Both the posted and the
+//! decoded body are bounded by `max_body_bytes` as they are read - a large upload, chunked or not,
+//! or a small payload inflating to gigabytes is refused with a 413 before it is held in memory.  A
+//! signed body is verified as decoded, so the signature covers the telemetry whatever the transport
+//! encoding.
+//!
+//! Replies of at least `min_response_bytes` are compressed with the encoding the `Accept-Encoding`
+//! header prefers, `zstd` before `gzip` when both are equally welcome.  Only replies of a known
+//! length are compressed - server-sent events and other streamed replies are passed on as they are
+//! produced rather than held back until they end.
+
+use std::io::Write;
+
+use flate2::write::{GzEncoder, MultiGzDecoder};
+use flate2::Compression;
+use futures_util::{pin_mut, Stream, StreamExt};
+use serde::Deserialize;
+use warp::http::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use warp::http::HeaderValue;
+use warp::hyper::body::{Buf, Bytes, HttpBody};
+use warp::hyper::Body;
+use warp::reply::Response;
+use warp::{Filter, Rejection};
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// largest decoded request body
+    pub max_body_bytes: usize,
+    /// smallest reply compressed
+    pub min_response_bytes: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            max_body_bytes: 16 * 1024 * 1024,
+            min_response_bytes: 1024,
+        }
+    }
+}
+
+/// A request body could not be decoded.
+#[derive(Debug, PartialEq)]
+pub enum AuEncodingError {
+    /// the `Content-Encoding` is not supported - 415
+    Unsupported(String),
+    /// the decoded body would exceed the bound - 413
+    TooLarge(usize),
+    /// the body is not in its encoding - 400
+    Invalid(String),
+}
+
+impl warp::reject::Reject for AuEncodingError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AuEncoding {
+    Gzip,
+    Zstd,
+}
+
+impl AuEncoding {
+    fn name(&self) -> &'static str {
+        match self {
+            AuEncoding::Gzip => "gzip",
+            AuEncoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Collects decoded bytes, refusing more than `limit` at a time.
+pub struct AuSink {
+    out: Vec<u8>,
+    limit: usize,
+    overflowed: bool,
+}
+
+impl Write for AuSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.out.len() + buf.len() > self.limit {
+            self.overflowed = true;
+            return Err(std::io::Error::other("decoded body too large"));
+        }
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decodes a body as its chunks arrive, holding at most `limit` decoded bytes per chunk.
+pub enum AuDecoder {
+    Identity,
+    Gzip(MultiGzDecoder<AuSink>),
+    Zstd(zstd::stream::write::Decoder<'static, AuSink>),
+}
+
+impl AuDecoder {
+    pub fn new(encoding: Option<&str>, limit: usize) -> Result<AuDecoder, AuEncodingError> {
+        let sink = AuSink {
+            out: Vec::new(),
+            limit,
+            overflowed: false,
+        };
+        match encoding.map(|e| e.trim().to_lowercase()).as_deref() {
+            None | Some("") | Some("identity") => Ok(AuDecoder::Identity),
+            Some("gzip") | Some("x-gzip") => Ok(AuDecoder::Gzip(MultiGzDecoder::new(sink))),
+            Some("zstd") => zstd::stream::write::Decoder::new(sink)
+                .map(AuDecoder::Zstd)
+                .map_err(|e| AuEncodingError::Invalid(e.to_string())),
+            Some(e) => Err(AuEncodingError::Unsupported(e.to_string())),
+        }
+    }
+
+    fn sink(&mut self) -> Option<&mut AuSink> {
+        match self {
+            AuDecoder::Identity => None,
+            AuDecoder::Gzip(d) => Some(d.get_mut()),
+            AuDecoder::Zstd(d) => Some(d.get_mut()),
+        }
+    }
+
+    /// the error of a write, too large when the sink refused it
+    fn error(&mut self, e: std::io::Error) -> AuEncodingError {
+        match self.sink() {
+            Some(sink) if sink.overflowed => AuEncodingError::TooLarge(sink.limit),
+            _ => AuEncodingError::Invalid(e.to_string()),
+        }
+    }
+
+    /// the bytes a chunk decodes to
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<u8>, AuEncodingError> {
+        let written = match self {
+            AuDecoder::Identity => return Ok(chunk.to_vec()),
+            AuDecoder::Gzip(d) => d.write_all(chunk),
+            AuDecoder::Zstd(d) => d.write_all(chunk),
+        };
+        written.map_err(|e| self.error(e))?;
+        Ok(self
+            .sink()
+            .map(|s| std::mem::take(&mut s.out))
+            .unwrap_or_default())
+    }
+
+    /// the last bytes of the body
+    pub fn finish(mut self) -> Result<Vec<u8>, AuEncodingError> {
+        let finished = match &mut self {
+            AuDecoder::Identity => return Ok(Vec::new()),
+            AuDecoder::Gzip(d) => d.try_finish(),
+            AuDecoder::Zstd(d) => d.flush(),
+        };
+        finished.map_err(|e| self.error(e))?;
+        Ok(self
+            .sink()
+            .map(|s| std::mem::take(&mut s.out))
+            .unwrap_or_default())
+    }
+}
+
+/// decode a whole body, refusing one decoding to more than `limit` bytes
+pub fn decode(encoding: Option<&str>, body: Bytes, limit: usize) -> Result<Bytes, AuEncodingError> {
+    let mut decoder = AuDecoder::new(encoding, limit)?;
+    if let AuDecoder::Identity = decoder {
+        return Ok(body);
+    }
+    let mut out = decoder.push(&body)?;
+    out.extend(decoder.finish()?);
+    if out.len() > limit {
+        return Err(AuEncodingError::TooLarge(limit));
+    }
+    Ok(Bytes::from(out))
+}
+
+/// read a body as it arrives, decoding it and refusing it as soon as more than `limit` bytes are
+/// posted or decoded - whether or not it announced its `Content-Length`
+pub async fn read<S, B>(
+    mut decoder: AuDecoder,
+    body: S,
+    limit: usize,
+) -> Result<Bytes, AuEncodingError>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    pin_mut!(body);
+    let mut posted = 0;
+    let mut out = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|e| AuEncodingError::Invalid(e.to_string()))?;
+        while chunk.has_remaining() {
+            let n = chunk.chunk().len();
+            posted += n;
+            if posted > limit {
+                return Err(AuEncodingError::TooLarge(limit));
+            }
+            out.extend(decoder.push(chunk.chunk())?);
+            chunk.advance(n);
+            if out.len() > limit {
+                return Err(AuEncodingError::TooLarge(limit));
+            }
+        }
+    }
+    out.extend(decoder.finish()?);
+    if out.len() > limit {
+        return Err(AuEncodingError::TooLarge(limit));
+    }
+    Ok(Bytes::from(out))
+}
+
+/// the body of a request as posted, refused once more than `limit` bytes arrive
+pub fn bounded(limit: usize) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::body::stream().and_then(move |body| async move {
+        read(AuDecoder::Identity, body, limit)
+            .await
+            .map_err(warp::reject::custom)
+    })
+}
+
+/// the decoded body of a request, refused once it is posted or decodes to more than
+/// `max_body_bytes`
+pub fn body(
+    config: CompressionConfig,
+) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-encoding")
+        .and(warp::body::stream())
+        .and_then(move |encoding: Option<String>, body| async move {
+            let decoder = AuDecoder::new(encoding.as_deref(), config.max_body_bytes)
+                .map_err(warp::reject::custom)?;
+            read(decoder, body, config.max_body_bytes)
+                .await
+                .map_err(warp::reject::custom)
+        })
+}
+
+/// the encoding an `Accept-Encoding` header prefers, `None` for identity
+fn negotiate(accept: &str) -> Option<AuEncoding> {
+    let mut named: Vec<(AuEncoding, f32)> = Vec::new();
+    let mut any: Option<f32> = None;
+    for coding in accept.split(',') {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim().to_lowercase();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match name.as_str() {
+            "zstd" => named.push((AuEncoding::Zstd, q)),
+            "gzip" | "x-gzip" => named.push((AuEncoding::Gzip, q)),
+            "*" => any = Some(q),
+            _ => {}
+        }
+    }
+    // `*` stands only for the codings not named - a `zstd;q=0` still refuses zstd
+    let weight = |encoding: AuEncoding| {
+        named
+            .iter()
+            .filter(|(e, _)| *e == encoding)
+            .map(|(_, q)| *q)
+            .reduce(f32::max)
+            .or(any)
+            .unwrap_or(0.0)
+    };
+    let (zstd, gzip) = (weight(AuEncoding::Zstd), weight(AuEncoding::Gzip));
+    if zstd > 0.0 && zstd >= gzip {
+        Some(AuEncoding::Zstd)
+    } else if gzip > 0.0 {
+        Some(AuEncoding::Gzip)
+    } else {
+        None
+    }
+}
+
+fn encode(encoding: AuEncoding, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        AuEncoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        AuEncoding::Zstd => zstd::stream::encode_all(body, 0),
+    }
+}
+
+/// whether a reply is a stream of server-sent events, which must reach the client event by event
+fn is_event_stream(response: &Response) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"))
+}
+
+/// compress a reply with the encoding the client prefers when it is worth it
+pub async fn compress(
+    config: CompressionConfig,
+    accept: Option<String>,
+    response: Response,
+) -> Response {
+    let encoding = match accept.as_deref().and_then(negotiate) {
+        Some(encoding) => encoding,
+        None => return response,
+    };
+    if response.headers().contains_key(CONTENT_ENCODING) || is_event_stream(&response) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    // a streamed reply is passed on as it is produced rather than held in memory
+    if body.size_hint().exact().is_none() {
+        return Response::from_parts(parts, body);
+    }
+    let bytes = match warp::hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::error!("can not read reply to compress: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    if bytes.len() < config.min_response_bytes {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+    match encode(encoding, &bytes) {
+        Ok(encoded) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            Response::from_parts(parts, Body::from(encoded))
+        }
+        Err(e) => {
+            log::error!("can not compress reply: {}", e);
+            Response::from_parts(parts, Body::from(bytes))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::compression::*;
+
+    #[test]
+    fn decode_works() {
+        let body = b"[{\"name\": \"t\"}]".repeat(100);
+        for encoding in [AuEncoding::Gzip, AuEncoding::Zstd] {
+            let encoded = Bytes::from(encode(encoding, &body).unwrap());
+            let decoded = decode(Some(encoding.name()), encoded.clone(), 10_000).unwrap();
+            assert_eq!(decoded.as_ref(), body.as_slice());
+            assert_eq!(
+                decode(Some(encoding.name()), encoded, 100),
+                Err(AuEncodingError::TooLarge(100))
+            );
+        }
+        assert_eq!(
+            decode(None, Bytes::from_static(b"x"), 0).unwrap().as_ref(),
+            b"x"
+        );
+        assert!(decode(Some("gzip"), Bytes::from_static(b"not gzip"), 100).is_err());
+        assert_eq!(
+            decode(Some("br"), Bytes::from_static(b"x"), 100),
+            Err(AuEncodingError::Unsupported("br".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn read_works() {
+        let chunks = |n: usize| {
+            futures_util::stream::iter(
+                (0..n).map(|_| Ok::<_, warp::Error>(Bytes::from_static(b"0123456789"))),
+            )
+        };
+        // a body without a Content-Length is bounded as it arrives
+        let body = read(AuDecoder::Identity, chunks(3), 30).await.unwrap();
+        assert_eq!(body.len(), 30);
+        assert_eq!(
+            read(AuDecoder::Identity, chunks(4), 30).await,
+            Err(AuEncodingError::TooLarge(30))
+        );
+        let encoded = encode(AuEncoding::Gzip, &b"x".repeat(100)).unwrap();
+        let gzipped = futures_util::stream::iter(vec![Ok::<_, warp::Error>(Bytes::from(encoded))]);
+        let decoder = AuDecoder::new(Some("gzip"), 50).unwrap();
+        assert_eq!(
+            read(decoder, gzipped, 50).await,
+            Err(AuEncodingError::TooLarge(50))
+        );
+    }
+
+    #[test]
+    fn stream_works() {
+        let body = b"line\n".repeat(1000);
+        let encoded = encode(AuEncoding::Gzip, &body).unwrap();
+        let mut decoder = AuDecoder::new(Some("gzip"), 10_000).unwrap();
+        let mut decoded = Vec::new();
+        for chunk in encoded.chunks(7) {
+            decoded.extend(decoder.push(chunk).unwrap());
+        }
+        decoded.extend(decoder.finish().unwrap());
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn negotiate_works() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(AuEncoding::Gzip));
+        assert_eq!(negotiate("gzip, zstd"), Some(AuEncoding::Zstd));
+        assert_eq!(negotiate("zstd;q=0.5, gzip"), Some(AuEncoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, identity"), None);
+        assert_eq!(negotiate("*"), Some(AuEncoding::Zstd));
+        assert_eq!(negotiate("zstd;q=0, *"), Some(AuEncoding::Gzip));
+        assert_eq!(negotiate("*, zstd;q=0"), Some(AuEncoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, zstd;q=0, *"), None);
+        assert_eq!(negotiate("*;q=0"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[tokio::test]
+    async fn compress_works() {
+        let config = CompressionConfig::default();
+        let accept = || Some("gzip".to_string());
+        let big = "x".repeat(config.min_response_bytes);
+        let reply = compress(config, accept(), Response::new(Body::from(big))).await;
+        assert_eq!(reply.headers()[CONTENT_ENCODING], "gzip");
+        let small = compress(config, accept(), Response::new(Body::from("x"))).await;
+        assert!(!small.headers().contains_key(CONTENT_ENCODING));
+        // a stream of unknown length is passed on untouched
+        let (_sender, streamed) = Body::channel();
+        let reply = compress(config, accept(), Response::new(streamed)).await;
+        assert!(!reply.headers().contains_key(CONTENT_ENCODING));
+        assert!(reply.into_body().size_hint().exact().is_none());
+    }
+}
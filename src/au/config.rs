@@ -0,0 +1,130 @@
+//! Server configuration.
+//!
+//! Configuration is read at startup from the json file named by the `AUGORAMA_CONFIG`
+//! environment variable.  Every section is optional - without a config file the server starts
+//! with the defaults of each section.
+
+use std::env;
+use std::fs;
+
+use log::info;
+use serde::Deserialize;
+
+use crate::au::auth::AuthConfig;
+use crate::au::command::CommandConfig;
+use crate::au::compression::CompressionConfig;
+use crate::au::csv::CsvConfig;
+use crate::au::geo::GeoConfig;
+use crate::au::influx::InfluxConfig;
+use crate::au::load::LoadConfig;
+use crate::au::metrics::MetricsConfig;
+use crate::au::mqtt::MqttConfig;
+use crate::au::ndjson::NdjsonConfig;
+use crate::au::otlp::OtlpConfig;
+use crate::au::ratelimit::RateLimitConfig;
+use crate::au::remotewrite::RemoteWriteConfig;
+use crate::au::schema::SchemaConfig;
+use crate::au::signing::SigningConfig;
+use crate::au::statsd::StatsdConfig;
+use crate::au::tls::TlsConfig;
+use crate::au::units::UnitsConfig;
+
+/// the environment variable holding the path of the config file
+pub const CONFIG_ENV: &str = "AUGORAMA_CONFIG";
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct AuConfig {
+    /// bearer tokens and the authorization policy applied to `/actor` requests
+    pub auth: AuthConfig,
+    /// serve https, optionally binding client certificates to the twins they may Tell
+    pub tls: Option<TlsConfig>,
+    /// shared secrets for HMAC signed telemetry
+    pub signing: SigningConfig,
+    /// request rates allowed per client and per twin
+    pub rate_limit: RateLimitConfig,
+    /// bounds of the Tells pending per actor and in the whole system
+    pub load: LoadConfig,
+    /// bounds of compressed request bodies and the replies worth compressing
+    pub compression: CompressionConfig,
+    /// the spatial index of twin locations and the tracks kept
+    pub geo: GeoConfig,
+    /// units of the telemetry of each twin type
+    pub units: UnitsConfig,
+    /// the telemetry each twin type may report and how violations are handled
+    pub schemas: SchemaConfig,
+    /// who may scrape `/metrics`
+    pub metrics: MetricsConfig,
+    /// how long commands wait for their acks and devices for their commands
+    pub commands: CommandConfig,
+    /// milliseconds an Ask or Ls waits for its answer before the request is answered with a 504
+    pub ask_timeout_ms: u64,
+    /// rules mapping Prometheus remote_write series onto twins
+    pub remote_write: RemoteWriteConfig,
+    /// templates mapping InfluxDB line protocol onto twins
+    pub influx: InfluxConfig,
+    /// rules mapping OpenTelemetry resources onto twins
+    pub otlp: OtlpConfig,
+    /// bounds of the streamed json lines posted to `/ingest`
+    pub ndjson: NdjsonConfig,
+    /// columns and datetime format of the csv uploaded to and downloaded from `/csv`
+    pub csv: CsvConfig,
+    /// bridge the topics of an MQTT broker onto twins
+    pub mqtt: Option<MqttConfig>,
+    /// listen for StatsD lines over udp
+    pub statsd: Option<StatsdConfig>,
+}
+
+impl Default for AuConfig {
+    fn default() -> Self {
+        AuConfig {
+            auth: AuthConfig::default(),
+            tls: None,
+            signing: SigningConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            load: LoadConfig::default(),
+            compression: CompressionConfig::default(),
+            geo: GeoConfig::default(),
+            units: UnitsConfig::default(),
+            schemas: SchemaConfig::default(),
+            metrics: MetricsConfig::default(),
+            commands: CommandConfig::default(),
+            ask_timeout_ms: 5_000,
+            remote_write: RemoteWriteConfig::default(),
+            influx: InfluxConfig::default(),
+            otlp: OtlpConfig::default(),
+            ndjson: NdjsonConfig::default(),
+            csv: CsvConfig::default(),
+            mqtt: None,
+            statsd: None,
+        }
+    }
+}
+
+impl AuConfig {
+    /// parse a config document, refusing settings the server can not run with
+    pub fn from_json(json: &str) -> Result<AuConfig, String> {
+        let config: AuConfig =
+            serde_json::from_str(json).map_err(|e| format!("invalid config: {}", e))?;
+        if let Some(statsd) = &config.statsd {
+            statsd.validate()?;
+        }
+        config.commands.validate()?;
+        config.rate_limit.validate()?;
+        config.geo.validate()?;
+        Ok(config)
+    }
+
+    /// read the config file named by `AUGORAMA_CONFIG` or return the defaults if it is not set
+    pub fn load() -> Result<AuConfig, String> {
+        match env::var(CONFIG_ENV) {
+            Ok(path) => {
+                info!("loading config from {}", path);
+                let json = fs::read_to_string(&path)
+                    .map_err(|e| format!("can not read config {}: {}", path, e))?;
+                AuConfig::from_json(&json)
+            }
+            Err(_) => Ok(AuConfig::default()),
+        }
+    }
+}
@@ -0,0 +1,422 @@
+//! CSV upload and download of telemetry, ie: `POST /csv` and `GET /csv?prefix=/actor/person`.
+//!
+//! A row is a twin path, a telemetry name, a value and an optional datetime, unit and labels - the
+//! header row names the columns, their names configurable for the spreadsheets of the site.
+//! Datetimes are read and written in the configured format: `rfc3339`, `unix`, `unix_ms` or a
+//! chrono `strftime` pattern, taken as UTC when it has no offset.  A row without a datetime is
+//! stamped with the time of upload.
+//! A value is a number, `true` or `false`, or else a string.  The labels are a json object, ie:
+//! `{"stage":"2"}`, and an empty unit or labels field is none.
+//!
+//! The download lists the current state of every twin at or below the prefix.  It cannot list their
+//! history - twins keep only the latest record of each name and label set.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use serde::Deserialize;
+use tokio::time::error::Elapsed;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::auth::AuPermission;
+use crate::au::config::AuConfig;
+use crate::au::export::prefix_path;
+use crate::au::ingest::{credentials, group, twin_path};
+use crate::au::model::{AuTelemetry, AuTwin, AuValue};
+use crate::au::tls::AuClientIdentity;
+use crate::au::twins::{tell_reply, timeout_reply, AuTwins};
+
+pub const CONTENT_TYPE: &str = "text/csv; charset=utf-8";
+
+/// The header of each column.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct AuCsvColumns {
+    pub path: String,
+    pub name: String,
+    pub value: String,
+    pub datetime: String,
+    pub unit: String,
+    pub labels: String,
+}
+
+impl Default for AuCsvColumns {
+    fn default() -> Self {
+        AuCsvColumns {
+            path: "path".to_string(),
+            name: "name".to_string(),
+            value: "value".to_string(),
+            datetime: "datetime".to_string(),
+            unit: "unit".to_string(),
+            labels: "labels".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct CsvConfig {
+    pub delimiter: char,
+    pub columns: AuCsvColumns,
+    /// `rfc3339`, `unix`, `unix_ms` or a `strftime` pattern
+    pub datetime_format: String,
+}
+
+impl Default for CsvConfig {
+    fn default() -> Self {
+        CsvConfig {
+            delimiter: ',',
+            columns: AuCsvColumns::default(),
+            datetime_format: "rfc3339".to_string(),
+        }
+    }
+}
+
+/// the records of a body, fields quoted as in RFC 4180
+fn records(body: &str, delimiter: char) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            _ if quoted => field.push(c),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            _ if c == delimiter => record.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    // blank lines hold no record
+    records.retain(|r| !(r.len() == 1 && r[0].trim().is_empty()));
+    Ok(records)
+}
+
+/// a field quoted when it holds the delimiter, a quote or a line break
+fn quote(field: &str, delimiter: char) -> String {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub struct AuCsv {
+    config: CsvConfig,
+}
+
+impl AuCsv {
+    pub fn new(config: &CsvConfig) -> AuCsv {
+        AuCsv {
+            config: config.clone(),
+        }
+    }
+
+    fn parse_datetime(&self, s: &str) -> Result<DateTime<Utc>, String> {
+        let invalid = || format!("invalid datetime {}", s);
+        match self.config.datetime_format.as_str() {
+            "rfc3339" => s.parse::<DateTime<Utc>>().map_err(|_| invalid()),
+            "unix" => Utc
+                .timestamp_opt(s.parse().map_err(|_| invalid())?, 0)
+                .single()
+                .ok_or_else(invalid),
+            "unix_ms" => Utc
+                .timestamp_millis_opt(s.parse().map_err(|_| invalid())?)
+                .single()
+                .ok_or_else(invalid),
+            format => DateTime::parse_from_str(s, format)
+                .map(|d| d.with_timezone(&Utc))
+                .or_else(|_| {
+                    NaiveDateTime::parse_from_str(s, format).map(|d| Utc.from_utc_datetime(&d))
+                })
+                .map_err(|_| invalid()),
+        }
+    }
+
+    fn format_datetime(&self, datetime: &DateTime<Utc>) -> String {
+        match self.config.datetime_format.as_str() {
+            "rfc3339" => datetime.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            "unix" => datetime.timestamp().to_string(),
+            "unix_ms" => datetime.timestamp_millis().to_string(),
+            format => datetime.format(format).to_string(),
+        }
+    }
+
+    /// the telemetry of the twins in an uploaded body
+    pub fn decode(&self, body: &str) -> Result<Vec<AuTwin>, String> {
+        let mut rows = records(body, self.config.delimiter)?.into_iter();
+        let header = rows.next().ok_or("no header row")?;
+        let column = |name: &str| header.iter().position(|h| h.trim() == name);
+        let columns = &self.config.columns;
+        let missing = |name: &str| format!("no {} column", name);
+        let path = column(&columns.path).ok_or_else(|| missing(&columns.path))?;
+        let name = column(&columns.name).ok_or_else(|| missing(&columns.name))?;
+        let value = column(&columns.value).ok_or_else(|| missing(&columns.value))?;
+        let datetime = column(&columns.datetime);
+        let unit = column(&columns.unit);
+        let labels = column(&columns.labels);
+        let now = Utc::now();
+        let mut telemetry = Vec::new();
+        // the header is the first row
+        for (n, row) in rows.enumerate().map(|(n, r)| (n + 2, r)) {
+            let field = |i: usize| row.get(i).map(|f| f.trim()).unwrap_or_default();
+            let twin = twin_path(field(path))
+                .ok_or_else(|| format!("row {}: invalid twin path {}", n, field(path)))?;
+            if field(name).is_empty() {
+                return Err(format!("row {}: no name", n));
+            }
+            if field(value).is_empty() {
+                return Err(format!("row {}: no value", n));
+            }
+            let v = AuValue::from_text(field(value));
+            let d = match datetime.map(field).filter(|d| !d.is_empty()) {
+                Some(d) => self
+                    .parse_datetime(d)
+                    .map_err(|e| format!("row {}: {}", n, e))?,
+                None => now,
+            };
+            let u = unit.map(field).filter(|u| !u.is_empty()).map(String::from);
+            let l = match labels.map(field).filter(|l| !l.is_empty()) {
+                Some(l) => serde_json::from_str::<BTreeMap<String, String>>(l)
+                    .map_err(|e| format!("row {}: invalid labels: {}", n, e))?,
+                None => BTreeMap::new(),
+            };
+            telemetry.push((
+                twin,
+                AuTelemetry {
+                    datetime: d,
+                    name: field(name).to_string(),
+                    value: v,
+                    unit: u,
+                    labels: l,
+                    violations: Vec::new(),
+                },
+            ));
+        }
+        Ok(group(telemetry))
+    }
+
+    /// a header row and a row for each telemetry of the twins
+    pub fn render(&self, twins: &[AuTwin]) -> String {
+        let d = self.config.delimiter;
+        let columns = &self.config.columns;
+        let row = |fields: [&str; 6]| {
+            let fields: Vec<String> = fields.iter().map(|f| quote(f, d)).collect();
+            fields.join(&d.to_string()) + "\r\n"
+        };
+        let mut out = row([
+            &columns.path,
+            &columns.name,
+            &columns.value,
+            &columns.datetime,
+            &columns.unit,
+            &columns.labels,
+        ]);
+        for (path, telemetry) in twins {
+            let path = format!("/actor/{}", path.join("/"));
+            let mut telemetry: Vec<&AuTelemetry> = telemetry.iter().collect();
+            telemetry.sort_by(|a, b| a.name.cmp(&b.name));
+            for t in telemetry {
+                let labels = match t.labels.is_empty() {
+                    true => String::new(),
+                    false => serde_json::to_string(&t.labels).unwrap_or_default(),
+                };
+                out.push_str(&row([
+                    &path,
+                    &t.name,
+                    &t.value.to_string(),
+                    &self.format_datetime(&t.datetime),
+                    t.unit.as_deref().unwrap_or_default(),
+                    &labels,
+                ]));
+            }
+        }
+        out
+    }
+}
+
+/// the reply to a csv download - the state of the twins or a 504 if an actor did not answer
+fn csv_reply(csv: &AuCsv, result: Result<Vec<AuTwin>, Elapsed>) -> Response {
+    match result {
+        Ok(twins) => warp::reply::with_header(csv.render(&twins), "Content-Type", CONTENT_TYPE)
+            .into_response(),
+        Err(_) => timeout_reply(),
+    }
+}
+
+/// `GET /csv?prefix=` and `POST /csv`
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let csv = Arc::new(AuCsv::new(&config.csv));
+
+    let twins_download = twins.clone();
+    let csv_download = csv.clone();
+    let download = warp::path("csv")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |query: HashMap<String, String>, header: Option<String>| {
+                let prefix = prefix_path(query.get("prefix"));
+                let result = twins_download
+                    .auth
+                    .check(header.as_deref(), AuPermission::Ask, &prefix)
+                    .map(|_| twins_download.export(prefix))
+                    .map_err(warp::reject::custom);
+                async move { result }
+            },
+        )
+        .map(move |result| csv_reply(&csv_download, result));
+
+    let twins_upload = twins.clone();
+    let upload = warp::path("csv")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(crate::au::body::twins(
+            twins,
+            crate::au::body::no_context(),
+            crate::au::compression::body(config.compression),
+            move |_: &(), body: &[u8]| {
+                std::str::from_utf8(body)
+                    .map_err(|e| e.to_string())
+                    .and_then(|body| csv.decode(body))
+            },
+        ))
+        .and(credentials())
+        .and_then(
+            move |_,
+                  decoded: Vec<AuTwin>,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let result = twins_upload.tell(decoded, header, identity);
+                async move { result }
+            },
+        )
+        .map(tell_reply);
+
+    download.or(upload).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::csv::*;
+
+    #[test]
+    fn records_work() {
+        let r = records("a,\"b,\"\"c\"\"\"\r\n\n\"d\ne\",f", ',').unwrap();
+        assert_eq!(
+            r,
+            vec![
+                vec!["a".to_string(), "b,\"c\"".to_string()],
+                vec!["d\ne".to_string(), "f".to_string()]
+            ]
+        );
+        assert!(records("\"a", ',').is_err());
+        assert_eq!(quote("b,\"c\"", ','), "\"b,\"\"c\"\"\"");
+    }
+
+    #[test]
+    fn decode_works() {
+        let config: CsvConfig = serde_json::from_str(
+            r#"{"delimiter": ";", "columns": {"path": "Twin", "datetime": "When"},
+                "datetime_format": "%d/%m/%Y %H:%M"}"#,
+        )
+        .unwrap();
+        let csv = AuCsv::new(&config);
+        let body = "Twin;name;value;When\n\
+                    /actor/device/p1;rpm;1200;02/01/2020 10:30\n\
+                    /actor/device/p1;temp;21.5;01/01/2020 10:30\n\
+                    /actor/device/p2;rpm;900;\n";
+        let twins = csv.decode(body).unwrap();
+        assert_eq!(twins.len(), 2);
+        assert_eq!(twins[0].1[0].name, "temp");
+        assert_eq!(
+            twins[0].1[1].datetime.to_rfc3339(),
+            "2020-01-02T10:30:00+00:00"
+        );
+        assert!(csv
+            .decode("Twin;name;value\n/actor/device;rpm;1\n")
+            .is_err());
+        assert!(csv
+            .decode("Twin;name;value\n/actor/device/p1;rpm;\n")
+            .is_err());
+        let twins = csv
+            .decode("Twin;name;value\n/actor/device/p1;on;true\n/actor/device/p1;fw;v1.2\n")
+            .unwrap();
+        assert_eq!(twins[0].1[0].value, AuValue::Boolean(true));
+        assert_eq!(twins[0].1[1].value, AuValue::String("v1.2".to_string()));
+        assert!(csv.decode("name;value\n").is_err());
+    }
+
+    #[test]
+    fn render_works() {
+        let csv = AuCsv::new(&CsvConfig::default());
+        let twins = vec![(
+            vec!["device".to_string(), "p1".to_string()],
+            vec![AuTelemetry {
+                datetime: Utc.timestamp_opt(0, 0).unwrap(),
+                name: "a,b".to_string(),
+                value: 1.5.into(),
+                ..Default::default()
+            }],
+        )];
+        assert_eq!(
+            csv.render(&twins),
+            "path,name,value,datetime,unit,labels\r\n\
+             /actor/device/p1,\"a,b\",1.5,1970-01-01T00:00:00Z,,\r\n"
+        );
+        let decoded = csv.decode(&csv.render(&twins)).unwrap();
+        assert_eq!(decoded[0].0, twins[0].0);
+        assert_eq!(decoded[0].1[0].name, "a,b");
+        assert_eq!(decoded[0].1[0].datetime, twins[0].1[0].datetime);
+        assert_eq!(decoded[0].1[0].unit, None);
+        assert!(decoded[0].1[0].labels.is_empty());
+    }
+
+    #[test]
+    fn unit_and_labels_work() {
+        let csv = AuCsv::new(&CsvConfig::default());
+        let mut labels = BTreeMap::new();
+        labels.insert("stage".to_string(), "2".to_string());
+        labels.insert("site".to_string(), "north, east".to_string());
+        let twins = vec![(
+            vec!["device".to_string(), "p1".to_string()],
+            vec![AuTelemetry {
+                datetime: Utc.timestamp_opt(0, 0).unwrap(),
+                name: "temp".to_string(),
+                value: 21.5.into(),
+                unit: Some("Cel".to_string()),
+                labels,
+                ..Default::default()
+            }],
+        )];
+        let decoded = csv.decode(&csv.render(&twins)).unwrap();
+        assert_eq!(decoded[0].1[0].unit, twins[0].1[0].unit);
+        assert_eq!(decoded[0].1[0].labels, twins[0].1[0].labels);
+        assert!(csv
+            .decode("path,name,value,labels\n/actor/device/p1,t,1,stage=2\n")
+            .is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! Twin state rendered as OpenMetrics for scraping, ie: `GET /export/metrics?prefix=/actor/person`.
+//!
+//! Every telemetry record of a twin becomes a gauge named for the record and labelled with the
+//! type/id pairs of the twin's path - `/actor/person/mary/phone/p1` is labelled
+//! `{person="mary",phone="p1"}`, followed by the record's own labels unless the path already
+//! uses their name.  Names and label names are reduced to the characters Prometheus allows.  A
+//! boolean is exported as 0 or 1, strings and enum states have no sample.
+
+use std::collections::{BTreeMap, HashMap};
+
+use tokio::time::error::Elapsed;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::auth::AuPermission;
+use crate::au::model::AuTwin;
+use crate::au::twins::{timeout_reply, AuTwins};
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// the actor path of a `prefix` query parameter, ie: `/actor/person` is `[person]`
+pub fn prefix_path(prefix: Option<&String>) -> Vec<String> {
+    let mut segments = prefix
+        .map(|p| p.as_str())
+        .unwrap_or_default()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .peekable();
+    if segments.peek() == Some(&"actor") {
+        segments.next();
+    }
+    segments.map(|s| s.to_lowercase()).collect()
+}
+
+/// a metric or label name - invalid characters replaced by `_`
+fn sanitize(name: &str) -> String {
+    let mut s: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    // names may not start with a digit and those starting with `__` are reserved
+    if s.is_empty() || s.starts_with(|c: char| c.is_ascii_digit()) || s.starts_with("__") {
+        s.insert_str(0, "au_");
+    }
+    s
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn labels(path: &[String], own: &BTreeMap<String, String>) -> String {
+    let mut names: Vec<String> = Vec::new();
+    let mut pairs: Vec<String> = Vec::new();
+    for (depth, pair) in path.chunks(2).enumerate().filter(|(_, p)| p.len() == 2) {
+        // a type repeated along the path is labelled with its depth
+        let mut name = sanitize(&pair[0]);
+        if names.contains(&name) {
+            name = format!("{}_{}", name, depth);
+        }
+        pairs.push(format!("{}=\"{}\"", name, escape(&pair[1])));
+        names.push(name);
+    }
+    for (name, value) in own.iter() {
+        let name = sanitize(name);
+        if !names.contains(&name) {
+            pairs.push(format!("{}=\"{}\"", name, escape(value)));
+            names.push(name);
+        }
+    }
+    pairs.join(",")
+}
+
+/// the OpenMetrics exposition of twins
+pub fn render(twins: &[AuTwin]) -> String {
+    // the samples of a metric family must be contiguous
+    let mut families: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (path, telemetry) in twins.iter() {
+        for t in telemetry.iter() {
+            let value = match t.value.as_f64() {
+                Some(value) => value,
+                None => continue,
+            };
+            families.entry(sanitize(&t.name)).or_default().push(format!(
+                "{{{}}} {}",
+                labels(path, &t.labels),
+                value
+            ));
+        }
+    }
+    let mut out = String::new();
+    for (name, samples) in families.iter() {
+        out.push_str(&format!("# TYPE {} gauge\n", name));
+        for sample in samples.iter() {
+            out.push_str(&format!("{}{}\n", name, sample));
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// the reply to an export - the OpenMetrics of the twins or a 504 if an actor did not answer
+fn export_reply(result: Result<Vec<AuTwin>, Elapsed>) -> Response {
+    match result {
+        Ok(twins) => {
+            warp::reply::with_header(render(&twins), "Content-Type", CONTENT_TYPE).into_response()
+        }
+        Err(_) => timeout_reply(),
+    }
+}
+
+/// `GET /export/metrics?prefix=`
+pub fn routes(twins: &AuTwins) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let twins = twins.clone();
+    warp::path("export")
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |query: HashMap<String, String>, header: Option<String>| {
+                let prefix = prefix_path(query.get("prefix"));
+                let result = twins
+                    .auth
+                    .check(header.as_deref(), AuPermission::Ask, &prefix)
+                    .map(|_| twins.export(prefix))
+                    .map_err(warp::reject::custom);
+                async move { result }
+            },
+        )
+        .map(export_reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::export::*;
+    use crate::au::model::{AuTelemetry, AuValue};
+    use crate::au::path;
+
+    #[test]
+    fn prefix_path_works() {
+        let prefix = "/actor/Person/".to_string();
+        assert_eq!(prefix_path(Some(&prefix)), path(&["person"]));
+        assert_eq!(prefix_path(None), Vec::<String>::new());
+    }
+
+    #[test]
+    fn render_works() {
+        let temp = AuTelemetry {
+            name: "phone.temp.celsius".to_string(),
+            value: 22.5.into(),
+            ..Default::default()
+        };
+        let twins = vec![
+            (path(&["person", "mary"]), vec![temp.clone()]),
+            (path(&["person", "mary", "phone", "p\"1"]), vec![temp]),
+            (
+                path(&["person", "joe"]),
+                vec![AuTelemetry {
+                    name: "firmware".to_string(),
+                    value: AuValue::String("v1.2".to_string()),
+                    ..Default::default()
+                }],
+            ),
+        ];
+        assert_eq!(
+            render(&twins),
+            "# TYPE phone_temp_celsius gauge\n\
+             phone_temp_celsius{person=\"mary\"} 22.5\n\
+             phone_temp_celsius{person=\"mary\",phone=\"p\\\"1\"} 22.5\n\
+             # EOF\n"
+        );
+        assert_eq!(sanitize("1st"), "au_1st");
+        assert_eq!(
+            labels(&path(&["part", "a", "part", "b"]), &BTreeMap::new()),
+            "part=\"a\",part_1=\"b\""
+        );
+        let own = [("position", "top"), ("part", "x")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(
+            labels(&path(&["part", "a"]), &own),
+            "part=\"a\",position=\"top\""
+        );
+    }
+}
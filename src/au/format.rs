@@ -0,0 +1,174 @@
+//! Content negotiation of the `/actor` routes.
+//!
+//! Telemetry may be posted and state read as json, CBOR or MessagePack - chosen by the
+//! `Content-Type` of a POST and the `Accept` of a GET, json by default.  The encodings
+//! serialize the same serde types so a twin answers the same whatever the wire format.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use warp::{Filter, Rejection};
+
+/// No format the `Accept` header allows is supported - 406.
+#[derive(Debug)]
+pub struct AuNotAcceptable(pub String);
+
+impl warp::reject::Reject for AuNotAcceptable {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuFormat {
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl AuFormat {
+    /// the format of a media type, ignoring its parameters
+    fn of(media_type: &str) -> Option<AuFormat> {
+        let essence = media_type.split(';').next().unwrap_or_default().trim();
+        match essence.to_lowercase().as_str() {
+            "application/json" => Some(AuFormat::Json),
+            "application/cbor" => Some(AuFormat::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(AuFormat::MsgPack)
+            }
+            _ => None,
+        }
+    }
+
+    /// the format of a posted body, json unless its `Content-Type` is a binary format - clients
+    /// have posted json as `text/plain` and form data all along
+    pub fn from_content_type(content_type: Option<&str>) -> AuFormat {
+        content_type
+            .and_then(AuFormat::of)
+            .unwrap_or(AuFormat::Json)
+    }
+
+    /// the supported format an `Accept` header prefers, json without a header
+    pub fn from_accept(accept: Option<&str>) -> Result<AuFormat, String> {
+        let accept = match accept.map(str::trim).filter(|a| !a.is_empty()) {
+            Some(accept) => accept,
+            None => return Ok(AuFormat::Json),
+        };
+        let mut ranges: Vec<(f32, &str)> = accept
+            .split(',')
+            .map(|range| {
+                let mut params = range.split(';');
+                let media_type = params.next().unwrap_or_default().trim();
+                let q = params
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (q, media_type)
+            })
+            .filter(|(q, _)| *q > 0.0)
+            .collect();
+        // the sort is stable so equally preferred ranges keep the client's order
+        ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ranges
+            .into_iter()
+            .find_map(|(_, media_type)| match media_type {
+                "*/*" | "application/*" => Some(AuFormat::Json),
+                _ => AuFormat::of(media_type),
+            })
+            .ok_or_else(|| accept.to_string())
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            AuFormat::Json => "application/json",
+            AuFormat::Cbor => "application/cbor",
+            AuFormat::MsgPack => "application/msgpack",
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, String> {
+        match self {
+            AuFormat::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            AuFormat::Cbor => ciborium::de::from_reader(body).map_err(|e| e.to_string()),
+            AuFormat::MsgPack => rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            AuFormat::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            AuFormat::Cbor => {
+                let mut out = Vec::new();
+                ciborium::ser::into_writer(value, &mut out).map_err(|e| e.to_string())?;
+                Ok(out)
+            }
+            AuFormat::MsgPack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// a reply of the value in this format
+    pub fn reply<T: Serialize>(&self, value: &T) -> warp::reply::Response {
+        use warp::http::StatusCode;
+        use warp::Reply;
+
+        match self.encode(value) {
+            Ok(body) => {
+                warp::reply::with_header(body, "Content-Type", self.content_type()).into_response()
+            }
+            Err(e) => {
+                warp::reply::with_status(e, StatusCode::INTERNAL_SERVER_ERROR).into_response()
+            }
+        }
+    }
+}
+
+/// the format of a reply the `Accept` header prefers
+pub fn accept() -> impl Filter<Extract = (AuFormat,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept").and_then(|accept: Option<String>| async move {
+        AuFormat::from_accept(accept.as_deref())
+            .map_err(|a| warp::reject::custom(AuNotAcceptable(a)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::au::format::*;
+    use crate::au::model::AuTelemetry;
+
+    #[test]
+    fn negotiation_works() {
+        assert_eq!(AuFormat::from_content_type(None), AuFormat::Json);
+        assert_eq!(
+            AuFormat::from_content_type(Some("application/cbor")),
+            AuFormat::Cbor
+        );
+        assert_eq!(
+            AuFormat::from_content_type(Some("application/x-www-form-urlencoded")),
+            AuFormat::Json
+        );
+        assert_eq!(AuFormat::from_accept(Some("*/*")), Ok(AuFormat::Json));
+        assert_eq!(
+            AuFormat::from_accept(Some("application/json;q=0.5, application/msgpack")),
+            Ok(AuFormat::MsgPack)
+        );
+        assert_eq!(
+            AuFormat::from_accept(Some("text/html, application/cbor;q=0.1")),
+            Ok(AuFormat::Cbor)
+        );
+        assert!(AuFormat::from_accept(Some("text/html")).is_err());
+    }
+
+    #[test]
+    fn round_trip_works() {
+        let telemetry = vec![AuTelemetry {
+            datetime: Utc.timestamp_opt(1, 0).unwrap(),
+            name: "temp".to_string(),
+            value: 21.5.into(),
+            ..Default::default()
+        }];
+        for format in [AuFormat::Json, AuFormat::Cbor, AuFormat::MsgPack] {
+            let body = format.encode(&telemetry).unwrap();
+            let decoded: Vec<AuTelemetry> = format.decode(&body).unwrap();
+            assert_eq!(decoded[0].name, "temp");
+            assert_eq!(decoded[0].datetime, telemetry[0].datetime);
+        }
+        assert!(AuFormat::Cbor.decode::<Vec<AuTelemetry>>(b"{").is_err());
+    }
+}
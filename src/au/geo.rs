@@ -0,0 +1,488 @@
+//! Positions of twins and geofence queries, ie:
+//! `GET /geo/within?prefix=/actor/truck&lat=51.5&lon=-0.12&radius=5000`.
+//!
+//! A twin reporting a `location` value is entered in a grid of `cell_degrees` square cells at its
+//! last-known position, kept as its actor applies each Tell.  A query finds the twins at or below
+//! a prefix within `radius` meters of a point or inside a `polygon` of `lat,lon` vertices separated
+//! by `;`, looking only at the cells the area covers - or, when it covers more than
+//! `MAX_SCAN_CELLS` or than there are occupied cells, at the occupied cells inside it.  Each
+//! actor also keeps the track of its last `track_points` locations, ie:
+//! `GET /geo/track?path=/actor/truck/t1`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::auth::AuPermission;
+use crate::au::export::prefix_path;
+use crate::au::ingest::twin_path;
+use crate::au::model::AuOperator::Track;
+use crate::au::model::{AuLocation, AuTelemetry, AuValue};
+use crate::au::twins::{ask_reply, AuTwins};
+
+/// mean radius of the earth in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// most cells of its area a query looks up one by one
+const MAX_SCAN_CELLS: i64 = 10_000;
+
+/// A geofence or track query is malformed - 400.
+#[derive(Debug)]
+pub struct AuBadGeoQuery(pub String);
+
+impl warp::reject::Reject for AuBadGeoQuery {}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct GeoConfig {
+    /// size of a cell of the index
+    pub cell_degrees: f64,
+    /// locations kept in the track of each twin
+    pub track_points: usize,
+}
+
+impl Default for GeoConfig {
+    fn default() -> Self {
+        GeoConfig {
+            cell_degrees: 0.1,
+            track_points: 1000,
+        }
+    }
+}
+
+impl GeoConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.cell_degrees.is_finite() && self.cell_degrees > 0.0) {
+            return Err("invalid config: geo cell_degrees must be above 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// An area twins are looked for in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuArea {
+    Radius { center: AuLocation, meters: f64 },
+    Polygon(Vec<AuLocation>),
+}
+
+impl AuArea {
+    /// the area of the `lat`, `lon` and `radius` or the `polygon` query parameters
+    pub fn from_query(query: &HashMap<String, String>) -> Result<AuArea, String> {
+        let number = |k: &str| -> Result<f64, String> {
+            let v = query.get(k).ok_or_else(|| format!("no {}", k))?;
+            v.parse().map_err(|_| format!("invalid {} {}", k, v))
+        };
+        if let Some(polygon) = query.get("polygon") {
+            let vertices = polygon
+                .split(';')
+                .map(|vertex| {
+                    let (lat, lon) = vertex
+                        .split_once(',')
+                        .ok_or_else(|| format!("invalid vertex {}", vertex))?;
+                    let lat = lat
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid vertex {}", vertex));
+                    let lon = lon
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid vertex {}", vertex));
+                    AuLocation::new(lat?, lon?, None)
+                })
+                .collect::<Result<Vec<AuLocation>, String>>()?;
+            if vertices.len() < 3 {
+                return Err("a polygon needs three vertices".to_string());
+            }
+            return Ok(AuArea::Polygon(vertices));
+        }
+        let center = AuLocation::new(number("lat")?, number("lon")?, None)?;
+        let meters = number("radius")?;
+        if meters.is_nan() || meters < 0.0 {
+            return Err(format!("invalid radius {}", meters));
+        }
+        Ok(AuArea::Radius { center, meters })
+    }
+
+    pub fn contains(&self, location: &AuLocation) -> bool {
+        match self {
+            AuArea::Radius { center, meters } => distance(center, location) <= *meters,
+            AuArea::Polygon(vertices) => {
+                // a ray cast along the meridian, flat in degrees
+                let mut inside = false;
+                let mut j = vertices.len() - 1;
+                for (i, a) in vertices.iter().enumerate() {
+                    let b = &vertices[j];
+                    if (a.lat > location.lat) != (b.lat > location.lat)
+                        && location.lon
+                            < (b.lon - a.lon) * (location.lat - a.lat) / (b.lat - a.lat) + a.lon
+                    {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            }
+        }
+    }
+
+    /// the corners of the area, south west and north east, `None` across the antimeridian
+    fn bounds(&self) -> Option<((f64, f64), (f64, f64))> {
+        match self {
+            AuArea::Radius { center, meters } => {
+                let dlat = (meters / EARTH_RADIUS).to_degrees();
+                let south = (center.lat - dlat).max(-90.0);
+                let north = (center.lat + dlat).min(90.0);
+                let widest = center.lat.abs().max(south.abs()).max(north.abs());
+                if widest >= 90.0 {
+                    return None;
+                }
+                let dlon = dlat / widest.to_radians().cos();
+                let (west, east) = (center.lon - dlon, center.lon + dlon);
+                if west < -180.0 || east > 180.0 {
+                    return None;
+                }
+                Some(((south, west), (north, east)))
+            }
+            AuArea::Polygon(vertices) => {
+                let fold = |f: fn(f64, f64) -> f64, init: f64, g: fn(&AuLocation) -> f64| {
+                    vertices.iter().map(g).fold(init, f)
+                };
+                Some((
+                    (
+                        fold(f64::min, f64::INFINITY, |v| v.lat),
+                        fold(f64::min, f64::INFINITY, |v| v.lon),
+                    ),
+                    (
+                        fold(f64::max, f64::NEG_INFINITY, |v| v.lat),
+                        fold(f64::max, f64::NEG_INFINITY, |v| v.lon),
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+/// great circle distance in meters
+pub fn distance(a: &AuLocation, b: &AuLocation) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.lon - a.lon).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().asin()
+}
+
+/// A twin found in an area.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuGeoHit {
+    pub path: String,
+    /// the telemetry name of the location
+    pub name: String,
+    pub location: AuLocation,
+    pub datetime: DateTime<Utc>,
+}
+
+struct AuPosition {
+    cell: (i64, i64),
+    name: String,
+    location: AuLocation,
+    datetime: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct AuGrid {
+    cells: HashMap<(i64, i64), BTreeSet<String>>,
+    /// the last-known position of each twin by actor key
+    positions: HashMap<String, AuPosition>,
+}
+
+/// The last-known positions of the twins, indexed by grid cell.
+pub struct AuGeoIndex {
+    config: GeoConfig,
+    grid: Mutex<AuGrid>,
+}
+
+impl AuGeoIndex {
+    pub fn new(config: &GeoConfig) -> AuGeoIndex {
+        AuGeoIndex {
+            config: config.clone(),
+            grid: Mutex::new(AuGrid::default()),
+        }
+    }
+
+    pub fn track_points(&self) -> usize {
+        self.config.track_points
+    }
+
+    fn cell(&self, lat: f64, lon: f64) -> (i64, i64) {
+        (
+            (lat / self.config.cell_degrees).floor() as i64,
+            (lon / self.config.cell_degrees).floor() as i64,
+        )
+    }
+
+    /// move the twin at `key`, ie: `truck/t1`, to a location unless it already has a later one
+    pub fn update(&self, key: &str, t: &AuTelemetry) {
+        let location = match &t.value {
+            AuValue::Location(location) => *location,
+            _ => return,
+        };
+        let cell = self.cell(location.lat, location.lon);
+        let mut grid = self.grid.lock().unwrap();
+        if let Some(old) = grid.positions.get(key) {
+            if old.datetime > t.datetime {
+                return;
+            }
+            let old_cell = old.cell;
+            if let Some(keys) = grid.cells.get_mut(&old_cell) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    grid.cells.remove(&old_cell);
+                }
+            }
+        }
+        grid.cells.entry(cell).or_default().insert(key.to_string());
+        grid.positions.insert(
+            key.to_string(),
+            AuPosition {
+                cell,
+                name: t.name.clone(),
+                location,
+                datetime: t.datetime,
+            },
+        );
+    }
+
+    /// whether the twin at `key` has reported a location
+    pub fn contains(&self, key: &str) -> bool {
+        self.grid.lock().unwrap().positions.contains_key(key)
+    }
+
+    /// the twins at or below the actor path `prefix`, ie: `[truck]`, last seen inside the area
+    pub fn within(&self, prefix: &[String], area: &AuArea) -> Vec<AuGeoHit> {
+        let grid = self.grid.lock().unwrap();
+        let below = |key: &str| {
+            let segments: Vec<&str> = key.split('/').collect();
+            segments.len() >= prefix.len() && prefix.iter().zip(segments).all(|(p, s)| p == s)
+        };
+        // the cells of the area, `None` if they do not fit an i64
+        let count = |sw: (i64, i64), ne: (i64, i64)| {
+            let lats = ne.0.checked_sub(sw.0)?.checked_add(1)?;
+            let lons = ne.1.checked_sub(sw.1)?.checked_add(1)?;
+            lats.checked_mul(lons)
+        };
+        let keys: Vec<&String> = match area
+            .bounds()
+            .map(|(sw, ne)| (self.cell(sw.0, sw.1), self.cell(ne.0, ne.1)))
+        {
+            Some((sw, ne)) => match count(sw, ne) {
+                // scan the cells of the area unless there are more of them than occupied cells
+                Some(cells) if cells <= MAX_SCAN_CELLS && cells as usize <= grid.cells.len() => {
+                    let mut keys = Vec::new();
+                    for lat in sw.0..=ne.0 {
+                        for lon in sw.1..=ne.1 {
+                            keys.extend(grid.cells.get(&(lat, lon)).into_iter().flatten());
+                        }
+                    }
+                    keys
+                }
+                _ => grid
+                    .cells
+                    .iter()
+                    .filter(|(cell, _)| {
+                        (sw.0..=ne.0).contains(&cell.0) && (sw.1..=ne.1).contains(&cell.1)
+                    })
+                    .flat_map(|(_, keys)| keys)
+                    .collect(),
+            },
+            None => grid.positions.keys().collect(),
+        };
+        let mut hits: Vec<AuGeoHit> = keys
+            .into_iter()
+            .filter(|key| below(key))
+            .filter_map(|key| {
+                let position = &grid.positions[key];
+                area.contains(&position.location).then(|| AuGeoHit {
+                    path: format!("/actor/{}", key),
+                    name: position.name.clone(),
+                    location: position.location,
+                    datetime: position.datetime,
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| a.path.cmp(&b.path));
+        hits
+    }
+}
+
+/// `GET /geo/within?prefix=` and `GET /geo/track?path=`
+pub fn routes(twins: &AuTwins) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let twins_within = twins.clone();
+    let within = warp::path("geo")
+        .and(warp::path("within"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |query: HashMap<String, String>, header: Option<String>| {
+                let twins = &twins_within;
+                let prefix = prefix_path(query.get("prefix"));
+                let result = twins
+                    .auth
+                    .check(header.as_deref(), AuPermission::Ask, &prefix)
+                    .map_err(warp::reject::custom)
+                    .and_then(|_| {
+                        AuArea::from_query(&query)
+                            .map_err(|e| warp::reject::custom(AuBadGeoQuery(e)))
+                    })
+                    .map(|area| {
+                        warp::reply::json(&twins.geo.within(&prefix, &area)).into_response()
+                    });
+                async move { result }
+            },
+        );
+
+    let twins_track = twins.clone();
+    let track = warp::path("geo")
+        .and(warp::path("track"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |query: HashMap<String, String>, header: Option<String>| {
+                let twins = &twins_track;
+                let path = query.get("path").map(String::as_str).unwrap_or_default();
+                let result = twin_path(path)
+                    .ok_or_else(|| {
+                        warp::reject::custom(AuBadGeoQuery(format!("invalid twin path {}", path)))
+                    })
+                    .and_then(|path| {
+                        twins
+                            .auth
+                            .check(header.as_deref(), AuPermission::Ask, &path)
+                            .map(|_| path)
+                            .map_err(warp::reject::custom)
+                    })
+                    .map(|mut path| {
+                        // only a twin that reported a location has a track to ask its actor for
+                        if !twins.geo.contains(&path.join("/")) {
+                            return Ok(Some(Vec::new()));
+                        }
+                        let root = path.remove(0);
+                        match twins.live_root(&root) {
+                            Some(actor) => twins.ask_path(&actor, Track, path).map(|msg| msg.data),
+                            None => Ok(Some(Vec::new())),
+                        }
+                    });
+                async move { result }
+            },
+        )
+        .and(crate::au::format::accept())
+        .map(ask_reply);
+
+    within.or(track).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::geo::*;
+
+    fn at(lat: f64, lon: f64, secs: i64) -> AuTelemetry {
+        use chrono::TimeZone;
+        AuTelemetry {
+            datetime: Utc.timestamp_opt(secs, 0).unwrap(),
+            name: "position".to_string(),
+            value: AuValue::Location(AuLocation::new(lat, lon, None).unwrap()),
+            ..Default::default()
+        }
+    }
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn distance_works() {
+        let london = AuLocation::new(51.5074, -0.1278, None).unwrap();
+        let paris = AuLocation::new(48.8566, 2.3522, None).unwrap();
+        assert!((distance(&london, &paris) - 343_500.0).abs() < 1_000.0);
+    }
+
+    #[test]
+    fn area_works() {
+        let area = AuArea::from_query(&query(&[("polygon", "0,0;0,10;10,10;10,0")])).unwrap();
+        assert!(area.contains(&AuLocation::new(5.0, 5.0, None).unwrap()));
+        assert!(!area.contains(&AuLocation::new(5.0, 11.0, None).unwrap()));
+        assert!(AuArea::from_query(&query(&[("polygon", "0,0;0,10")])).is_err());
+        assert!(AuArea::from_query(&query(&[("lat", "1"), ("lon", "1")])).is_err());
+        assert!(
+            AuArea::from_query(&query(&[("lat", "1"), ("lon", "1"), ("radius", "-1")])).is_err()
+        );
+    }
+
+    #[test]
+    fn within_works() {
+        let index = AuGeoIndex::new(&GeoConfig::default());
+        index.update("truck/t1", &at(51.50, -0.12, 1));
+        index.update("truck/t2", &at(51.60, -0.12, 1));
+        index.update("van/v1", &at(51.50, -0.12, 1));
+        // an earlier location does not move a twin
+        index.update("truck/t2", &at(51.50, -0.12, 0));
+        let prefix = vec!["truck".to_string()];
+        let area = AuArea::Radius {
+            center: AuLocation::new(51.5, -0.12, None).unwrap(),
+            meters: 5_000.0,
+        };
+        let paths: Vec<String> = index
+            .within(&prefix, &area)
+            .into_iter()
+            .map(|h| h.path)
+            .collect();
+        assert_eq!(paths, vec!["/actor/truck/t1".to_string()]);
+        index.update("truck/t2", &at(51.51, -0.12, 2));
+        assert_eq!(index.within(&prefix, &area).len(), 2);
+        assert_eq!(index.within(&[], &area).len(), 3);
+        assert!(index.contains("van/v1"));
+        assert!(!index.contains("van/v2"));
+    }
+
+    #[test]
+    fn validate_works() {
+        assert!(GeoConfig::default().validate().is_ok());
+        for json in [r#"{"cell_degrees": 0.0}"#, r#"{"cell_degrees": -1.0}"#].iter() {
+            let config: GeoConfig = serde_json::from_str(json).unwrap();
+            assert!(config.validate().is_err(), "{}", json);
+        }
+        assert!(
+            crate::au::config::AuConfig::from_json(r#"{"geo": {"cell_degrees": 0.0}}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn within_many_cells_works() {
+        // cells so small their numbers saturate, the area covers more than an i64 counts
+        let config: GeoConfig = serde_json::from_str(r#"{"cell_degrees": 1e-300}"#).unwrap();
+        let index = AuGeoIndex::new(&config);
+        index.update("truck/t1", &at(51.50, -0.12, 1));
+        index.update("truck/t2", &at(-51.50, 0.12, 1));
+        let area = AuArea::from_query(&query(&[("polygon", "-60,-1;-60,1;60,1;60,-1")])).unwrap();
+        assert_eq!(index.within(&[], &area).len(), 2);
+        let area = AuArea::from_query(&query(&[("polygon", "0,-1;0,1;60,1;60,-1")])).unwrap();
+        assert_eq!(index.within(&[], &area).len(), 1);
+        // and an area of more cells than scanned one by one looks at the occupied ones
+        let index = AuGeoIndex::new(&GeoConfig::default());
+        index.update("truck/t1", &at(51.50, -0.12, 1));
+        let area =
+            AuArea::from_query(&query(&[("polygon", "-80,-170;-80,170;80,170;80,-170")])).unwrap();
+        assert_eq!(index.within(&[], &area).len(), 1);
+    }
+}
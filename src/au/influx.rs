@@ -0,0 +1,337 @@
+//! InfluxDB line protocol ingestion, ie: `POST /write?precision=s`.
+//!
+//! Each line is `measurement[,tag=value...] field=value[,field=value...] [timestamp]`.  A line is
+//! mapped onto a twin by the first template for its measurement - the `path` and `name` templates
+//! are filled from the line's tags, its `{measurement}` and, for the name, each `{field}`.  Every
+//! field becomes an `AuTelemetry` stamped with the line's timestamp - a float a number, an `i` or
+//! `u` suffixed field an integer, `t`/`f` a boolean and a quoted field a string, labelled with the
+//! tags neither template uses.  Lines no template maps are dropped.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeZone, Utc};
+use log::debug;
+use serde::Deserialize;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::config::AuConfig;
+use crate::au::ingest::{credentials, fill, group, twin_path, unfilled, AuTellError};
+use crate::au::model::{AuTelemetry, AuTwin, AuValue};
+use crate::au::tls::AuClientIdentity;
+use crate::au::twins::{tell_reply, AuTwins};
+
+fn default_name() -> String {
+    "{measurement}.{field}".to_string()
+}
+
+/// Maps the lines of a measurement onto a twin.
+#[derive(Clone, Deserialize)]
+pub struct AuInfluxTemplate {
+    /// the measurement mapped, any measurement when absent
+    pub measurement: Option<String>,
+    /// template of the twin path, ie: `/actor/host/{host}`
+    pub path: String,
+    /// template of the telemetry name, `{measurement}.{field}` by default
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct InfluxConfig {
+    /// tried in order, the first mapping a line is used
+    pub templates: Vec<AuInfluxTemplate>,
+}
+
+struct AuLine {
+    measurement: String,
+    tags: Vec<(String, String)>,
+    fields: Vec<(String, AuValue)>,
+    datetime: DateTime<Utc>,
+}
+
+/// split at the separators not escaped by a backslash, nor quoted when `quotes` is set
+fn split(s: &str, sep: char, quotes: bool, limit: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if quotes => quoted = !quoted,
+            _ if c == sep && !quoted && parts.len() + 1 < limit => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn pair(s: &str) -> Result<(String, &str), String> {
+    match split(s, '=', false, 2).as_slice() {
+        [k, v] if !k.is_empty() => Ok((unescape(k), v)),
+        _ => Err(format!("invalid key=value {}", s)),
+    }
+}
+
+/// the value of a field, an unsigned integer too large for an `i64` a number
+fn field_value(v: &str) -> Result<AuValue, String> {
+    let invalid = || format!("invalid field value {}", v);
+    let value = match v {
+        _ if v.len() >= 2 && v.starts_with('"') && v.ends_with('"') => {
+            AuValue::String(unescape(&v[1..v.len() - 1]))
+        }
+        "t" | "T" | "true" | "True" | "TRUE" => AuValue::Boolean(true),
+        "f" | "F" | "false" | "False" | "FALSE" => AuValue::Boolean(false),
+        _ if v.ends_with('i') => {
+            AuValue::Integer(v[..v.len() - 1].parse::<i64>().map_err(|_| invalid())?)
+        }
+        _ if v.ends_with('u') => {
+            let u = v[..v.len() - 1].parse::<u64>().map_err(|_| invalid())?;
+            match i64::try_from(u) {
+                Ok(i) => AuValue::Integer(i),
+                Err(_) => AuValue::Number(u as f64),
+            }
+        }
+        _ => AuValue::Number(v.parse::<f64>().map_err(|_| invalid())?),
+    };
+    Ok(value)
+}
+
+/// nanoseconds in a unit of the `precision` parameter
+fn precision_nanos(precision: Option<&str>) -> Result<i64, String> {
+    match precision.unwrap_or("ns") {
+        "n" | "ns" => Ok(1),
+        "u" | "us" => Ok(1_000),
+        "ms" => Ok(1_000_000),
+        "s" => Ok(1_000_000_000),
+        "m" => Ok(60_000_000_000),
+        "h" => Ok(3_600_000_000_000),
+        p => Err(format!("invalid precision {}", p)),
+    }
+}
+
+fn parse_line(line: &str, nanos: i64) -> Result<AuLine, String> {
+    let parts = split(line, ' ', true, 3);
+    if parts.len() < 2 {
+        return Err(format!("no fields in {}", line));
+    }
+    let mut keys = split(parts[0], ',', false, usize::MAX).into_iter();
+    let measurement = unescape(keys.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err(format!("no measurement in {}", line));
+    }
+    let mut tags = Vec::new();
+    for tag in keys {
+        let (k, v) = pair(tag)?;
+        tags.push((k, unescape(v)));
+    }
+    let mut fields = Vec::new();
+    for field in split(parts[1], ',', true, usize::MAX) {
+        let (k, v) = pair(field)?;
+        fields.push((k, field_value(v)?));
+    }
+    let datetime = match parts.get(2).map(|t| t.trim()).filter(|t| !t.is_empty()) {
+        Some(t) => {
+            let ts: i64 = t.parse().map_err(|_| format!("invalid timestamp {}", t))?;
+            let ns = ts
+                .checked_mul(nanos)
+                .ok_or_else(|| format!("invalid timestamp {}", t))?;
+            Utc.timestamp_nanos(ns)
+        }
+        None => Utc::now(),
+    };
+    Ok(AuLine {
+        measurement,
+        tags,
+        fields,
+        datetime,
+    })
+}
+
+pub struct AuInflux {
+    templates: Vec<AuInfluxTemplate>,
+}
+
+impl AuInflux {
+    pub fn new(config: &InfluxConfig) -> AuInflux {
+        AuInflux {
+            templates: config.templates.clone(),
+        }
+    }
+
+    /// the telemetry of the twins in a line protocol body
+    pub fn decode(&self, body: &str, precision: Option<&str>) -> Result<Vec<AuTwin>, String> {
+        let nanos = precision_nanos(precision)?;
+        let mut records = Vec::new();
+        for line in body.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line = parse_line(line, nanos)?;
+            let lookup = |k: &str| match k {
+                "measurement" => Some(line.measurement.clone()),
+                _ => line
+                    .tags
+                    .iter()
+                    .find(|(t, _)| t == k)
+                    .map(|(_, v)| v.clone()),
+            };
+            let mapped = self
+                .templates
+                .iter()
+                .filter(|t| {
+                    t.measurement.is_none() || t.measurement == Some(line.measurement.clone())
+                })
+                .find_map(|t| Some((twin_path(&fill(&t.path, lookup)?)?, t)));
+            let (path, template) = match mapped {
+                Some(mapped) => mapped,
+                None => {
+                    debug!("no template maps measurement {}", line.measurement);
+                    continue;
+                }
+            };
+            let labels = unfilled(
+                line.tags.iter().map(|(k, v)| (k, v)),
+                &[template.path.as_str(), template.name.as_str()],
+            );
+            for (field, value) in line.fields.iter() {
+                let name = fill(&template.name, |k| match k {
+                    "field" => Some(field.clone()),
+                    _ => lookup(k),
+                });
+                if let Some(name) = name {
+                    records.push((
+                        path.clone(),
+                        AuTelemetry {
+                            datetime: line.datetime,
+                            name,
+                            value: value.clone(),
+                            unit: None,
+                            labels: labels.clone(),
+                            violations: Vec::new(),
+                        },
+                    ));
+                }
+            }
+        }
+        Ok(group(records))
+    }
+}
+
+/// the reply to a line protocol write - 204 as Influx clients expect, 503 while overloaded
+fn write_reply(result: Result<String, AuTellError>) -> Response {
+    match result {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => tell_reply(result),
+    }
+}
+
+/// `POST /write?precision=`
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let influx = Arc::new(AuInflux::new(&config.influx));
+    let twins = twins.clone();
+    warp::path("write")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(crate::au::body::twins(
+            &twins,
+            warp::query::<HashMap<String, String>>(),
+            crate::au::compression::body(config.compression),
+            move |query: &HashMap<String, String>, body: &[u8]| {
+                std::str::from_utf8(body)
+                    .map_err(|e| e.to_string())
+                    .and_then(|body| {
+                        influx.decode(body, query.get("precision").map(String::as_str))
+                    })
+            },
+        ))
+        .and(credentials())
+        .and_then(
+            move |_,
+                  decoded: Vec<AuTwin>,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let result = twins.tell(decoded, header, identity);
+                async move { result }
+            },
+        )
+        .map(write_reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::influx::*;
+
+    fn influx() -> AuInflux {
+        let config: InfluxConfig = serde_json::from_str(
+            r#"{"templates": [
+                {"measurement": "cpu", "path": "/actor/host/{host}"},
+                {"path": "/actor/{measurement}/{id}", "name": "{field}"}
+            ]}"#,
+        )
+        .unwrap();
+        AuInflux::new(&config)
+    }
+
+    #[test]
+    fn parse_line_works() {
+        let line = parse_line(
+            r#"my\ cpu,host=web\,1,region=us usage=0.5,count=3i,up=t,note="a b" 1000"#,
+            1_000_000_000,
+        )
+        .unwrap();
+        assert_eq!(line.measurement, "my cpu");
+        assert_eq!(line.tags[0], ("host".to_string(), "web,1".to_string()));
+        assert_eq!(line.fields.len(), 4);
+        assert_eq!(line.fields[1], ("count".to_string(), AuValue::Integer(3)));
+        assert_eq!(line.fields[2].1, AuValue::Boolean(true));
+        assert_eq!(line.fields[3].1, AuValue::String("a b".to_string()));
+        assert_eq!(line.datetime.timestamp(), 1000);
+        assert!(parse_line("cpu", 1).is_err());
+        assert!(parse_line("cpu usage=x", 1).is_err());
+    }
+
+    #[test]
+    fn decode_works() {
+        let body = "# comment\n\
+                    cpu,host=web-1 usage=0.5,idle=0.25 2000\n\
+                    cpu,host=web-1,core=0 usage=0.75 1000\n\
+                    pump,id=p1 rpm=1200i\n\
+                    disk,host=web-1 free=10\n";
+        let twins = influx().decode(body, Some("s")).unwrap();
+        assert_eq!(twins.len(), 2);
+        assert_eq!(twins[0].0, vec!["host".to_string(), "web-1".to_string()]);
+        let names: Vec<&str> = twins[0].1.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["cpu.usage", "cpu.usage", "cpu.idle"]);
+        assert_eq!(twins[0].1[0].value, 0.75);
+        assert_eq!(twins[0].1[0].labels["core"], "0");
+        assert!(twins[0].1[1].labels.is_empty());
+        assert!(twins[1].1[0].labels.is_empty());
+        assert_eq!(twins[1].1[0].name, "rpm");
+        assert!(influx().decode(body, Some("fortnight")).is_err());
+    }
+}
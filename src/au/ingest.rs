@@ -0,0 +1,299 @@
+//! Shared handling of telemetry ingested in foreign formats.
+//!
+//! A format maps each of its records onto a twin path and an `AuTelemetry`.  The records are
+//! grouped so every addressed actor receives a single Tell, and the client must be allowed to
+//! Tell each twin of the batch - by its certificate binding or else by its bearer token.
+//!
+//! A body of one of these formats - `/csv`, `/write`, `/api/v1/write` or `/v1/metrics` - is Told
+//! whole or not at all: a record breaking its schema refuses it with a 422 and an overloaded twin
+//! with a 503, before any twin is Told, so the client may resend it as it is.  `/batch` is the
+//! exception, it Tells each of its paths on its own and reports the status of every one.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+use warp::filters::path::FullPath;
+use warp::{Filter, Rejection};
+
+use crate::au::auth::{AuAuth, AuAuthError, AuPermission};
+use crate::au::load::AuOverloaded;
+use crate::au::model::{AuTelemetry, AuTwin};
+use crate::au::tls::{AuBindings, AuClientIdentity};
+
+/// most segments of a twin path below `/actor`, five type/id pairs - the longest path routed
+pub const MAX_SEGMENTS: usize = 10;
+
+/// A twin path names an actor riker cannot - 400.
+#[derive(Debug)]
+pub struct AuBadPath(pub String);
+
+impl warp::reject::Reject for AuBadPath {}
+
+/// A Told record refused, ie: for a unit of the wrong dimension.
+#[derive(Debug, Serialize)]
+pub struct AuRecordError {
+    pub path: String,
+    /// zero based position of the record in the telemetry of its twin
+    pub index: usize,
+    pub name: String,
+    pub error: String,
+}
+
+impl AuRecordError {
+    pub fn new(path: &[String], index: usize, name: &str, error: String) -> AuRecordError {
+        AuRecordError {
+            path: format!("/actor/{}", path.join("/")),
+            index,
+            name: name.to_string(),
+            error,
+        }
+    }
+}
+
+/// A Tell was not accepted.
+#[derive(Debug)]
+pub enum AuTellError {
+    /// the actors are too busy - 503
+    Overloaded(AuOverloaded),
+    /// records of the telemetry are refused - 422
+    Invalid(Vec<AuRecordError>),
+    /// the twin's actor could not be created - 500
+    Failed(String),
+}
+
+impl From<AuOverloaded> for AuTellError {
+    fn from(e: AuOverloaded) -> Self {
+        AuTellError::Overloaded(e)
+    }
+}
+
+impl std::fmt::Display for AuTellError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuTellError::Overloaded(e) => write!(f, "Overloaded: {:?}", e),
+            AuTellError::Failed(e) => write!(f, "Failed: {}", e),
+            AuTellError::Invalid(errors) => {
+                let errors: Vec<String> = errors
+                    .iter()
+                    .map(|e| format!("{} {}: {}", e.path, e.name, e.error))
+                    .collect();
+                write!(f, "Invalid: {}", errors.join("; "))
+            }
+        }
+    }
+}
+
+/// whether riker accepts a path segment as an actor name, ie: `pump-1` but not `pump.1`
+pub fn valid_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// the actor path of a twin, ie: `/actor/device/pump-1` is `[device, pump-1]`.  twins are
+/// addressed by type/id pairs so a path of an odd length or naming an invalid actor is refused.
+pub fn twin_path(path: &str) -> Option<Vec<String>> {
+    let mut segments: Vec<String> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_lowercase())
+        .collect();
+    if segments.first().map(String::as_str) == Some("actor") {
+        segments.remove(0);
+    }
+    if segments.is_empty() || segments.len() % 2 == 1 || segments.len() > MAX_SEGMENTS {
+        return None;
+    }
+    if !segments.iter().all(|s| valid_segment(s)) {
+        return None;
+    }
+    Some(segments)
+}
+
+/// rejects `/actor` requests with a path segment that is not an actor name before an actor is
+/// asked to create it
+pub fn guard() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::full()
+        .and_then(|path: FullPath| async move {
+            let mut segments = path.as_str().split('/').filter(|s| !s.is_empty());
+            if segments.next() != Some("actor") {
+                return Ok(());
+            }
+            match segments.find(|s| !valid_segment(s)) {
+                Some(segment) => Err(warp::reject::custom(AuBadPath(format!(
+                    "invalid actor name {:?}",
+                    segment
+                )))),
+                None => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+/// expand the `{name}` placeholders of a template, ie: `/actor/device/{host}`.  a value that is
+/// missing or would add a path segment leaves the template unexpanded.
+pub fn fill<F>(template: &str, lookup: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        out.push_str(&rest[..start]);
+        let value = lookup(&rest[start + 1..end])?;
+        if value.is_empty() || value.contains('/') {
+            return None;
+        }
+        out.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+/// the pairs no template expands, kept as the labels of each record so that series apart in the
+/// source stay apart in the twin
+pub fn unfilled<'a, I>(pairs: I, templates: &[&str]) -> BTreeMap<String, String>
+where
+    I: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    pairs
+        .into_iter()
+        .filter(|(k, _)| {
+            let placeholder = format!("{{{}}}", k);
+            !templates.iter().any(|t| t.contains(&placeholder))
+        })
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/// group records by twin, oldest first within a twin so the latest observation is applied last
+pub fn group(records: Vec<(Vec<String>, AuTelemetry)>) -> Vec<AuTwin> {
+    let mut twins: Vec<AuTwin> = Vec::new();
+    let mut index: HashMap<Vec<String>, usize> = HashMap::new();
+    for (path, telemetry) in records {
+        match index.get(&path) {
+            Some(i) => twins[*i].1.push(telemetry),
+            None => {
+                index.insert(path.clone(), twins.len());
+                twins.push((path, vec![telemetry]));
+            }
+        }
+    }
+    for (_, batch) in twins.iter_mut() {
+        batch.sort_by_key(|t| t.datetime);
+    }
+    twins
+}
+
+/// check the client may Tell every twin of the batch
+pub fn authorize(
+    auth: &AuAuth,
+    bindings: &AuBindings,
+    header: Option<&str>,
+    identity: Option<&AuClientIdentity>,
+    twins: &[AuTwin],
+) -> Result<(), AuAuthError> {
+    for (path, _) in twins.iter() {
+        authorize_path(auth, bindings, header, identity, path)?;
+    }
+    Ok(())
+}
+
+/// check the client may Tell the twin at `path`
+pub fn authorize_path(
+    auth: &AuAuth,
+    bindings: &AuBindings,
+    header: Option<&str>,
+    identity: Option<&AuClientIdentity>,
+    path: &[String],
+) -> Result<(), AuAuthError> {
+    match identity {
+        Some(identity) => bindings.check(identity, path),
+        None => auth.check(header, AuPermission::Tell, path),
+    }
+}
+
+/// the bearer token and client certificate of an ingestion request
+pub fn credentials(
+) -> impl Filter<Extract = (Option<String>, Option<AuClientIdentity>), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and(warp::ext::optional::<AuClientIdentity>())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::au::ingest::*;
+    use crate::au::path;
+
+    #[test]
+    fn twin_path_works() {
+        assert_eq!(
+            twin_path("/actor/Device/pump-1"),
+            Some(path(&["device", "pump-1"]))
+        );
+        assert_eq!(
+            twin_path("device/pump-1"),
+            Some(path(&["device", "pump-1"]))
+        );
+        assert_eq!(twin_path("/actor/device"), None);
+        assert_eq!(twin_path(""), None);
+        assert_eq!(twin_path("/actor/device/pump.1"), None);
+        assert_eq!(twin_path("/actor/device/pump%201"), None);
+    }
+
+    #[test]
+    fn fill_works() {
+        let lookup = |k: &str| match k {
+            "host" => Some("pump-1".to_string()),
+            "bad" => Some("a/b".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            fill("/actor/device/{host}", lookup),
+            Some("/actor/device/pump-1".to_string())
+        );
+        assert_eq!(fill("/actor/device/{missing}", lookup), None);
+        assert_eq!(fill("/actor/device/{bad}", lookup), None);
+        assert_eq!(
+            fill("/actor/device/pump", lookup),
+            Some("/actor/device/pump".to_string())
+        );
+    }
+
+    #[test]
+    fn unfilled_works() {
+        let pairs = vec![
+            ("host".to_string(), "pump-1".to_string()),
+            ("stage".to_string(), "2".to_string()),
+        ];
+        let labels = unfilled(
+            pairs.iter().map(|(k, v)| (k, v)),
+            &["/actor/device/{host}", "{measurement}.{field}"],
+        );
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels["stage"], "2");
+    }
+
+    #[test]
+    fn group_works() {
+        let at = |secs: i64, value: f64| AuTelemetry {
+            datetime: Utc.timestamp_opt(secs, 0).unwrap(),
+            name: "temp".to_string(),
+            value: value.into(),
+            ..Default::default()
+        };
+        let twins = group(vec![
+            (path(&["device", "a"]), at(2, 2.0)),
+            (path(&["device", "b"]), at(1, 3.0)),
+            (path(&["device", "a"]), at(1, 1.0)),
+        ]);
+        assert_eq!(twins.len(), 2);
+        assert_eq!(twins[0].0, path(&["device", "a"]));
+        let values: Vec<f64> = twins[0].1.iter().filter_map(|t| t.value.as_f64()).collect();
+        assert_eq!(values, vec![1.0, 2.0]);
+    }
+}
@@ -0,0 +1,122 @@
+//! Label selectors of an Ask, ie: `GET /actor/device/p1?labels=position=top,quality!=bad`.
+//!
+//! Telemetry may carry string labels - a sensor position, a quality flag - and a twin keeps the
+//! latest record of each name and label set.  A selector is a comma separated list of matchers:
+//! `key=value` keeps the records with the label, `key!=value` those without it.  An Ask without
+//! `labels` answers every record.
+//!
+//! Selectors filter the current state only - twins keep no history of past records yet, so there is
+//! no history query for a selector to narrow.
+
+use std::collections::{BTreeMap, HashMap};
+
+use warp::{Filter, Rejection};
+
+use crate::au::model::AuTelemetry;
+
+/// The `labels` parameter of an Ask is not a selector - 400.
+#[derive(Debug)]
+pub struct AuBadSelector(pub String);
+
+impl warp::reject::Reject for AuBadSelector {}
+
+#[derive(Clone, Debug, PartialEq)]
+struct AuMatcher {
+    key: String,
+    value: String,
+    negated: bool,
+}
+
+impl AuMatcher {
+    fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        (labels.get(&self.key) == Some(&self.value)) != self.negated
+    }
+}
+
+/// Matchers every selected record satisfies.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuSelector {
+    matchers: Vec<AuMatcher>,
+}
+
+impl AuSelector {
+    pub fn parse(selector: &str) -> Result<AuSelector, String> {
+        let mut matchers = Vec::new();
+        for matcher in selector.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            let (key, value, negated) = match matcher.split_once("!=") {
+                Some((k, v)) => (k, v, true),
+                None => match matcher.split_once('=') {
+                    Some((k, v)) => (k, v, false),
+                    None => return Err(format!("invalid label matcher {}", matcher)),
+                },
+            };
+            let key = key.trim();
+            if key.is_empty() {
+                return Err(format!("invalid label matcher {}", matcher));
+            }
+            matchers.push(AuMatcher {
+                key: key.to_string(),
+                value: value.trim().to_string(),
+                negated,
+            });
+        }
+        Ok(AuSelector { matchers })
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.matchers.iter().all(|m| m.matches(labels))
+    }
+
+    /// the records the selector matches
+    pub fn select(&self, telemetry: Vec<AuTelemetry>) -> Vec<AuTelemetry> {
+        telemetry
+            .into_iter()
+            .filter(|t| self.matches(&t.labels))
+            .collect()
+    }
+}
+
+/// the selector of the `labels` query parameter, matching everything without one
+pub fn selector() -> impl Filter<Extract = (AuSelector,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().and_then(|query: HashMap<String, String>| async move {
+        AuSelector::parse(query.get("labels").map(String::as_str).unwrap_or_default())
+            .map_err(|e| warp::reject::custom(AuBadSelector(e)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::labels::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn selector_works() {
+        let selector = AuSelector::parse("position=top, quality!=bad").unwrap();
+        assert!(selector.matches(&labels(&[("position", "top")])));
+        assert!(selector.matches(&labels(&[("position", "top"), ("quality", "good")])));
+        assert!(!selector.matches(&labels(&[("position", "top"), ("quality", "bad")])));
+        assert!(!selector.matches(&labels(&[])));
+        assert!(AuSelector::parse("").unwrap().matches(&labels(&[])));
+        assert!(AuSelector::parse("position").is_err());
+        assert!(AuSelector::parse("=top").is_err());
+    }
+
+    #[test]
+    fn select_works() {
+        let at = |position: &str| AuTelemetry {
+            labels: labels(&[("position", position)]),
+            ..Default::default()
+        };
+        let selected = AuSelector::parse("position=top")
+            .unwrap()
+            .select(vec![at("top"), at("bottom")]);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].labels["position"], "top");
+    }
+}
@@ -0,0 +1,150 @@
+//! Load shedding for the unbounded actor mailboxes.
+//!
+//! Riker mailboxes grow without bound, so Tells are counted from the moment the server accepts
+//! them until the addressed actor has applied them.  A Tell is refused - the POST answered with a
+//! 503 - when the addressed actor already has `mailbox_bound` Tells pending or the whole system
+//! has `max_in_flight`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LoadConfig {
+    /// most Tells pending for one actor
+    pub mailbox_bound: Option<usize>,
+    /// most Tells pending for one actor of a root type, replacing `mailbox_bound`
+    pub types: HashMap<String, usize>,
+    /// most Tells pending in the whole system
+    pub max_in_flight: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuOverloaded {
+    /// the addressed actor has too many Tells pending
+    Actor,
+    /// the system has too many Tells pending
+    System,
+}
+
+struct AuPending {
+    in_flight: usize,
+    actors: HashMap<String, usize>,
+}
+
+pub struct AuLoad {
+    config: LoadConfig,
+    pending: Mutex<AuPending>,
+}
+
+impl AuLoad {
+    pub fn new(config: &LoadConfig) -> AuLoad {
+        AuLoad {
+            config: config.clone(),
+            pending: Mutex::new(AuPending {
+                in_flight: 0,
+                actors: HashMap::new(),
+            }),
+        }
+    }
+
+    /// count a Tell to the actor at `key`, ie: `person/mary`, unless it would exceed a bound
+    pub fn admit(&self, key: &str) -> Result<(), AuOverloaded> {
+        let root = key.split('/').next().unwrap_or_default();
+        let bound = self
+            .config
+            .types
+            .get(root)
+            .copied()
+            .or(self.config.mailbox_bound);
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(max) = self.config.max_in_flight {
+            if pending.in_flight >= max {
+                return Err(AuOverloaded::System);
+            }
+        }
+        let depth = pending.actors.get(key).copied().unwrap_or(0);
+        if let Some(bound) = bound {
+            if depth >= bound {
+                return Err(AuOverloaded::Actor);
+            }
+        }
+        pending.in_flight += 1;
+        pending.actors.insert(key.to_string(), depth + 1);
+        Ok(())
+    }
+
+    /// the actor at `key` applied one of its Tells
+    pub fn done(&self, key: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        let depth = match pending.actors.get(key) {
+            Some(depth) => *depth,
+            None => return,
+        };
+        pending.in_flight -= 1;
+        if depth > 1 {
+            pending.actors.insert(key.to_string(), depth - 1);
+        } else {
+            pending.actors.remove(key);
+        }
+    }
+
+    /// Tells pending in the whole system
+    pub fn in_flight(&self) -> usize {
+        self.pending.lock().unwrap().in_flight
+    }
+
+    /// Tells pending for the actor at `key`
+    pub fn depth(&self, key: &str) -> usize {
+        let pending = self.pending.lock().unwrap();
+        pending.actors.get(key).copied().unwrap_or(0)
+    }
+
+    /// the keys of the actors with Tells pending and their depths
+    pub fn depths(&self) -> Vec<(String, usize)> {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .actors
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::load::*;
+
+    #[test]
+    fn actor_bound_works() {
+        let load = AuLoad::new(&LoadConfig {
+            mailbox_bound: Some(2),
+            types: vec![("device".to_string(), 1)].into_iter().collect(),
+            ..Default::default()
+        });
+        assert_eq!(load.admit("person/mary"), Ok(()));
+        assert_eq!(load.admit("person/mary"), Ok(()));
+        assert_eq!(load.admit("person/mary"), Err(AuOverloaded::Actor));
+        assert_eq!(load.admit("device/pump"), Ok(()));
+        assert_eq!(load.admit("device/pump"), Err(AuOverloaded::Actor));
+        load.done("person/mary");
+        assert_eq!(load.depth("person/mary"), 1);
+        assert_eq!(load.admit("person/mary"), Ok(()));
+        assert_eq!(load.in_flight(), 3);
+        assert_eq!(load.depths().len(), 2);
+    }
+
+    #[test]
+    fn system_bound_works() {
+        let load = AuLoad::new(&LoadConfig {
+            max_in_flight: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(load.admit("person/mary"), Ok(()));
+        assert_eq!(load.admit("person/bob"), Err(AuOverloaded::System));
+        load.done("person/mary");
+        assert_eq!(load.admit("person/bob"), Ok(()));
+    }
+}
@@ -0,0 +1,227 @@
+//! Prometheus metrics of the server and the actor system, scraped from `GET /metrics`.
+//!
+//! Request counts and latencies are recorded as each response is sent, actor counts as actors
+//! start and stop and state update latencies as actors apply their Tells.  Pending Tells - accepted
+//! by the server and not yet applied, counted by the load tracker - are read when scraped.
+//!
+//! The metrics name the root types of the twins, so a scrape needs a bearer token granted `ask` on
+//! `/actor` unless the `metrics` config makes them `public`.
+
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use serde::Deserialize;
+use warp::http::{Method, StatusCode};
+use warp::log::Info;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::auth::AuPermission;
+use crate::au::config::AuConfig;
+use crate::au::load::AuLoad;
+use crate::au::twins::AuTwins;
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// serve `/metrics` without a bearer token, ie: to a scraper on a private network
+    pub public: bool,
+}
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "augorama_http_requests_total",
+        "HTTP requests by route, operator and status.",
+        &["route", "op", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "augorama_http_request_duration_seconds",
+        "HTTP request latency by route and operator.",
+        &["route", "op"]
+    )
+    .unwrap();
+    pub static ref ACTORS: IntGaugeVec = register_int_gauge_vec!(
+        "augorama_actors",
+        "Live AugieActors by root type.",
+        &["root_type"]
+    )
+    .unwrap();
+    pub static ref PENDING_TELLS: IntGaugeVec = register_int_gauge_vec!(
+        "augorama_pending_tells",
+        "Tells accepted but not yet applied by the actors of a root type.",
+        &["root_type"]
+    )
+    .unwrap();
+    pub static ref PENDING_TELLS_MAX: IntGaugeVec = register_int_gauge_vec!(
+        "augorama_pending_tells_max",
+        "Most Tells accepted but not yet applied by a single actor of a root type.",
+        &["root_type"]
+    )
+    .unwrap();
+    pub static ref IN_FLIGHT: IntGauge = register_int_gauge!(
+        "augorama_in_flight_tells",
+        "Tells pending in the whole actor system."
+    )
+    .unwrap();
+    pub static ref STATE_UPDATE: HistogramVec = register_histogram_vec!(
+        "augorama_state_update_duration_seconds",
+        "Time an actor takes to apply a Told batch to its in-memory state by root type.",
+        &["root_type"]
+    )
+    .unwrap();
+    pub static ref ASK_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+        "augorama_ask_timeouts_total",
+        "Asks and Ls unanswered within the ask timeout.",
+        &["op"]
+    )
+    .unwrap();
+    pub static ref SCHEMA_VIOLATIONS: IntCounterVec = register_int_counter_vec!(
+        "augorama_schema_violations_total",
+        "Told records breaking the schema of their twin type, refused or flagged.",
+        &["type", "mode"]
+    )
+    .unwrap();
+    pub static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "augorama_rate_limited_total",
+        "Requests rejected by the rate limiter by exceeded limit and root type.",
+        &["limit", "root_type"]
+    )
+    .unwrap();
+    pub static ref STATSD_DROPPED: IntCounterVec = register_int_counter_vec!(
+        "augorama_statsd_dropped_total",
+        "StatsD lines dropped for exceeding max_keys or max_samples.",
+        &["limit"]
+    )
+    .unwrap();
+}
+
+/// label a request by its first path segment and the operation it performs
+fn labels(method: &Method, path: &str, status: StatusCode) -> (String, String) {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let route = match segments.first() {
+        // unmatched paths would grow the label set without bound
+        _ if status == StatusCode::NOT_FOUND => "other".to_string(),
+        Some(first) => format!("/{}", first),
+        None => "/".to_string(),
+    };
+    let op = match *method {
+        _ if route != "/actor" => method.as_str().to_lowercase(),
+        Method::POST => "tell".to_string(),
+        Method::DELETE => "delete".to_string(),
+        _ if segments.last() == Some(&"children") => "ls".to_string(),
+        _ => "ask".to_string(),
+    };
+    (route, op)
+}
+
+/// record a served request - for use with `warp::log::custom`
+pub fn observe(info: Info) {
+    let (route, op) = labels(info.method(), info.path(), info.status());
+    HTTP_REQUESTS
+        .with_label_values(&[&route, &op, info.status().as_str()])
+        .inc();
+    HTTP_DURATION
+        .with_label_values(&[&route, &op])
+        .observe(info.elapsed().as_secs_f64());
+}
+
+/// the text exposition of all metrics
+pub fn render(load: &AuLoad) -> String {
+    lazy_static::initialize(&HTTP_REQUESTS);
+    lazy_static::initialize(&HTTP_DURATION);
+    lazy_static::initialize(&ACTORS);
+    lazy_static::initialize(&STATE_UPDATE);
+    lazy_static::initialize(&ASK_TIMEOUTS);
+    lazy_static::initialize(&RATE_LIMITED);
+    lazy_static::initialize(&STATSD_DROPPED);
+
+    let mut depths: HashMap<String, (usize, usize)> = HashMap::new();
+    for (key, depth) in load.depths() {
+        let root = key.split('/').next().unwrap_or_default().to_string();
+        let entry = depths.entry(root).or_insert((0, 0));
+        entry.0 += depth;
+        entry.1 = entry.1.max(depth);
+    }
+    PENDING_TELLS.reset();
+    PENDING_TELLS_MAX.reset();
+    for (root, (sum, max)) in depths.iter() {
+        PENDING_TELLS.with_label_values(&[root]).set(*sum as i64);
+        PENDING_TELLS_MAX
+            .with_label_values(&[root])
+            .set(*max as i64);
+    }
+    IN_FLIGHT.set(load.in_flight() as i64);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// a scrape of the statistics of the twins - with a bearer token granted `ask` on `/actor` unless
+/// they are `public`
+pub fn scraper(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let auth = twins.auth.clone();
+    let public = config.metrics.public;
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let result = match public {
+                true => Ok(()),
+                false => auth
+                    .check(header.as_deref(), AuPermission::Ask, &[])
+                    .map_err(warp::reject::custom),
+            };
+            async move { result }
+        })
+        .untuple_one()
+}
+
+/// `GET /metrics`
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let load = twins.load.clone();
+    warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(scraper(twins, config))
+        .map(move || {
+            warp::reply::with_header(render(&load), "Content-Type", prometheus::TEXT_FORMAT)
+                .into_response()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::metrics::*;
+
+    #[test]
+    fn labels_work() {
+        assert_eq!(
+            labels(&Method::POST, "/actor/person/mary", StatusCode::ACCEPTED),
+            ("/actor".to_string(), "tell".to_string())
+        );
+        assert_eq!(
+            labels(&Method::GET, "/actor/person/children", StatusCode::OK),
+            ("/actor".to_string(), "ls".to_string())
+        );
+        assert_eq!(
+            labels(&Method::GET, "/metrics", StatusCode::OK),
+            ("/metrics".to_string(), "get".to_string())
+        );
+        assert_eq!(
+            labels(&Method::GET, "/no/such/thing", StatusCode::NOT_FOUND),
+            ("other".to_string(), "get".to_string())
+        );
+    }
+}
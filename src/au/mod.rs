@@ -4,4 +4,39 @@ extern crate env_logger;
 extern crate log;
 
 pub mod actor;
+pub mod auth;
+pub mod batch;
+pub mod body;
+pub mod command;
+pub mod compression;
+pub mod config;
+pub mod csv;
+pub mod export;
+pub mod format;
+pub mod geo;
+pub mod influx;
+pub mod ingest;
+pub mod labels;
+pub mod load;
+pub mod metrics;
 pub mod model;
+pub mod mqtt;
+pub mod ndjson;
+pub mod otlp;
+pub mod ratelimit;
+pub mod rejection;
+pub mod remotewrite;
+pub mod schema;
+pub mod shadow;
+pub mod signing;
+pub mod statsd;
+pub mod thing;
+pub mod tls;
+pub mod twins;
+pub mod units;
+
+/// a path of its segments, ie: `path(&["person", "mary"])`
+#[cfg(test)]
+pub fn path(p: &[&str]) -> Vec<String> {
+    p.iter().map(|s| s.to_string()).collect()
+}
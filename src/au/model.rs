@@ -2,18 +2,207 @@
 //!
 //! The main data structure is the AuMsg, an envelope for commands and queries.  A command may
 //! be a query to get state, a query to get journal records, or a command to update state with
-//! the attached telemetry.  Telemetry is always a record with a name, datetime, and a numerical
-//! value.
+//! the attached telemetry.  Telemetry is always a record with a name, datetime, and a typed
+//! value - a number, integer, boolean, string, enum state or location - optionally labelled.
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum AuOperator {
     Ask,
     Tell,
     Ls,
+    /// query for the track of a twin's locations
+    Track,
+    /// set properties of the desired document of a twin's shadow
+    Desire,
+    /// set properties of the reported document of a twin's shadow
+    Report,
+    /// acknowledge a desired version applied
+    Ack,
+    /// query for a twin's shadow
+    Shadow,
+    /// enqueue a command for a twin's device
+    Command,
+    /// deliver the pending commands of a twin
+    Poll,
+    /// finish a command with the outcome its device acknowledged
+    Complete,
+    /// query for a twin's commands and their status
+    Commands,
+}
+
+/// The value of a telemetry record.
+///
+/// In json a plain number, boolean or string is a `Number`, `Boolean` or `String` - so the plain
+/// numbers posted all along still parse.  Integers, enum states and locations are tagged, ie:
+/// `{"integer": 42}`, `{"enum": "open"}` and `{"location": {"lat": 51.5, "lon": -0.12}}`, as are
+/// the other types when written explicitly.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "AuValueRepr", into = "AuValueRepr")]
+pub enum AuValue {
+    /// a double, ie: `22.9`
+    Number(f64),
+    Integer(i64),
+    Boolean(bool),
+    /// free text, ie: a firmware version
+    String(String),
+    /// one of the states of a twin, ie: `open`
+    Enum(String),
+    Location(AuLocation),
+}
+
+/// A position in WGS 84 degrees, its altitude in meters.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "AuLocationRepr")]
+pub struct AuLocation {
+    pub lat: f64,
+    pub lon: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct AuLocationRepr {
+    lat: f64,
+    lon: f64,
+    alt: Option<f64>,
+}
+
+impl TryFrom<AuLocationRepr> for AuLocation {
+    type Error = String;
+
+    fn try_from(repr: AuLocationRepr) -> Result<Self, Self::Error> {
+        AuLocation::new(repr.lat, repr.lon, repr.alt)
+    }
+}
+
+impl AuLocation {
+    pub fn new(lat: f64, lon: f64, alt: Option<f64>) -> Result<AuLocation, String> {
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(format!("invalid location {},{}", lat, lon));
+        }
+        Ok(AuLocation { lat, lon, alt })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AuTaggedValue {
+    Number(f64),
+    Integer(i64),
+    Boolean(bool),
+    String(String),
+    Enum(String),
+    Location(AuLocation),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum AuValueRepr {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Tagged(AuTaggedValue),
+}
+
+impl From<AuValueRepr> for AuValue {
+    fn from(repr: AuValueRepr) -> Self {
+        match repr {
+            AuValueRepr::Number(n) | AuValueRepr::Tagged(AuTaggedValue::Number(n)) => {
+                AuValue::Number(n)
+            }
+            AuValueRepr::Boolean(b) | AuValueRepr::Tagged(AuTaggedValue::Boolean(b)) => {
+                AuValue::Boolean(b)
+            }
+            AuValueRepr::String(s) | AuValueRepr::Tagged(AuTaggedValue::String(s)) => {
+                AuValue::String(s)
+            }
+            AuValueRepr::Tagged(AuTaggedValue::Integer(i)) => AuValue::Integer(i),
+            AuValueRepr::Tagged(AuTaggedValue::Enum(e)) => AuValue::Enum(e),
+            AuValueRepr::Tagged(AuTaggedValue::Location(l)) => AuValue::Location(l),
+        }
+    }
+}
+
+impl From<AuValue> for AuValueRepr {
+    fn from(value: AuValue) -> Self {
+        match value {
+            AuValue::Number(n) => AuValueRepr::Number(n),
+            AuValue::Boolean(b) => AuValueRepr::Boolean(b),
+            AuValue::String(s) => AuValueRepr::String(s),
+            AuValue::Integer(i) => AuValueRepr::Tagged(AuTaggedValue::Integer(i)),
+            AuValue::Enum(e) => AuValueRepr::Tagged(AuTaggedValue::Enum(e)),
+            AuValue::Location(l) => AuValueRepr::Tagged(AuTaggedValue::Location(l)),
+        }
+    }
+}
+
+impl AuValue {
+    /// the value as a number where the type supports arithmetic - a boolean counts as 0 or 1,
+    /// strings, enum states and locations have no number
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            AuValue::Number(n) => Some(*n),
+            AuValue::Integer(i) => Some(*i as f64),
+            AuValue::Boolean(b) => Some(*b as u8 as f64),
+            AuValue::String(_) | AuValue::Enum(_) | AuValue::Location(_) => None,
+        }
+    }
+
+    /// the name of the value's type, ie: `integer`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            AuValue::Number(_) => "number",
+            AuValue::Integer(_) => "integer",
+            AuValue::Boolean(_) => "boolean",
+            AuValue::String(_) => "string",
+            AuValue::Enum(_) => "enum",
+            AuValue::Location(_) => "location",
+        }
+    }
+
+    /// the value of untyped text, ie: a csv field - a number, a boolean or else a string
+    pub fn from_text(text: &str) -> AuValue {
+        match text {
+            "true" => AuValue::Boolean(true),
+            "false" => AuValue::Boolean(false),
+            _ => match text.parse::<f64>() {
+                Ok(n) => AuValue::Number(n),
+                Err(_) => AuValue::String(text.to_string()),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for AuValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuValue::Number(n) => write!(f, "{}", n),
+            AuValue::Integer(i) => write!(f, "{}", i),
+            AuValue::Boolean(b) => write!(f, "{}", b),
+            AuValue::String(s) | AuValue::Enum(s) => write!(f, "{}", s),
+            AuValue::Location(l) => write!(f, "{},{}", l.lat, l.lon),
+        }
+    }
+}
+
+impl From<f64> for AuValue {
+    fn from(n: f64) -> Self {
+        AuValue::Number(n)
+    }
+}
+
+/// numeric values compare by number, ie: `Integer(7) == 7.0`
+impl PartialEq<f64> for AuValue {
+    fn eq(&self, other: &f64) -> bool {
+        match self {
+            AuValue::Number(_) | AuValue::Integer(_) => self.as_f64() == Some(*other),
+            _ => false,
+        }
+    }
 }
 
 /// The single data structure representing the source of all actor state.
@@ -21,15 +210,39 @@ pub enum AuOperator {
 pub struct AuTelemetry {
     /// UTC TZ 8601 format that is ideally a representation of when the observation was made in the real world
     pub datetime: DateTime<Utc>,
-    /// space (deployment) scoped name to type (not instance), ie: `refrigerator.temp.celsius`
+    /// space (deployment) scoped name to type (not instance), ie: `refrigerator.temp`
     pub name: String,
-    /// a double, ie: `22.9`
-    pub value: f64,
+    /// ie: `22.9`, `true` or `{"enum": "open"}`
+    pub value: AuValue,
+    /// UCUM code of the unit of a numeric value, ie: `Cel`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// ie: `{"position": "top"}` - records of a name with other labels are kept apart
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// how the record breaks the schema of its twin's type, accepted in permissive mode
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<String>,
+}
+
+impl AuTelemetry {
+    /// the name and labels the record is kept under, ie: `temp{position="top"}`
+    pub fn key(&self) -> String {
+        if self.labels.is_empty() {
+            return self.name.clone();
+        }
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={:?}", k, v))
+            .collect();
+        format!("{}{{{}}}", self.name, labels.join(","))
+    }
 }
 
 impl std::fmt::Debug for AuTelemetry {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "({} {:?} {:?})", self.name, self.value, self.datetime)
+        write!(f, "({} {} {:?})", self.key(), self.value, self.datetime)
     }
 }
 
@@ -38,11 +251,17 @@ impl Default for AuTelemetry {
         AuTelemetry {
             datetime: Utc::now(),
             name: "measurement".to_string(),
-            value: 0.0,
+            value: AuValue::Number(0.0),
+            unit: None,
+            labels: BTreeMap::new(),
+            violations: Vec::new(),
         }
     }
 }
 
+/// The full actor path of a twin, ie: `[person, mary]`, and telemetry about it.
+pub type AuTwin = (Vec<String>, Vec<AuTelemetry>);
+
 /// Actors keep their state in collections of telemetry records - some derived and some
 /// are meters (last update).
 #[derive(Clone, Serialize, Deserialize)]
@@ -63,6 +282,15 @@ impl std::fmt::Display for AuOperator {
             AuOperator::Ask => write!(f, "Ask"),
             AuOperator::Tell => write!(f, "Tell"),
             AuOperator::Ls => write!(f, "Ls"),
+            AuOperator::Track => write!(f, "Track"),
+            AuOperator::Desire => write!(f, "Desire"),
+            AuOperator::Report => write!(f, "Report"),
+            AuOperator::Ack => write!(f, "Ack"),
+            AuOperator::Shadow => write!(f, "Shadow"),
+            AuOperator::Command => write!(f, "Command"),
+            AuOperator::Poll => write!(f, "Poll"),
+            AuOperator::Complete => write!(f, "Complete"),
+            AuOperator::Commands => write!(f, "Commands"),
             //AugieCmd::Ls => write!(f, "Set"),
         }
     }
@@ -104,10 +332,65 @@ mod tests {
     fn default_override_works() {
         let t = AuTelemetry {
             name: "charge_remaining".to_string(),
-            value: 0.1,
+            value: 0.1.into(),
             ..Default::default()
         };
         assert_eq!(t.name, "charge_remaining".to_string());
         assert_eq!(t.value, 0.1);
     }
+
+    #[test]
+    fn labels_work() {
+        let t: AuTelemetry = serde_json::from_str(
+            r#"{"datetime": "2019-01-01T00:00:00Z", "name": "temp", "value": 1}"#,
+        )
+        .unwrap();
+        assert_eq!(t.key(), "temp");
+        assert!(!serde_json::to_string(&t).unwrap().contains("labels"));
+        let t: AuTelemetry = serde_json::from_str(
+            r#"{"datetime": "2019-01-01T00:00:00Z", "name": "temp", "value": 1,
+                "labels": {"quality": "good", "position": "top"}}"#,
+        )
+        .unwrap();
+        assert_eq!(t.key(), r#"temp{position="top",quality="good"}"#);
+    }
+
+    #[test]
+    fn value_json_works() {
+        let values: Vec<AuValue> = serde_json::from_str(
+            r#"[1, 2.5, true, "v1.2", {"integer": 42}, {"enum": "open"}, {"number": 3}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            values,
+            vec![
+                AuValue::Number(1.0),
+                AuValue::Number(2.5),
+                AuValue::Boolean(true),
+                AuValue::String("v1.2".to_string()),
+                AuValue::Integer(42),
+                AuValue::Enum("open".to_string()),
+                AuValue::Number(3.0),
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&values).unwrap(),
+            r#"[1.0,2.5,true,"v1.2",{"integer":42},{"enum":"open"},3.0]"#
+        );
+        assert!(serde_json::from_str::<AuValue>(r#"{"integer": 1.5}"#).is_err());
+        let location: AuValue =
+            serde_json::from_str(r#"{"location": {"lat": 51.5, "lon": -0.12}}"#).unwrap();
+        assert_eq!(
+            location,
+            AuValue::Location(AuLocation::new(51.5, -0.12, None).unwrap())
+        );
+        assert_eq!(
+            serde_json::to_string(&location).unwrap(),
+            r#"{"location":{"lat":51.5,"lon":-0.12}}"#
+        );
+        assert!(serde_json::from_str::<AuValue>(r#"{"location": {"lat": 91, "lon": 0}}"#).is_err());
+        assert_eq!(AuValue::Boolean(true).as_f64(), Some(1.0));
+        assert_eq!(AuValue::Enum("open".to_string()).as_f64(), None);
+        assert_eq!(AuValue::Integer(7), 7.0);
+    }
 }
@@ -0,0 +1,283 @@
+//! MQTT ingestion bridge - an optional client subscribing to the topics of field devices.
+//!
+//! Each route maps a topic filter onto a twin path template, the levels matched by `+` filling
+//! its numbered placeholders: `site/+/sensor/+` with `/actor/site/{1}/sensor/{2}` Tells the
+//! payloads of `site/north/sensor/t1` to `/actor/site/north/sensor/t1`.  A payload may be a list
+//! of `AuTelemetry`, a single `{"name", "value"}` record, an object of fields or a bare value
+//! named by the route's `name` template - the last topic level by default.
+//!
+//! The client is built with the `mqtt` cargo feature and started only when configured.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::au::ingest::{fill, twin_path};
+use crate::au::model::{AuTelemetry, AuTwin, AuValue};
+
+/// Maps the topics matching a filter onto a twin.
+#[derive(Clone, Deserialize)]
+pub struct AuTopicRoute {
+    /// topic filter, ie: `site/+/sensor/+`
+    pub filter: String,
+    /// template of the twin path, ie: `/actor/site/{1}/sensor/{2}`
+    pub path: String,
+    /// template of the name of a bare number payload, the last topic level by default
+    pub name: Option<String>,
+    /// subscription quality of service, 0 or 1
+    #[serde(default)]
+    pub qos: u8,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive_secs: u64,
+    /// tried in order, the first mapping a topic is used
+    pub routes: Vec<AuTopicRoute>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "augorama".to_string(),
+            username: None,
+            password: None,
+            keep_alive_secs: 30,
+            routes: Vec::new(),
+        }
+    }
+}
+
+/// the topic levels matched by the `+` wildcards of a filter, `None` if the topic does not match
+fn captures(filter: &str, topic: &str) -> Option<Vec<String>> {
+    // wildcards do not match the `$SYS` style topics of the broker
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return None;
+    }
+    let mut levels = topic.split('/');
+    let mut captured = Vec::new();
+    for f in filter.split('/') {
+        match (f, levels.next()) {
+            ("#", _) => return Some(captured),
+            ("+", Some(level)) => captured.push(level.to_string()),
+            (f, Some(level)) if f == level => {}
+            _ => return None,
+        }
+    }
+    match levels.next() {
+        Some(_) => None,
+        None => Some(captured),
+    }
+}
+
+/// the telemetry in a payload, a bare value named `name`
+fn extract(payload: &[u8], name: &str) -> Result<Vec<AuTelemetry>, String> {
+    let json: Value =
+        serde_json::from_slice(payload).map_err(|e| format!("invalid payload: {}", e))?;
+    let now = Utc::now();
+    let value = |v: &Value| serde_json::from_value::<AuValue>(v.clone()).ok();
+    match json {
+        Value::Array(_) => {
+            serde_json::from_value(json).map_err(|e| format!("invalid telemetry: {}", e))
+        }
+        Value::Object(fields) => match (fields.get("name"), fields.get("value")) {
+            (Some(Value::String(name)), Some(v)) => {
+                let datetime = match fields.get("datetime").and_then(Value::as_str) {
+                    Some(d) => d
+                        .parse::<DateTime<Utc>>()
+                        .map_err(|e| format!("invalid datetime: {}", e))?,
+                    None => now,
+                };
+                let value = value(v).ok_or_else(|| format!("invalid value {}", v))?;
+                Ok(vec![AuTelemetry {
+                    datetime,
+                    name: name.clone(),
+                    value,
+                    unit: None,
+                    labels: BTreeMap::new(),
+                    violations: Vec::new(),
+                }])
+            }
+            _ => Ok(fields
+                .iter()
+                .filter_map(|(k, v)| {
+                    value(v).map(|value| AuTelemetry {
+                        datetime: now,
+                        name: k.clone(),
+                        value,
+                        unit: None,
+                        labels: BTreeMap::new(),
+                        violations: Vec::new(),
+                    })
+                })
+                .collect()),
+        },
+        v => match value(&v) {
+            Some(value) => Ok(vec![AuTelemetry {
+                datetime: now,
+                name: name.to_string(),
+                value,
+                unit: None,
+                labels: BTreeMap::new(),
+                violations: Vec::new(),
+            }]),
+            None => Err(format!("invalid payload {}", v)),
+        },
+    }
+}
+
+pub struct AuMqttBridge {
+    routes: Vec<AuTopicRoute>,
+}
+
+impl AuMqttBridge {
+    pub fn new(config: &MqttConfig) -> AuMqttBridge {
+        AuMqttBridge {
+            routes: config.routes.clone(),
+        }
+    }
+
+    /// the twin and telemetry of a message, `None` if no route maps its topic
+    pub fn map(&self, topic: &str, payload: &[u8]) -> Result<Option<AuTwin>, String> {
+        let mapped = self.routes.iter().find_map(|r| {
+            let captured = captures(&r.filter, topic)?;
+            let lookup = |k: &str| {
+                let n: usize = k.parse().ok()?;
+                captured.get(n.checked_sub(1)?).cloned()
+            };
+            let path = twin_path(&fill(&r.path, lookup)?)?;
+            let name = match &r.name {
+                Some(template) => fill(template, lookup)?,
+                None => topic.rsplit('/').next().unwrap_or_default().to_string(),
+            };
+            Some((path, name))
+        });
+        match mapped {
+            Some((path, name)) => Ok(Some((path, extract(payload, &name)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// connect to the broker and Tell the telemetry of every message routed onto a twin
+#[cfg(feature = "mqtt")]
+pub fn start<F>(config: MqttConfig, tell: F)
+where
+    F: Fn(AuTwin) + Send + 'static,
+{
+    use std::time::Duration;
+
+    use log::{debug, error, info, warn};
+    use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+
+    let bridge = AuMqttBridge::new(&config);
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 100);
+    tokio::spawn(async move {
+        info!("bridging mqtt {}:{}", config.host, config.port);
+        loop {
+            match eventloop.poll().await {
+                // subscribe again on every connection, a clean session forgets them
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    for route in config.routes.iter() {
+                        let qos = match route.qos {
+                            0 => QoS::AtMostOnce,
+                            _ => QoS::AtLeastOnce,
+                        };
+                        if let Err(e) = client.try_subscribe(&route.filter, qos) {
+                            error!("can not subscribe {}: {}", route.filter, e);
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    match bridge.map(&publish.topic, &publish.payload) {
+                        Ok(Some(twin)) => tell(twin),
+                        Ok(None) => debug!("no route maps topic {}", publish.topic),
+                        Err(e) => warn!("dropping message of {}: {}", publish.topic, e),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("mqtt connection error: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+}
+
+/// without the `mqtt` feature a configured bridge is reported and not started
+#[cfg(not(feature = "mqtt"))]
+pub fn start<F>(_config: MqttConfig, _tell: F)
+where
+    F: Fn(AuTwin) + Send + 'static,
+{
+    log::error!("mqtt is configured but augorama was built without the mqtt feature");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::mqtt::*;
+    use crate::au::path;
+
+    #[test]
+    fn captures_work() {
+        assert_eq!(
+            captures("site/+/sensor/+", "site/north/sensor/t1"),
+            Some(path(&["north", "t1"]))
+        );
+        assert_eq!(captures("site/+/sensor/+", "site/north/sensor"), None);
+        assert_eq!(captures("site/+/sensor/+", "site/north/sensor/t1/x"), None);
+        assert_eq!(
+            captures("site/+/#", "site/north/a/b"),
+            Some(path(&["north"]))
+        );
+        assert_eq!(captures("#", "$SYS/uptime"), None);
+    }
+
+    #[test]
+    fn map_works() {
+        let config: MqttConfig = serde_json::from_str(
+            r#"{"routes": [
+                {"filter": "site/+/sensor/+/temp", "path": "/actor/site/{1}/sensor/{2}"},
+                {"filter": "pump/+", "path": "/actor/pump/{1}", "name": "rpm"}
+            ]}"#,
+        )
+        .unwrap();
+        let bridge = AuMqttBridge::new(&config);
+        let (twin, telemetry) = bridge
+            .map("site/north/sensor/t1/temp", b"21.5")
+            .unwrap()
+            .unwrap();
+        assert_eq!(twin, path(&["site", "north", "sensor", "t1"]));
+        assert_eq!(telemetry[0].name, "temp");
+        assert_eq!(telemetry[0].value, 21.5);
+
+        let (_, telemetry) = bridge
+            .map("pump/p1", br#"{"name": "flow", "value": 3}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(telemetry[0].name, "flow");
+        let (_, telemetry) = bridge
+            .map("pump/p1", br#"{"rpm": 1200, "on": true, "note": "x"}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(telemetry.len(), 3);
+        assert!(bridge.map("pump/p1", b"not json").is_err());
+        assert!(bridge.map("other/p1", b"1").unwrap().is_none());
+    }
+}
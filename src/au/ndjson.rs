@@ -0,0 +1,415 @@
+//! Streamed newline delimited json ingestion, ie: `POST /ingest`.
+//!
+//! Each line addresses one twin, either with a list of telemetry:
+//!
+//! ```text
+//! {"path": "/actor/device/pump-1", "telemetry": [{"datetime": ..., "name": "rpm", "value": 1200}]}
+//! ```
+//!
+//! or with a single record, stamped with the time of arrival when it has no `datetime`:
+//!
+//! ```text
+//! {"path": "/actor/device/pump-1", "name": "rpm", "value": 1200, "labels": {"stage": "2"}}
+//! ```
+//!
+//! The body is read as it arrives and every line is Told on its own, so a backfill of millions of
+//! records is never buffered whole.  While the actors are overloaded the reading of the body waits,
+//! pushing back on the client, and a line that cannot be Told after `overload_wait_ms` is reported.
+//! A bad line does not stop the stream - the reply counts the lines accepted and rejected and
+//! describes the first errors.
+//!
+//! A signed body is the exception - it is read whole, up to `max_body_bytes`, and verified against
+//! every twin its lines address before any line is Told.  A line of an unsigned body addressing a
+//! twin with a secret is rejected.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures_util::{pin_mut, stream, Stream, StreamExt};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use warp::hyper::body::{Buf, Bytes};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::body::AuBodyError;
+use crate::au::compression::{AuDecoder, AuEncodingError};
+use crate::au::config::AuConfig;
+use crate::au::ingest::{authorize, authorize_path, credentials, twin_path};
+use crate::au::model::{AuTelemetry, AuTwin, AuValue};
+use crate::au::ratelimit::client;
+use crate::au::signing::{signature, AuSignature};
+use crate::au::tls::AuClientIdentity;
+use crate::au::twins::AuTwins;
+
+/// most line errors described in a reply
+const MAX_ERRORS: usize = 100;
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct NdjsonConfig {
+    /// longest line accepted, a longer one is reported and skipped
+    pub max_line_bytes: usize,
+    /// milliseconds a line waits for overloaded actors before it is rejected
+    pub overload_wait_ms: u64,
+}
+
+impl Default for NdjsonConfig {
+    fn default() -> Self {
+        NdjsonConfig {
+            max_line_bytes: 1024 * 1024,
+            overload_wait_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AuLine {
+    path: String,
+    telemetry: Option<Vec<AuTelemetry>>,
+    name: Option<String>,
+    value: Option<AuValue>,
+    datetime: Option<DateTime<Utc>>,
+    unit: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+/// the twin and telemetry of a line
+pub fn parse_line(line: &str) -> Result<AuTwin, String> {
+    let line: AuLine = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let path = twin_path(&line.path).ok_or_else(|| format!("invalid twin path {}", line.path))?;
+    let telemetry = match (line.telemetry, line.name, line.value) {
+        (Some(telemetry), None, None) => telemetry,
+        (None, Some(name), Some(value)) => vec![AuTelemetry {
+            datetime: line.datetime.unwrap_or_else(Utc::now),
+            name,
+            value,
+            unit: line.unit,
+            labels: line.labels,
+            violations: Vec::new(),
+        }],
+        _ => return Err("a line needs either telemetry or a name and value".to_string()),
+    };
+    Ok((path, telemetry))
+}
+
+/// Splits the chunks of a body into lines.
+pub struct AuLineBuffer {
+    max_line_bytes: usize,
+    buf: Vec<u8>,
+    /// the current line is too long and is skipped up to its newline
+    skipping: bool,
+}
+
+impl AuLineBuffer {
+    pub fn new(max_line_bytes: usize) -> AuLineBuffer {
+        AuLineBuffer {
+            max_line_bytes,
+            buf: Vec::new(),
+            skipping: false,
+        }
+    }
+
+    /// the lines a chunk completes, a line too long is an error
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Result<String, String>> {
+        let mut lines = Vec::new();
+        for segment in chunk.split_inclusive(|b| *b == b'\n') {
+            let (segment, complete) = match segment.strip_suffix(b"\n") {
+                Some(s) => (s, true),
+                None => (segment, false),
+            };
+            if !self.skipping {
+                self.buf.extend_from_slice(segment);
+                if self.buf.len() > self.max_line_bytes {
+                    self.buf.clear();
+                    self.skipping = true;
+                    lines.push(Err(format!(
+                        "line longer than {} bytes",
+                        self.max_line_bytes
+                    )));
+                }
+            }
+            if complete {
+                if !self.skipping {
+                    lines.push(self.take());
+                }
+                self.skipping = false;
+            }
+        }
+        lines
+    }
+
+    /// the last line of a body without a final newline
+    pub fn finish(&mut self) -> Option<Result<String, String>> {
+        match self.buf.is_empty() || self.skipping {
+            true => None,
+            false => Some(self.take()),
+        }
+    }
+
+    fn take(&mut self) -> Result<String, String> {
+        let line = std::mem::take(&mut self.buf);
+        String::from_utf8(line).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuLineError {
+    /// one based line number
+    pub line: usize,
+    pub error: String,
+}
+
+/// The outcome of an ingested stream.
+#[derive(Debug, Default, Serialize)]
+pub struct AuIngestReport {
+    pub accepted: usize,
+    pub rejected: usize,
+    /// the first errors
+    pub errors: Vec<AuLineError>,
+}
+
+impl AuIngestReport {
+    pub fn accept(&mut self) {
+        self.accepted += 1;
+    }
+
+    pub fn reject(&mut self, line: usize, error: String) {
+        self.rejected += 1;
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(AuLineError { line, error });
+        }
+    }
+}
+
+/// The client posting a stream.
+struct AuPoster {
+    header: Option<String>,
+    identity: Option<AuClientIdentity>,
+    /// the client of the rate limits
+    client: String,
+    /// the body was verified against every twin it addresses
+    verified: bool,
+}
+
+/// authorize, rate limit and Tell the twin of a json line, refusing a twin with a secret unless
+/// the body was verified
+async fn tell_line(
+    twins: &AuTwins,
+    line: &str,
+    poster: &AuPoster,
+    wait: Duration,
+) -> Result<(), String> {
+    let twin = parse_line(line)?;
+    if !poster.verified && twins.signing.secured(&twin.0) {
+        return Err("a twin with a secret needs a signed body".to_string());
+    }
+    authorize(
+        &twins.auth,
+        &twins.bindings,
+        poster.header.as_deref(),
+        poster.identity.as_ref(),
+        std::slice::from_ref(&twin),
+    )
+    .map_err(|e| format!("{:?}", e))?;
+    twins
+        .limiter
+        .check(&poster.client, &twin.0)
+        .map_err(|e| e.to_string())?;
+    twins
+        .tell_waiting(twin, wait)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// the whole decoded body of a signed stream, verified against every twin its lines address
+/// that the client may Tell
+async fn read_signed<S, B>(
+    twins: &AuTwins,
+    signature: &AuSignature,
+    poster: &AuPoster,
+    decoder: AuDecoder,
+    max_body_bytes: usize,
+    body: S,
+) -> Result<Bytes, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    let out = crate::au::compression::read(decoder, body, max_body_bytes)
+        .await
+        .map_err(warp::reject::custom)?;
+    let paths: Vec<Vec<String>> = out
+        .split(|b| *b == b'\n')
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .filter_map(|line| parse_line(line).ok())
+        .map(|(path, _)| path)
+        .filter(|path| {
+            authorize_path(
+                &twins.auth,
+                &twins.bindings,
+                poster.header.as_deref(),
+                poster.identity.as_ref(),
+                path,
+            )
+            .is_ok()
+        })
+        .collect();
+    twins
+        .signing
+        .verify(signature, &paths, &out)
+        .map_err(warp::reject::custom)?;
+    Ok(out)
+}
+
+/// Tell each line of a streamed body as it arrives, reading no further while a line waits
+async fn tell_lines<S, B>(
+    twins: AuTwins,
+    config: NdjsonConfig,
+    mut decoder: AuDecoder,
+    poster: AuPoster,
+    body: S,
+) -> Result<Response, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    pin_mut!(body);
+    let wait = Duration::from_millis(config.overload_wait_ms);
+    let mut buffer = AuLineBuffer::new(config.max_line_bytes);
+    let mut report = AuIngestReport::default();
+    let mut number = 0;
+    let mut ended = false;
+    while !ended {
+        let lines = match body.next().await {
+            Some(Ok(mut chunk)) => {
+                let mut lines = Vec::new();
+                while chunk.has_remaining() {
+                    let n = chunk.chunk().len();
+                    match decoder.push(chunk.chunk()) {
+                        Ok(bytes) => lines.extend(buffer.push(&bytes)),
+                        Err(e) => return Err(warp::reject::custom(e)),
+                    }
+                    chunk.advance(n);
+                }
+                lines
+            }
+            Some(Err(e)) => {
+                warn!("ingest stream broken after line {}: {}", number, e);
+                return Err(warp::reject::custom(AuBodyError(e.to_string())));
+            }
+            None => {
+                ended = true;
+                let decoder = std::mem::replace(&mut decoder, AuDecoder::Identity);
+                let mut lines = match decoder.finish() {
+                    Ok(bytes) => buffer.push(&bytes),
+                    Err(e) => return Err(warp::reject::custom(e)),
+                };
+                lines.extend(buffer.finish());
+                lines
+            }
+        };
+        for line in lines {
+            number += 1;
+            let result = match line {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => tell_line(&twins, &line, &poster, wait).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => report.accept(),
+                Err(e) => report.reject(number, e),
+            }
+        }
+    }
+    Ok(warp::reply::json(&report).into_response())
+}
+
+/// `POST /ingest`
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let ndjson = config.ndjson.clone();
+    let max_body_bytes = config.compression.max_body_bytes;
+    let twins = twins.clone();
+    warp::path("ingest")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(credentials())
+        .and(client(twins.auth.clone()))
+        .and(signature())
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::stream())
+        .and_then(
+            move |header,
+                  identity,
+                  client: String,
+                  signature: AuSignature,
+                  encoding: Option<String>,
+                  body| {
+                let twins = twins.clone();
+                let ndjson = ndjson.clone();
+                async move {
+                    let decoder = AuDecoder::new(encoding.as_deref(), max_body_bytes)
+                        .map_err(warp::reject::custom)?;
+                    let mut poster = AuPoster {
+                        header,
+                        identity,
+                        client,
+                        verified: false,
+                    };
+                    if !signature.present() {
+                        return tell_lines(twins, ndjson, decoder, poster, body).await;
+                    }
+                    let body =
+                        read_signed(&twins, &signature, &poster, decoder, max_body_bytes, body)
+                            .await?;
+                    let body = stream::once(async { Ok::<_, warp::Error>(body) });
+                    poster.verified = true;
+                    tell_lines(twins, ndjson, AuDecoder::Identity, poster, body).await
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::ndjson::*;
+
+    #[test]
+    fn parse_line_works() {
+        let (path, telemetry) =
+            parse_line(r#"{"path": "/actor/device/P1", "name": "rpm", "value": 1200}"#).unwrap();
+        assert_eq!(path, vec!["device".to_string(), "p1".to_string()]);
+        assert_eq!(telemetry[0].value, 1200.0);
+        let (_, telemetry) = parse_line(
+            r#"{"path": "/actor/device/p1", "telemetry": [
+                {"datetime": "2019-01-01T00:00:00Z", "name": "rpm", "value": 1}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(telemetry.len(), 1);
+        assert!(parse_line(r#"{"path": "/actor/device", "name": "rpm", "value": 1}"#).is_err());
+        assert!(parse_line(r#"{"path": "/actor/device/p1", "name": "rpm"}"#).is_err());
+        assert!(parse_line("not json").is_err());
+    }
+
+    #[test]
+    fn line_buffer_works() {
+        let mut buffer = AuLineBuffer::new(8);
+        assert!(buffer.push(b"ab").is_empty());
+        let lines = buffer.push(b"c\n\nde\nfghijklmnop");
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], Ok("abc".to_string()));
+        assert_eq!(lines[1], Ok(String::new()));
+        assert_eq!(lines[2], Ok("de".to_string()));
+        assert!(lines[3].is_err());
+        assert!(buffer.push(b"q\nrs").is_empty());
+        assert_eq!(buffer.finish(), Some(Ok("rs".to_string())));
+        assert_eq!(buffer.finish(), None);
+    }
+}
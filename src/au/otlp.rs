@@ -0,0 +1,549 @@
+//! OpenTelemetry OTLP/HTTP metrics receiver, ie: `POST /v1/metrics`.
+//!
+//! The body is an `ExportMetricsServiceRequest` encoded as protobuf or, with a json content type,
+//! as OTLP/JSON.  The metrics of a resource are mapped onto a twin by the first rule whose `match`
+//! attributes the resource carries - the rule's `path` template is filled from the resource
+//! attributes and its `name` template from `{metric}` and the attributes of each data point, then
+//! of the resource.  Every gauge and sum data point becomes an `AuTelemetry` labelled with the
+//! point attributes the name does not use and carrying the `unit` of its metric, other metric kinds
+//! are skipped.  Resources no rule maps are dropped.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use log::debug;
+use prost::{Message, Oneof};
+use serde::Deserialize;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::config::AuConfig;
+use crate::au::ingest::{credentials, fill, group, twin_path, unfilled, AuTellError};
+use crate::au::model::{AuTelemetry, AuTwin, AuValue};
+use crate::au::tls::AuClientIdentity;
+use crate::au::twins::{tell_reply, AuTwins};
+
+#[derive(Clone, PartialEq, Message)]
+struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ScopeMetrics {
+    #[prost(message, repeated, tag = "2")]
+    metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Metric {
+    #[prost(string, tag = "1")]
+    name: String,
+    /// UCUM code, ie: `ms`
+    #[prost(string, tag = "3")]
+    unit: String,
+    #[prost(oneof = "MetricData", tags = "5, 7")]
+    data: Option<MetricData>,
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+enum MetricData {
+    #[prost(message, tag = "5")]
+    Gauge(Points),
+    #[prost(message, tag = "7")]
+    Sum(Points),
+}
+
+/// the data points of a gauge or a sum
+#[derive(Clone, PartialEq, Message)]
+struct Points {
+    #[prost(message, repeated, tag = "1")]
+    data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    time_unix_nano: u64,
+    #[prost(oneof = "NumberValue", tags = "4, 6")]
+    value: Option<NumberValue>,
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+enum NumberValue {
+    #[prost(double, tag = "4")]
+    AsDouble(f64),
+    #[prost(sfixed64, tag = "6")]
+    AsInt(i64),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct KeyValue {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(message, optional, tag = "2")]
+    value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct AnyValue {
+    #[prost(oneof = "Value", tags = "1, 2, 3, 4")]
+    value: Option<Value>,
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+enum Value {
+    #[prost(string, tag = "1")]
+    String(String),
+    #[prost(bool, tag = "2")]
+    Bool(bool),
+    #[prost(int64, tag = "3")]
+    Int(i64),
+    #[prost(double, tag = "4")]
+    Double(f64),
+}
+
+/// OTLP/JSON writes 64 bit integers as strings
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonInt {
+    Number(i64),
+    Text(String),
+}
+
+impl JsonInt {
+    fn value(&self) -> Result<i64, String> {
+        match self {
+            JsonInt::Number(n) => Ok(*n),
+            JsonInt::Text(s) => s.parse().map_err(|_| format!("invalid integer {}", s)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonRequest {
+    #[serde(default)]
+    resource_metrics: Vec<JsonResourceMetrics>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonResourceMetrics {
+    resource: Option<JsonResource>,
+    #[serde(default)]
+    scope_metrics: Vec<JsonScopeMetrics>,
+}
+
+#[derive(Deserialize)]
+struct JsonResource {
+    #[serde(default)]
+    attributes: Vec<JsonKeyValue>,
+}
+
+#[derive(Deserialize)]
+struct JsonScopeMetrics {
+    #[serde(default)]
+    metrics: Vec<JsonMetric>,
+}
+
+#[derive(Deserialize)]
+struct JsonMetric {
+    name: String,
+    #[serde(default)]
+    unit: String,
+    gauge: Option<JsonPoints>,
+    sum: Option<JsonPoints>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonPoints {
+    #[serde(default)]
+    data_points: Vec<JsonPoint>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonPoint {
+    #[serde(default)]
+    attributes: Vec<JsonKeyValue>,
+    time_unix_nano: Option<JsonInt>,
+    as_double: Option<f64>,
+    as_int: Option<JsonInt>,
+}
+
+#[derive(Deserialize)]
+struct JsonKeyValue {
+    key: String,
+    value: Option<JsonAnyValue>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonAnyValue {
+    string_value: Option<String>,
+    bool_value: Option<bool>,
+    int_value: Option<JsonInt>,
+    double_value: Option<f64>,
+}
+
+impl JsonRequest {
+    /// the protobuf message of the same content
+    fn into_message(self) -> Result<ExportMetricsServiceRequest, String> {
+        let mut resource_metrics = Vec::new();
+        for rm in self.resource_metrics {
+            let mut metrics = Vec::new();
+            for m in rm.scope_metrics.into_iter().flat_map(|s| s.metrics) {
+                let data = match (m.gauge, m.sum) {
+                    (Some(points), _) => Some(MetricData::Gauge(points.into_message()?)),
+                    (None, Some(points)) => Some(MetricData::Sum(points.into_message()?)),
+                    (None, None) => None,
+                };
+                metrics.push(Metric {
+                    name: m.name,
+                    unit: m.unit,
+                    data,
+                });
+            }
+            resource_metrics.push(ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: attributes(rm.resource.map(|r| r.attributes).unwrap_or_default())?,
+                }),
+                scope_metrics: vec![ScopeMetrics { metrics }],
+            });
+        }
+        Ok(ExportMetricsServiceRequest { resource_metrics })
+    }
+}
+
+impl JsonPoints {
+    fn into_message(self) -> Result<Points, String> {
+        let mut data_points = Vec::new();
+        for p in self.data_points {
+            let value = match (p.as_double, p.as_int) {
+                (Some(d), _) => Some(NumberValue::AsDouble(d)),
+                (None, Some(i)) => Some(NumberValue::AsInt(i.value()?)),
+                (None, None) => None,
+            };
+            let time_unix_nano = match p.time_unix_nano {
+                Some(t) => t.value()? as u64,
+                None => 0,
+            };
+            data_points.push(NumberDataPoint {
+                attributes: attributes(p.attributes)?,
+                time_unix_nano,
+                value,
+            });
+        }
+        Ok(Points { data_points })
+    }
+}
+
+fn attributes(json: Vec<JsonKeyValue>) -> Result<Vec<KeyValue>, String> {
+    let mut attributes = Vec::new();
+    for kv in json {
+        let value = match kv.value {
+            Some(v) => match (v.string_value, v.bool_value, v.int_value, v.double_value) {
+                (Some(s), _, _, _) => Some(Value::String(s)),
+                (_, Some(b), _, _) => Some(Value::Bool(b)),
+                (_, _, Some(i), _) => Some(Value::Int(i.value()?)),
+                (_, _, _, Some(d)) => Some(Value::Double(d)),
+                _ => None,
+            },
+            None => None,
+        };
+        attributes.push(KeyValue {
+            key: kv.key,
+            value: Some(AnyValue { value }),
+        });
+    }
+    Ok(attributes)
+}
+
+/// attributes with a scalar value, as strings
+fn attribute_map(attributes: &[KeyValue]) -> HashMap<String, String> {
+    attributes
+        .iter()
+        .filter_map(|kv| {
+            let value = match kv.value.as_ref()?.value.as_ref()? {
+                Value::String(s) => s.clone(),
+                Value::Bool(b) => b.to_string(),
+                Value::Int(i) => i.to_string(),
+                Value::Double(d) => d.to_string(),
+            };
+            Some((kv.key.clone(), value))
+        })
+        .collect()
+}
+
+fn default_name() -> String {
+    "{metric}".to_string()
+}
+
+/// Maps the metrics of the resources carrying the `match` attributes onto a twin.
+#[derive(Clone, Deserialize)]
+pub struct AuResourceRule {
+    /// resource attributes and the values a resource must carry
+    #[serde(rename = "match", default)]
+    pub matches: HashMap<String, String>,
+    /// template of the twin path, ie: `/actor/service/{service.name}`
+    pub path: String,
+    /// template of the telemetry name, `{metric}` by default
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OtlpConfig {
+    /// tried in order, the first mapping a resource is used
+    pub rules: Vec<AuResourceRule>,
+}
+
+pub struct AuOtlp {
+    rules: Vec<AuResourceRule>,
+}
+
+impl AuOtlp {
+    pub fn new(config: &OtlpConfig) -> AuOtlp {
+        AuOtlp {
+            rules: config.rules.clone(),
+        }
+    }
+
+    /// the telemetry of the twins in a protobuf or, if `json` is set, an OTLP/JSON request
+    pub fn decode(&self, body: &[u8], json: bool) -> Result<Vec<AuTwin>, String> {
+        let request = if json {
+            serde_json::from_slice::<JsonRequest>(body)
+                .map_err(|e| format!("invalid otlp json: {}", e))?
+                .into_message()?
+        } else {
+            ExportMetricsServiceRequest::decode(body)
+                .map_err(|e| format!("invalid otlp protobuf: {}", e))?
+        };
+        let mut records = Vec::new();
+        for rm in request.resource_metrics {
+            let resource = attribute_map(&rm.resource.map(|r| r.attributes).unwrap_or_default());
+            let rule = self
+                .rules
+                .iter()
+                .filter(|r| r.matches.iter().all(|(k, v)| resource.get(k) == Some(v)))
+                .find_map(|r| Some((twin_path(&fill(&r.path, |k| resource.get(k).cloned())?)?, r)));
+            let (path, rule) = match rule {
+                Some(mapped) => mapped,
+                None => {
+                    debug!("no rule maps resource {:?}", resource.get("service.name"));
+                    continue;
+                }
+            };
+            for metric in rm.scope_metrics.into_iter().flat_map(|s| s.metrics) {
+                let points = match metric.data {
+                    Some(MetricData::Gauge(points)) | Some(MetricData::Sum(points)) => points,
+                    None => continue,
+                };
+                let unit = Some(metric.unit).filter(|u| !u.is_empty());
+                for point in points.data_points {
+                    let value = match point.value {
+                        Some(NumberValue::AsDouble(d)) => AuValue::Number(d),
+                        Some(NumberValue::AsInt(i)) => AuValue::Integer(i),
+                        None => continue,
+                    };
+                    let attributes = attribute_map(&point.attributes);
+                    let name = fill(&rule.name, |k| match k {
+                        "metric" => Some(metric.name.clone()),
+                        _ => attributes.get(k).or_else(|| resource.get(k)).cloned(),
+                    });
+                    let name = match name {
+                        Some(name) => name,
+                        None => continue,
+                    };
+                    let datetime = match point.time_unix_nano {
+                        0 => Utc::now(),
+                        ns => Utc.timestamp_nanos(ns.min(i64::MAX as u64) as i64),
+                    };
+                    records.push((
+                        path.clone(),
+                        AuTelemetry {
+                            datetime,
+                            name,
+                            value,
+                            unit: unit.clone(),
+                            labels: unfilled(&attributes, &[rule.name.as_str()]),
+                            violations: Vec::new(),
+                        },
+                    ));
+                }
+            }
+        }
+        Ok(group(records))
+    }
+}
+
+/// the reply to an OTLP export - an empty export response in the request's encoding, 503 while
+/// overloaded
+fn otlp_reply(json: bool, result: Result<String, AuTellError>) -> Response {
+    match result {
+        Ok(_) if json => {
+            warp::reply::with_header("{}", "Content-Type", "application/json").into_response()
+        }
+        Ok(_) => {
+            warp::reply::with_header("", "Content-Type", "application/x-protobuf").into_response()
+        }
+        Err(_) => tell_reply(result),
+    }
+}
+
+/// `POST /v1/metrics`
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let otlp = Arc::new(AuOtlp::new(&config.otlp));
+    let twins = twins.clone();
+    warp::path("v1")
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(crate::au::body::twins(
+            &twins,
+            warp::header::optional::<String>("content-type").map(|content_type: Option<String>| {
+                content_type
+                    .map(|c| c.starts_with("application/json"))
+                    .unwrap_or(false)
+            }),
+            crate::au::compression::body(config.compression),
+            move |json: &bool, body: &[u8]| otlp.decode(body, *json),
+        ))
+        .and(credentials())
+        .and_then(
+            move |json: bool,
+                  decoded: Vec<AuTwin>,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let result = twins
+                    .tell(decoded, header, identity)
+                    .map(|result| otlp_reply(json, result));
+                async move { result }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::otlp::*;
+
+    fn otlp() -> AuOtlp {
+        let config: OtlpConfig = serde_json::from_str(
+            r#"{"rules": [
+                {"match": {"service.namespace": "shop"}, "path": "/actor/service/{service.name}"}
+            ]}"#,
+        )
+        .unwrap();
+        AuOtlp::new(&config)
+    }
+
+    fn string_attribute(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(Value::String(value.to_string())),
+            }),
+        }
+    }
+
+    #[test]
+    fn decode_protobuf_works() {
+        let point = |value| NumberDataPoint {
+            attributes: Vec::new(),
+            time_unix_nano: 1_000_000_000,
+            value: Some(value),
+        };
+        let request = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![
+                        string_attribute("service.name", "cart"),
+                        string_attribute("service.namespace", "shop"),
+                    ],
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    metrics: vec![
+                        Metric {
+                            name: "queue.depth".to_string(),
+                            unit: "1".to_string(),
+                            data: Some(MetricData::Gauge(Points {
+                                data_points: vec![point(NumberValue::AsDouble(2.5))],
+                            })),
+                        },
+                        Metric {
+                            name: "orders".to_string(),
+                            unit: String::new(),
+                            data: Some(MetricData::Sum(Points {
+                                data_points: vec![point(NumberValue::AsInt(7))],
+                            })),
+                        },
+                    ],
+                }],
+            }],
+        };
+        let twins = otlp().decode(&request.encode_to_vec(), false).unwrap();
+        assert_eq!(twins.len(), 1);
+        assert_eq!(twins[0].0, vec!["service".to_string(), "cart".to_string()]);
+        assert_eq!(twins[0].1[1].name, "orders");
+        assert_eq!(twins[0].1[1].value, 7.0);
+        assert_eq!(twins[0].1[1].unit, None);
+        assert_eq!(twins[0].1[0].unit.as_deref(), Some("1"));
+        assert_eq!(twins[0].1[0].datetime.timestamp(), 1);
+    }
+
+    #[test]
+    fn decode_json_works() {
+        let body = br#"{"resourceMetrics": [{
+            "resource": {"attributes": [
+                {"key": "service.name", "value": {"stringValue": "cart"}},
+                {"key": "service.namespace", "value": {"stringValue": "shop"}}
+            ]},
+            "scopeMetrics": [{"metrics": [
+                {"name": "queue.depth", "unit": "{message}", "gauge": {"dataPoints": [
+                    {"timeUnixNano": "2000000000", "asDouble": 3.5, "attributes": [
+                        {"key": "queue", "value": {"stringValue": "orders"}}
+                    ]}
+                ]}},
+                {"name": "latency", "histogram": {"dataPoints": []}}
+            ]}]
+        }, {
+            "resource": {"attributes": [
+                {"key": "service.name", "value": {"stringValue": "other"}}
+            ]},
+            "scopeMetrics": [{"metrics": [
+                {"name": "up", "gauge": {"dataPoints": [{"asInt": "1"}]}}
+            ]}]
+        }]}"#;
+        let twins = otlp().decode(body, true).unwrap();
+        assert_eq!(twins.len(), 1);
+        assert_eq!(twins[0].1.len(), 1);
+        assert_eq!(twins[0].1[0].value, 3.5);
+        assert_eq!(twins[0].1[0].datetime.timestamp(), 2);
+        assert_eq!(twins[0].1[0].labels["queue"], "orders");
+        assert_eq!(twins[0].1[0].unit.as_deref(), Some("{message}"));
+        assert!(otlp().decode(b"{", true).is_err());
+    }
+}
@@ -0,0 +1,567 @@
+//! Token bucket rate limiting of the requests addressing twins.
+//!
+//! Every request takes a token from the bucket of its client and from the bucket of the twin it
+//! addresses - a body addressing many twins, ie: to `/batch` or `/write`, takes one from each
+//! bucket for each of its twins and is refused whole unless all are there, while each line of an
+//! `/ingest` stream is limited on its own.  A client is known by its bearer token principal, else
+//! its certificate name, else its address.  Buckets refill at `rate` tokens per second up to
+//! `burst` tokens and the limits may be overridden per root type.  A request finding a bucket
+//! empty is answered with a 429 and a `Retry-After` header, one wanting more tokens than a bucket
+//! holds - a body addressing more twins than `burst` - with a 413 as it would never be accepted.
+//! At most `max_buckets` buckets are kept, the least recently used is dropped for a new one.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::auth::AuAuth;
+use crate::au::config::AuConfig;
+use crate::au::ingest::twin_path;
+use crate::au::metrics::RATE_LIMITED;
+use crate::au::tls::{AuClientIdentity, AuPeer};
+use crate::au::twins::AuTwins;
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct AuBucketConfig {
+    /// tokens added per second
+    pub rate: f64,
+    /// most tokens a bucket holds - the largest burst of requests accepted at once
+    pub burst: f64,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuLimits {
+    /// limit of each client
+    pub client: Option<AuBucketConfig>,
+    /// limit of each twin
+    pub twin: Option<AuBucketConfig>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// the limits of requests to any root type
+    pub default: AuLimits,
+    /// the limits of requests to a root type, replacing the defaults they set
+    pub types: HashMap<String, AuLimits>,
+    /// buckets kept before the least recently used are dropped
+    pub max_buckets: usize,
+}
+
+impl RateLimitConfig {
+    /// refuse limits that would reject every request or never refill
+    pub fn validate(&self) -> Result<(), String> {
+        let limits = std::iter::once(("default", &self.default))
+            .chain(self.types.iter().map(|(root, l)| (root.as_str(), l)));
+        for (root, limits) in limits {
+            let buckets = [("client", limits.client), ("twin", limits.twin)];
+            for (name, limit) in buckets.iter().filter_map(|(n, l)| l.map(|l| (n, l))) {
+                if !(limit.rate.is_finite() && limit.rate > 0.0) {
+                    return Err(format!(
+                        "invalid config: rate_limit {} {} rate must be above 0",
+                        root, name
+                    ));
+                }
+                if !(limit.burst.is_finite() && limit.burst >= 1.0) {
+                    return Err(format!(
+                        "invalid config: rate_limit {} {} burst must be at least 1",
+                        root, name
+                    ));
+                }
+            }
+        }
+        if self.max_buckets == 0 {
+            return Err("invalid config: rate_limit max_buckets must be above 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            default: AuLimits::default(),
+            types: HashMap::new(),
+            max_buckets: 100_000,
+        }
+    }
+}
+
+/// A request refused by the limiter.
+#[derive(Debug, PartialEq)]
+pub enum AuRateLimited {
+    /// a bucket is empty - 429 after whole seconds until it holds the tokens wanted again
+    Empty { retry_after: u64 },
+    /// the request wants more tokens than a bucket holds at most - 413
+    TooCostly { tokens: f64, burst: f64 },
+}
+
+impl std::fmt::Display for AuRateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuRateLimited::Empty { retry_after } => {
+                write!(f, "rate limited, retry after {}s", retry_after)
+            }
+            AuRateLimited::TooCostly { tokens, burst } => {
+                write!(
+                    f,
+                    "wants {} tokens, more than the burst of {}",
+                    tokens, burst
+                )
+            }
+        }
+    }
+}
+
+impl warp::reject::Reject for AuRateLimited {}
+
+/// Counts of requests rejected by the limiter.
+#[derive(Clone, Default, Serialize)]
+pub struct AuRateLimitStats {
+    /// rejected for exceeding the client limit
+    pub client: u64,
+    /// rejected for exceeding the twin limit
+    pub twin: u64,
+    /// rejections by root type
+    pub types: HashMap<String, u64>,
+}
+
+struct AuBucket {
+    tokens: f64,
+    updated: Instant,
+    limit: AuBucketConfig,
+}
+
+impl AuBucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.updated = now;
+    }
+}
+
+/// The tokens a request wants from a bucket.
+struct AuWanted {
+    key: String,
+    limit: AuBucketConfig,
+    is_client: bool,
+    root: String,
+    tokens: f64,
+}
+
+/// The buckets by key and their keys by last use, least recent first.
+#[derive(Default)]
+struct AuBuckets {
+    buckets: HashMap<String, AuBucket>,
+    used: BTreeSet<(Instant, String)>,
+}
+
+impl AuBuckets {
+    /// refill the bucket of a key, or add a full one, and mark it used now
+    fn touch(&mut self, now: Instant, key: &str, limit: AuBucketConfig) {
+        match self.buckets.get_mut(key) {
+            Some(bucket) => {
+                self.used.remove(&(bucket.updated, key.to_string()));
+                bucket.refill(now);
+            }
+            None => {
+                self.buckets.insert(
+                    key.to_string(),
+                    AuBucket {
+                        tokens: limit.burst,
+                        updated: now,
+                        limit,
+                    },
+                );
+            }
+        }
+        self.used.insert((now, key.to_string()));
+    }
+
+    /// drop the least recently used buckets, but those of `keep`, until `count` fit
+    fn evict(&mut self, count: usize, keep: &[AuWanted]) {
+        let excess = self.buckets.len().saturating_sub(count);
+        let evicted: Vec<(Instant, String)> = self
+            .used
+            .iter()
+            .filter(|(_, key)| !keep.iter().any(|w| &w.key == key))
+            .take(excess)
+            .cloned()
+            .collect();
+        for used in evicted {
+            self.buckets.remove(&used.1);
+            self.used.remove(&used);
+        }
+    }
+}
+
+pub struct AuRateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<AuBuckets>,
+    stats: Mutex<AuRateLimitStats>,
+}
+
+impl AuRateLimiter {
+    pub fn new(config: &RateLimitConfig) -> AuRateLimiter {
+        AuRateLimiter {
+            config: config.clone(),
+            buckets: Mutex::new(AuBuckets::default()),
+            stats: Mutex::new(AuRateLimitStats::default()),
+        }
+    }
+
+    pub fn stats(&self) -> AuRateLimitStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// take a token for the client and the twin at the actor path, ie: `[person, mary]`
+    pub fn check(&self, client: &str, path: &[String]) -> Result<(), AuRateLimited> {
+        self.check_all(client, &[path.to_vec()])
+    }
+
+    /// take a token for the client and the twin of each actor path of a request, none unless
+    /// every bucket holds the tokens wanted
+    pub fn check_all(&self, client: &str, paths: &[Vec<String>]) -> Result<(), AuRateLimited> {
+        self.check_at(Instant::now(), client, paths)
+    }
+
+    /// the buckets a request takes tokens from - their key, limit, root and tokens wanted
+    fn wanted(&self, client: &str, paths: &[Vec<String>]) -> Vec<AuWanted> {
+        let mut wanted: Vec<AuWanted> = Vec::new();
+        let mut want =
+            |key: String, limit: AuBucketConfig, is_client: bool, root: &str| match wanted
+                .iter_mut()
+                .find(|w| w.key == key)
+            {
+                Some(w) => w.tokens += 1.0,
+                None => wanted.push(AuWanted {
+                    key,
+                    limit,
+                    is_client,
+                    root: root.to_string(),
+                    tokens: 1.0,
+                }),
+            };
+        for path in paths {
+            let root = match path.first() {
+                Some(root) => root,
+                None => continue,
+            };
+            let limits = self.config.types.get(root);
+            match limits.and_then(|l| l.client) {
+                Some(limit) => want(format!("client:{}:{}", root, client), limit, true, root),
+                None => {
+                    if let Some(limit) = self.config.default.client {
+                        want(format!("client:{}", client), limit, true, root)
+                    }
+                }
+            }
+            if let Some(limit) = limits.and_then(|l| l.twin).or(self.config.default.twin) {
+                let twin = path.join("/").to_lowercase();
+                want(format!("twin:{}", twin), limit, false, root);
+            }
+        }
+        wanted
+    }
+
+    fn check_at(
+        &self,
+        now: Instant,
+        client: &str,
+        paths: &[Vec<String>],
+    ) -> Result<(), AuRateLimited> {
+        let wanted = self.wanted(client, paths);
+        if wanted.is_empty() {
+            return Ok(());
+        }
+
+        // a request wanting more than a bucket holds would wait forever
+        let refused = match wanted.iter().find(|w| w.tokens > w.limit.burst) {
+            Some(w) => Some((
+                w,
+                AuRateLimited::TooCostly {
+                    tokens: w.tokens,
+                    burst: w.limit.burst,
+                },
+            )),
+            None => {
+                let mut buckets = self.buckets.lock().unwrap();
+                let new = wanted
+                    .iter()
+                    .filter(|w| !buckets.buckets.contains_key(&w.key))
+                    .count();
+                if new > 0 {
+                    let count = self.config.max_buckets.saturating_sub(new);
+                    buckets.evict(count, &wanted);
+                }
+                for w in wanted.iter() {
+                    buckets.touch(now, &w.key, w.limit);
+                }
+                // take from any bucket only if every one holds the tokens wanted
+                let empty = wanted.iter().find(|w| {
+                    buckets.buckets.get(&w.key).map(|b| b.tokens < w.tokens) == Some(true)
+                });
+                match empty {
+                    Some(w) => {
+                        let tokens = buckets.buckets.get(&w.key).map(|b| b.tokens).unwrap_or(0.0);
+                        let retry_after =
+                            ((w.tokens - tokens) / w.limit.rate).ceil().max(1.0) as u64;
+                        Some((w, AuRateLimited::Empty { retry_after }))
+                    }
+                    None => {
+                        for w in wanted.iter() {
+                            if let Some(b) = buckets.buckets.get_mut(&w.key) {
+                                b.tokens -= w.tokens;
+                            }
+                        }
+                        None
+                    }
+                }
+            }
+        };
+        match refused {
+            Some((w, refused)) => {
+                debug!("rate limited {}: {}", w.key, refused);
+                let limit = if w.is_client { "client" } else { "twin" };
+                RATE_LIMITED.with_label_values(&[limit, &w.root]).inc();
+                let mut stats = self.stats.lock().unwrap();
+                if w.is_client {
+                    stats.client += 1;
+                } else {
+                    stats.twin += 1;
+                }
+                *stats.types.entry(w.root.clone()).or_insert(0) += 1;
+                Err(refused)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// the client of a request - its bearer token principal, else its certificate name, else its
+/// address
+pub fn client(auth: Arc<AuAuth>) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::ext::optional::<AuClientIdentity>())
+        .and(warp::ext::optional::<AuPeer>())
+        .and(warp::addr::remote())
+        .map(
+            move |header: Option<String>,
+                  identity: Option<AuClientIdentity>,
+                  peer: Option<AuPeer>,
+                  remote: Option<SocketAddr>| {
+                let principal = match header {
+                    Some(h) if auth.enabled() => auth.authenticate(Some(&h)).ok(),
+                    _ => None,
+                };
+                match (principal, identity, peer.map(|p| p.0).or(remote)) {
+                    (Some(principal), _, _) => format!("principal:{}", principal),
+                    (None, Some(identity), _) if !identity.names.is_empty() => {
+                        format!("cert:{}", identity.names[0])
+                    }
+                    (None, _, Some(addr)) => format!("addr:{}", addr.ip()),
+                    _ => "unknown".to_string(),
+                }
+            },
+        )
+}
+
+/// the actor path a request addresses before its body is read - below `/actor` or in the `path`
+/// query parameter of `/shadow` and `/commands`
+fn request_path(path: &str, query: &HashMap<String, String>) -> Option<Vec<String>> {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    match segments.next() {
+        Some("actor") => {
+            let mut segments: Vec<String> = segments.map(|s| s.to_string()).collect();
+            if segments.last().map(String::as_str) == Some("children") {
+                segments.pop();
+            }
+            Some(segments)
+        }
+        Some("shadow") | Some("commands") => query.get("path").and_then(|p| twin_path(p)),
+        _ => None,
+    }
+}
+
+/// rejects `/actor`, `/shadow` and `/commands` requests of clients or to twins that exceeded their
+/// rate.  the twins of a posted body are limited once it is decoded.
+pub fn guard(
+    limiter: Arc<AuRateLimiter>,
+    auth: Arc<AuAuth>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::full()
+        .and(
+            warp::query::<HashMap<String, String>>()
+                .or(warp::any().map(HashMap::new))
+                .unify(),
+        )
+        .and(client(auth))
+        .and_then(
+            move |path: FullPath, query: HashMap<String, String>, client: String| {
+                let limiter = limiter.clone();
+                async move {
+                    match request_path(path.as_str(), &query) {
+                        Some(segments) => limiter
+                            .check(&client, &segments)
+                            .map_err(warp::reject::custom),
+                        None => Ok(()),
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// `GET /stats/rate_limit`, the buckets of the limiter - naming clients and twins, so guarded
+/// like `/metrics`
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let limiter = twins.limiter.clone();
+    warp::path("stats")
+        .and(warp::path("rate_limit"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(crate::au::metrics::scraper(twins, config))
+        .map(move || warp::reply::json(&limiter.stats()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::au::path;
+    use crate::au::ratelimit::*;
+
+    fn limiter() -> AuRateLimiter {
+        let config: RateLimitConfig = serde_json::from_str(
+            r#"{
+                "default": {"client": {"rate": 1.0, "burst": 2.0}},
+                "types": {"device": {"twin": {"rate": 0.5, "burst": 1.0}}}
+            }"#,
+        )
+        .unwrap();
+        AuRateLimiter::new(&config)
+    }
+
+    fn retry_after(refused: Result<(), AuRateLimited>) -> u64 {
+        match refused {
+            Err(AuRateLimited::Empty { retry_after }) => retry_after,
+            other => panic!("not empty: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate_works() {
+        assert!(RateLimitConfig::default().validate().is_ok());
+        let config = |json: &str| serde_json::from_str::<RateLimitConfig>(json).unwrap();
+        let valid = r#"{"default": {"client": {"rate": 0.5, "burst": 1.0}}}"#;
+        assert!(config(valid).validate().is_ok());
+        let invalid = [
+            r#"{"default": {"client": {"rate": 0.0, "burst": 2.0}}}"#,
+            r#"{"default": {"twin": {"rate": -1.0, "burst": 2.0}}}"#,
+            r#"{"types": {"device": {"twin": {"rate": 1.0, "burst": 0.5}}}}"#,
+            r#"{"max_buckets": 0}"#,
+        ];
+        for json in invalid.iter() {
+            assert!(config(json).validate().is_err(), "{}", json);
+        }
+        assert!(crate::au::config::AuConfig::from_json(
+            r#"{"rate_limit": {"default": {"client": {"rate": 0.0, "burst": 2.0}}}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn client_limit_works() {
+        let l = limiter();
+        let now = Instant::now();
+        let mary = [path(&["person", "mary"])];
+        assert!(l.check_at(now, "a", &mary).is_ok());
+        assert!(l.check_at(now, "a", &mary).is_ok());
+        assert_eq!(retry_after(l.check_at(now, "a", &mary)), 1);
+        assert!(l.check_at(now, "b", &mary).is_ok());
+        let later = now + Duration::from_secs(1);
+        assert!(l.check_at(later, "a", &mary).is_ok());
+        assert_eq!(l.stats().client, 1);
+    }
+
+    #[test]
+    fn twin_limit_works() {
+        let l = limiter();
+        let now = Instant::now();
+        let thermostat = [path(&["device", "thermostat-42"])];
+        assert!(l.check_at(now, "a", &thermostat).is_ok());
+        assert_eq!(retry_after(l.check_at(now, "b", &thermostat)), 2);
+        assert!(l.check_at(now, "b", &[path(&["device", "pump-1"])]).is_ok());
+        let stats = l.stats();
+        assert_eq!(stats.twin, 1);
+        assert_eq!(stats.types.get("device"), Some(&1));
+    }
+
+    #[test]
+    fn many_twins_work() {
+        let l = limiter();
+        let now = Instant::now();
+        let mary = path(&["person", "mary"]);
+        let bob = path(&["person", "bob"]);
+        let thermostat = path(&["device", "thermostat-42"]);
+        // a batch takes a client token per twin and none unless all are there
+        assert!(l.check_at(now, "a", &[mary.clone(), bob.clone()]).is_ok());
+        assert_eq!(
+            retry_after(l.check_at(now, "a", &[mary.clone(), bob.clone()])),
+            2
+        );
+        assert!(l
+            .check_at(now, "b", std::slice::from_ref(&thermostat))
+            .is_ok());
+        assert!(l.check_at(now, "b", &[bob.clone()]).is_ok());
+        // more than a bucket ever holds is refused outright
+        assert_eq!(
+            l.check_at(now, "c", &[mary, bob, thermostat.clone()]),
+            Err(AuRateLimited::TooCostly {
+                tokens: 3.0,
+                burst: 2.0
+            })
+        );
+        assert_eq!(
+            l.check_at(now, "d", &[thermostat.clone(), thermostat]),
+            Err(AuRateLimited::TooCostly {
+                tokens: 2.0,
+                burst: 1.0
+            })
+        );
+    }
+
+    #[test]
+    fn max_buckets_works() {
+        let config: RateLimitConfig = serde_json::from_str(
+            r#"{"default": {"client": {"rate": 1.0, "burst": 1.0}}, "max_buckets": 2}"#,
+        )
+        .unwrap();
+        let l = AuRateLimiter::new(&config);
+        let now = Instant::now();
+        let mary = [path(&["person", "mary"])];
+        assert!(l.check_at(now, "a", &mary).is_ok());
+        assert!(l
+            .check_at(now + Duration::from_millis(100), "b", &mary)
+            .is_ok());
+        let later = now + Duration::from_millis(200);
+        assert!(l.check_at(later, "a", &mary).is_err());
+        // a third client drops the least recently used bucket, that of b
+        assert!(l.check_at(later, "c", &mary).is_ok());
+        assert_eq!(l.buckets.lock().unwrap().buckets.len(), 2);
+        assert_eq!(l.buckets.lock().unwrap().used.len(), 2);
+        assert!(l.check_at(later, "a", &mary).is_err());
+        assert!(l.check_at(later, "b", &mary).is_ok());
+    }
+}
@@ -0,0 +1,122 @@
+//! Maps the rejections raised by the Augorama filters onto http responses.
+
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Rejection, Reply};
+
+use crate::au::auth::AuAuthError;
+use crate::au::body::AuBodyError;
+use crate::au::command::{AuBadCommand, AuCommandConflict};
+use crate::au::compression::AuEncodingError;
+use crate::au::format::AuNotAcceptable;
+use crate::au::geo::AuBadGeoQuery;
+use crate::au::ingest::AuBadPath;
+use crate::au::labels::AuBadSelector;
+use crate::au::ratelimit::AuRateLimited;
+use crate::au::shadow::{AuBadShadow, AuShadowConflict};
+use crate::au::signing::AuSignatureError;
+use crate::au::thing::AuBadThing;
+use crate::au::units::AuBadUnit;
+
+/// turn Augorama rejections into responses, leaving the others to warp
+pub async fn handle_rejection(err: Rejection) -> Result<Response, Rejection> {
+    if let Some(e) = err.find::<AuAuthError>() {
+        return Ok(match e {
+            AuAuthError::Unauthorized => warp::reply::with_header(
+                warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED),
+                "WWW-Authenticate",
+                "Bearer",
+            )
+            .into_response(),
+            AuAuthError::Forbidden => {
+                warp::reply::with_status("Forbidden", StatusCode::FORBIDDEN).into_response()
+            }
+        });
+    }
+    if let Some(AuSignatureError::Saturated) = err.find::<AuSignatureError>() {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status("Too many signed requests", StatusCode::SERVICE_UNAVAILABLE),
+            "Retry-After",
+            "1",
+        )
+        .into_response());
+    }
+    if let Some(e) = err.find::<AuSignatureError>() {
+        let reply = format!("Bad signature: {:?}", e);
+        return Ok(warp::reply::with_status(reply, StatusCode::UNAUTHORIZED).into_response());
+    }
+    if let Some(e) = err.find::<AuRateLimited>() {
+        return Ok(match e {
+            AuRateLimited::Empty { retry_after } => warp::reply::with_header(
+                warp::reply::with_status("Too Many Requests", StatusCode::TOO_MANY_REQUESTS),
+                "Retry-After",
+                retry_after.to_string(),
+            )
+            .into_response(),
+            AuRateLimited::TooCostly { .. } => {
+                let reply = format!("Request {}", e);
+                warp::reply::with_status(reply, StatusCode::PAYLOAD_TOO_LARGE).into_response()
+            }
+        });
+    }
+    if let Some(AuBodyError(e)) = err.find::<AuBodyError>() {
+        let reply = format!("Bad telemetry: {}", e);
+        return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
+    }
+    if let Some(e) = err.find::<AuEncodingError>() {
+        return Ok(match e {
+            AuEncodingError::Unsupported(e) => warp::reply::with_status(
+                format!("Unsupported Content-Encoding: {}", e),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            AuEncodingError::TooLarge(limit) => warp::reply::with_status(
+                format!("Decoded body larger than {} bytes", limit),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            AuEncodingError::Invalid(e) => warp::reply::with_status(
+                format!("Bad Content-Encoding: {}", e),
+                StatusCode::BAD_REQUEST,
+            ),
+        }
+        .into_response());
+    }
+    if let Some(AuNotAcceptable(a)) = err.find::<AuNotAcceptable>() {
+        let reply = format!("Not Acceptable: {}", a);
+        return Ok(warp::reply::with_status(reply, StatusCode::NOT_ACCEPTABLE).into_response());
+    }
+    if let Some(AuBadPath(e)) = err.find::<AuBadPath>() {
+        let reply = format!("Bad path: {}", e);
+        return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
+    }
+    if let Some(AuBadSelector(e)) = err.find::<AuBadSelector>() {
+        let reply = format!("Bad labels: {}", e);
+        return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
+    }
+    if let Some(AuBadUnit(e)) = err.find::<AuBadUnit>() {
+        let reply = format!("Bad unit: {}", e);
+        return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
+    }
+    if let Some(AuBadGeoQuery(e)) = err.find::<AuBadGeoQuery>() {
+        let reply = format!("Bad geo query: {}", e);
+        return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
+    }
+    if let Some(AuBadThing(e)) = err.find::<AuBadThing>() {
+        let reply = format!("Bad thing: {}", e);
+        return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
+    }
+    if let Some(AuBadCommand(e)) = err.find::<AuBadCommand>() {
+        let reply = format!("Bad command: {}", e);
+        return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
+    }
+    if let Some(AuCommandConflict(e)) = err.find::<AuCommandConflict>() {
+        return Ok(warp::reply::with_status(e.clone(), StatusCode::CONFLICT).into_response());
+    }
+    if let Some(AuBadShadow(e)) = err.find::<AuBadShadow>() {
+        let reply = format!("Bad shadow: {}", e);
+        return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
+    }
+    if let Some(AuShadowConflict(e)) = err.find::<AuShadowConflict>() {
+        return Ok(warp::reply::with_status(e.clone(), StatusCode::CONFLICT).into_response());
+    }
+    Err(err)
+}
@@ -0,0 +1,294 @@
+//! Prometheus remote_write receiver, ie: `POST /api/v1/write`.
+//!
+//! The body is a snappy compressed protobuf `WriteRequest`.  Each series is mapped onto a twin by
+//! the first rule whose `match` labels it carries - the rule's `path` and `name` templates are
+//! filled from the series labels, ie: `/actor/device/{instance}` and `{__name__}`.  Series no rule
+//! maps are dropped.  Every sample becomes an `AuTelemetry` Told to the twin, labelled with the
+//! series labels neither template uses.
+//!
+//! Neither the body nor the length it claims to decompress to may exceed `max_body_bytes`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use log::debug;
+use prost::Message;
+use serde::Deserialize;
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+use warp::{Filter, Rejection};
+
+use crate::au::compression::{AuEncodingError, CompressionConfig};
+use crate::au::config::AuConfig;
+use crate::au::ingest::{credentials, fill, group, twin_path, unfilled};
+use crate::au::model::{AuTelemetry, AuTwin};
+use crate::au::tls::AuClientIdentity;
+use crate::au::twins::{tell_reply, AuTwins};
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    /// unix milliseconds
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+fn default_name() -> String {
+    "{__name__}".to_string()
+}
+
+/// Maps the series carrying the `match` labels onto a twin.
+#[derive(Clone, Deserialize)]
+pub struct AuSeriesRule {
+    /// labels and the values a series must carry
+    #[serde(rename = "match", default)]
+    pub matches: HashMap<String, String>,
+    /// template of the twin path, ie: `/actor/device/{instance}`
+    pub path: String,
+    /// template of the telemetry name, `{__name__}` by default
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RemoteWriteConfig {
+    /// tried in order, the first mapping a series is used
+    pub rules: Vec<AuSeriesRule>,
+}
+
+pub struct AuRemoteWrite {
+    rules: Vec<AuSeriesRule>,
+}
+
+impl AuRemoteWrite {
+    pub fn new(config: &RemoteWriteConfig) -> AuRemoteWrite {
+        AuRemoteWrite {
+            rules: config.rules.clone(),
+        }
+    }
+
+    /// the twin path, telemetry name and remaining labels of a series
+    fn map(
+        &self,
+        labels: &HashMap<String, String>,
+    ) -> Option<(Vec<String>, String, BTreeMap<String, String>)> {
+        let lookup = |k: &str| labels.get(k).cloned();
+        self.rules
+            .iter()
+            .filter(|r| r.matches.iter().all(|(k, v)| labels.get(k) == Some(v)))
+            .find_map(|r| {
+                let path = twin_path(&fill(&r.path, lookup)?)?;
+                let name = fill(&r.name, lookup)?;
+                // `__` labels are reserved by Prometheus, ie: `__name__`
+                let own = labels.iter().filter(|(k, _)| !k.starts_with("__"));
+                Some((
+                    path,
+                    name,
+                    unfilled(own, &[r.path.as_str(), r.name.as_str()]),
+                ))
+            })
+    }
+
+    /// the telemetry of the twins in a snappy compressed `WriteRequest`, checked by `body`
+    pub fn decode(&self, body: &[u8]) -> Result<Vec<AuTwin>, String> {
+        let raw = snap::raw::Decoder::new()
+            .decompress_vec(body)
+            .map_err(|e| format!("invalid snappy: {}", e))?;
+        let request =
+            WriteRequest::decode(raw.as_slice()).map_err(|e| format!("invalid protobuf: {}", e))?;
+        let mut records = Vec::new();
+        for series in request.timeseries {
+            let labels: HashMap<String, String> = series
+                .labels
+                .into_iter()
+                .map(|l| (l.name, l.value))
+                .collect();
+            let (path, name, labels) = match self.map(&labels) {
+                Some(mapped) => mapped,
+                None => {
+                    debug!("no rule maps series {:?}", labels.get("__name__"));
+                    continue;
+                }
+            };
+            for sample in series.samples {
+                let datetime = Utc
+                    .timestamp_millis_opt(sample.timestamp)
+                    .single()
+                    .ok_or_else(|| format!("invalid timestamp {}", sample.timestamp))?;
+                records.push((
+                    path.clone(),
+                    AuTelemetry {
+                        datetime,
+                        name: name.clone(),
+                        value: sample.value.into(),
+                        unit: None,
+                        labels: labels.clone(),
+                        violations: Vec::new(),
+                    },
+                ));
+            }
+        }
+        Ok(group(records))
+    }
+}
+
+/// refuse a snappy body claiming to decompress to more than `max_bytes`
+fn check_len(body: &[u8], max_bytes: usize) -> Result<(), AuEncodingError> {
+    let len =
+        snap::raw::decompress_len(body).map_err(|e| AuEncodingError::Invalid(e.to_string()))?;
+    if len > max_bytes {
+        return Err(AuEncodingError::TooLarge(max_bytes));
+    }
+    Ok(())
+}
+
+/// the snappy compressed body of a write, refused unless it and its decompressed length are
+/// within `max_body_bytes`
+pub fn body(
+    config: CompressionConfig,
+) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    crate::au::compression::bounded(config.max_body_bytes).and_then(move |body: Bytes| async move {
+        check_len(&body, config.max_body_bytes)
+            .map(|_| body)
+            .map_err(warp::reject::custom)
+    })
+}
+
+/// `POST /api/v1/write`
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let remote_write = Arc::new(AuRemoteWrite::new(&config.remote_write));
+    let twins = twins.clone();
+    warp::path("api")
+        .and(warp::path("v1"))
+        .and(warp::path("write"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(crate::au::body::twins(
+            &twins,
+            crate::au::body::no_context(),
+            body(config.compression),
+            move |_: &(), body: &[u8]| remote_write.decode(body),
+        ))
+        .and(credentials())
+        .and_then(
+            move |_,
+                  decoded: Vec<AuTwin>,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let result = twins.tell(decoded, header, identity);
+                async move { result }
+            },
+        )
+        .map(tell_reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::remotewrite::*;
+
+    fn series(labels: &[(&str, &str)], samples: &[(f64, i64)]) -> TimeSeries {
+        TimeSeries {
+            labels: labels
+                .iter()
+                .map(|(n, v)| Label {
+                    name: n.to_string(),
+                    value: v.to_string(),
+                })
+                .collect(),
+            samples: samples
+                .iter()
+                .map(|(value, timestamp)| Sample {
+                    value: *value,
+                    timestamp: *timestamp,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn decode_works() {
+        let config: RemoteWriteConfig = serde_json::from_str(
+            r#"{"rules": [
+                {"match": {"job": "thermostat"}, "path": "/actor/device/{instance}"},
+                {"path": "/actor/host/{instance}", "name": "host.{__name__}"}
+            ]}"#,
+        )
+        .unwrap();
+        let rw = AuRemoteWrite::new(&config);
+        let request = WriteRequest {
+            timeseries: vec![
+                series(
+                    &[
+                        ("__name__", "temp"),
+                        ("job", "thermostat"),
+                        ("instance", "t-42"),
+                        ("sensor", "top"),
+                    ],
+                    &[(21.5, 2000), (21.0, 1000)],
+                ),
+                series(&[("__name__", "up"), ("instance", "web-1")], &[(1.0, 1000)]),
+                series(&[("__name__", "orphan")], &[(1.0, 1000)]),
+            ],
+        };
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap();
+        let twins = rw.decode(&body).unwrap();
+        assert_eq!(twins.len(), 2);
+        assert_eq!(twins[0].0, vec!["device".to_string(), "t-42".to_string()]);
+        assert_eq!(twins[0].1[1].value, 21.5);
+        // the labels filling the templates are not repeated on the record
+        assert_eq!(twins[0].1[1].labels.len(), 2);
+        assert_eq!(twins[0].1[1].labels["sensor"], "top");
+        assert!(twins[1].1[0].labels.is_empty());
+        assert_eq!(twins[1].1[0].name, "host.up");
+        assert!(rw.decode(b"garbage").is_err());
+    }
+
+    #[test]
+    fn check_len_works() {
+        let body = snap::raw::Encoder::new().compress_vec(&[0; 1000]).unwrap();
+        assert!(check_len(&body, 1000).is_ok());
+        assert_eq!(check_len(&body, 999), Err(AuEncodingError::TooLarge(999)));
+        // a bomb claiming more than it holds is refused before it is decompressed
+        let mut bomb = Vec::new();
+        snap_len(&mut bomb, u32::MAX as u64);
+        assert_eq!(check_len(&bomb, 1000), Err(AuEncodingError::TooLarge(1000)));
+    }
+
+    fn snap_len(out: &mut Vec<u8>, mut n: u64) {
+        while n >= 0x80 {
+            out.push((n as u8) | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+    }
+}
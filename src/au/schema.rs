@@ -0,0 +1,267 @@
+//! Schemas of twin types, checked as telemetry is Told.
+//!
+//! The schema of a root or child type lists the telemetry names its twins may report and, for
+//! each, the type of the value, its range, its enum states and its unit:
+//!
+//!   `{"types": {"refrigerator": {"telemetry": {"temp": {"type": "number", "min": -40, "max": 20,
+//!   "unit": "Cel"}, "door": {"type": "enum", "values": ["open", "closed"]}}}}}`
+//!
+//! Types may also be modelled by DTDL interfaces or Thing Descriptions, see `thing`.
+//!
+//! A name not listed is a violation unless the schema allows `additional` names, and twins of a
+//! type without a schema may report anything.  The units declared in `units` are checked too.
+//! A Tell with a violating record is refused with a 422 describing every violation - or, in
+//! `permissive` mode, accepted with the violations of each record flagged in its `violations`.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::au::ingest::AuRecordError;
+use crate::au::metrics::SCHEMA_VIOLATIONS;
+use crate::au::model::{AuTelemetry, AuValue};
+use crate::au::thing;
+use crate::au::units::{AuUnits, UnitsConfig};
+
+/// What a telemetry name of a type may report.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AuTelemetrySchema {
+    /// `number`, `integer`, `boolean`, `string`, `enum` or `location` - any type when not given
+    #[serde(rename = "type")]
+    pub value_type: Option<String>,
+    /// least numeric value
+    pub min: Option<f64>,
+    /// greatest numeric value
+    pub max: Option<f64>,
+    /// UCUM code of the unit, ie: `Cel`
+    pub unit: Option<String>,
+    /// the states of an enum
+    pub values: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AuTypeSchema {
+    pub telemetry: HashMap<String, AuTelemetrySchema>,
+    /// accept names the schema does not list
+    pub additional: bool,
+    /// flag rather than refuse violations, replacing the global mode
+    pub permissive: Option<bool>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SchemaConfig {
+    /// accept violating records, flagged
+    pub permissive: bool,
+    /// the schema of each twin type
+    pub types: HashMap<String, AuTypeSchema>,
+    /// DTDL or Thing Description files and directories modelling twin types
+    pub models: Vec<String>,
+}
+
+const VALUE_TYPES: &[&str] = &["number", "integer", "boolean", "string", "enum", "location"];
+
+/// The schemas of the twin types and the units they declare.
+pub struct AuSchemas {
+    permissive: bool,
+    types: HashMap<String, AuTypeSchema>,
+    units: AuUnits,
+}
+
+impl AuSchemas {
+    pub fn new(config: &SchemaConfig, units: &UnitsConfig) -> Result<AuSchemas, String> {
+        let mut units = units.clone();
+        let mut types = HashMap::new();
+        // a type of the config replaces its model
+        let mut declared = thing::load(&config.models)?;
+        for (typ, schema) in config.types.iter() {
+            declared.insert(typ.to_lowercase(), schema.clone());
+        }
+        for (typ, schema) in declared.iter() {
+            let typ = typ.to_lowercase();
+            for (name, t) in schema.telemetry.iter() {
+                if let Some(value_type) = &t.value_type {
+                    if !VALUE_TYPES.contains(&value_type.as_str()) {
+                        return Err(format!("unknown type {} of {}.{}", value_type, typ, name));
+                    }
+                }
+                // the units of a schema are checked with those declared on their own
+                if let Some(unit) = &t.unit {
+                    units
+                        .types
+                        .entry(typ.clone())
+                        .or_default()
+                        .insert(name.clone(), unit.clone());
+                }
+            }
+            types.insert(typ, schema.clone());
+        }
+        Ok(AuSchemas {
+            permissive: config.permissive,
+            types,
+            units: AuUnits::new(&units)?,
+        })
+    }
+
+    /// the schema of a twin type
+    pub fn schema(&self, typ: &str) -> Option<&AuTypeSchema> {
+        self.types.get(typ)
+    }
+
+    /// the violations of a record of a name the schema lists
+    fn violations(schema: &AuTelemetrySchema, value: &AuValue) -> Vec<String> {
+        let mut violations = Vec::new();
+        if let Some(expected) = &schema.value_type {
+            let fits = match (expected.as_str(), value) {
+                ("number", AuValue::Number(_) | AuValue::Integer(_)) => true,
+                // plain json, csv and line protocol values arrive as numbers and strings
+                ("integer", AuValue::Number(n)) => n.fract() == 0.0 && n.abs() <= i64::MAX as f64,
+                // the states listed decide which strings are enum states
+                ("enum", AuValue::String(_)) => schema.values.is_some(),
+                (expected, value) => expected == value.type_name(),
+            };
+            if !fits {
+                violations.push(format!("a {} is not a {}", value.type_name(), expected));
+            }
+        }
+        if let Some(n) = value.as_f64() {
+            if schema.min.is_some_and(|min| n < min) || schema.max.is_some_and(|max| n > max) {
+                let bound = |b: Option<f64>| b.map(|b| b.to_string()).unwrap_or_default();
+                violations.push(format!(
+                    "{} is outside {}..{}",
+                    n,
+                    bound(schema.min),
+                    bound(schema.max)
+                ));
+            }
+        }
+        if let (Some(values), AuValue::Enum(state) | AuValue::String(state)) =
+            (&schema.values, value)
+        {
+            if !values.contains(state) {
+                violations.push(format!("{} is not one of {}", state, values.join(", ")));
+            }
+        }
+        violations
+    }
+
+    /// check the records Told to a twin, describing those refused.  the records accepted in
+    /// permissive mode carry their violations instead.
+    pub fn check(&self, path: &[String], telemetry: &mut [AuTelemetry]) -> Vec<AuRecordError> {
+        // a twin's type precedes its id
+        let typ = path.len().checked_sub(2).map(|i| path[i].as_str());
+        let schema = typ.and_then(|t| self.types.get(t));
+        let mut errors = self.units.check(path, telemetry);
+        for (index, t) in telemetry.iter_mut().enumerate() {
+            t.violations.clear();
+            let schema = match schema {
+                Some(schema) => schema,
+                None => continue,
+            };
+            let violations = match schema.telemetry.get(&t.name) {
+                Some(telemetry) => AuSchemas::violations(telemetry, &t.value),
+                None if schema.additional => Vec::new(),
+                None => vec![format!("{} is not a telemetry name of the type", t.name)],
+            };
+            for violation in violations {
+                errors.push(AuRecordError::new(path, index, &t.name, violation));
+            }
+        }
+        if errors.is_empty() {
+            return errors;
+        }
+        errors.sort_by_key(|e| e.index);
+        let typ = typ.unwrap_or_default();
+        let permissive = schema.and_then(|s| s.permissive).unwrap_or(self.permissive);
+        let mode = if permissive { "flagged" } else { "refused" };
+        SCHEMA_VIOLATIONS
+            .with_label_values(&[typ, mode])
+            .inc_by(errors.len() as u64);
+        if !permissive {
+            return errors;
+        }
+        for e in errors {
+            telemetry[e.index].violations.push(e.error);
+        }
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::schema::*;
+
+    fn schemas(permissive: bool) -> AuSchemas {
+        let mut config: SchemaConfig = serde_json::from_str(
+            r#"{"types": {"fridge": {"telemetry": {
+                "temp": {"type": "number", "min": -40, "max": 20, "unit": "Cel"},
+                "door": {"type": "enum", "values": ["open", "closed"]},
+                "count": {"type": "integer"}
+            }}}}"#,
+        )
+        .unwrap();
+        config.permissive = permissive;
+        AuSchemas::new(&config, &UnitsConfig::default()).unwrap()
+    }
+
+    fn at(name: &str, value: AuValue) -> AuTelemetry {
+        AuTelemetry {
+            name: name.to_string(),
+            value,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn check_works() {
+        let path = vec!["fridge".to_string(), "f1".to_string()];
+        let mut telemetry = vec![
+            at("temp", 4.0.into()),
+            at("temprature", 4.0.into()),
+            at("temp", 1e308.into()),
+            at("door", AuValue::Enum("ajar".to_string())),
+            at("count", 1.5.into()),
+            at("door", AuValue::Enum("open".to_string())),
+        ];
+        let errors = schemas(false).check(&path, &mut telemetry);
+        let indices: Vec<usize> = errors.iter().map(|e| e.index).collect();
+        assert_eq!(indices, vec![1, 2, 3, 4]);
+        assert_eq!(telemetry[0].unit.as_deref(), Some("Cel"));
+        // twins of other types report anything
+        let other = vec!["truck".to_string(), "t1".to_string()];
+        assert!(schemas(false).check(&other, &mut telemetry).is_empty());
+    }
+
+    #[test]
+    fn plain_values_work() {
+        let path = vec!["fridge".to_string(), "f1".to_string()];
+        let plain = |json: &str| serde_json::from_str::<AuValue>(json).unwrap();
+        let mut telemetry = vec![
+            at("count", plain("3")),
+            at("door", plain(r#""open""#)),
+            at("count", plain("3.5")),
+            at("door", plain(r#""ajar""#)),
+        ];
+        let errors = schemas(false).check(&path, &mut telemetry);
+        let indices: Vec<usize> = errors.iter().map(|e| e.index).collect();
+        assert_eq!(indices, vec![2, 3]);
+    }
+
+    #[test]
+    fn permissive_works() {
+        let path = vec!["fridge".to_string(), "f1".to_string()];
+        let mut telemetry = vec![at("temp", 30.0.into()), at("temp", 4.0.into())];
+        assert!(schemas(true).check(&path, &mut telemetry).is_empty());
+        assert_eq!(
+            telemetry[0].violations,
+            vec!["30 is outside -40..20".to_string()]
+        );
+        assert!(telemetry[1].violations.is_empty());
+        let config: SchemaConfig =
+            serde_json::from_str(r#"{"types": {"fridge": {"telemetry": {"t": {"type": "x"}}}}}"#)
+                .unwrap();
+        assert!(AuSchemas::new(&config, &UnitsConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,438 @@
+//! Shadows of devices - the properties operators want a twin to have and those it has.
+//!
+//! Besides its telemetry each twin holds two documents of properties: `desired`, set by
+//! operators, and `reported`, set by the twin's device.  A property is set with its value and
+//! removed with `null`:
+//!
+//!   `PUT /shadow/desired?path=thermostat/t1` `{"target": 21, "mode": {"enum": "heat"}}`
+//!
+//! Every update advances the version of its document.  The delta - the desired properties the
+//! device has not reported the same value of - is fetched by the device with
+//! `GET /shadow/delta?path=thermostat/t1`, and once applied acknowledged with
+//! `POST /shadow/ack?path=thermostat/t1&version=3`.  `GET /shadow?path=` answers the whole shadow.
+//!
+//! The reported properties are written by the device, so for a twin with a secret their update is
+//! signed like its telemetry, the `path` of its query included so it can not be pointed at another
+//! twin.
+//!
+//! Actors exchange telemetry, so a shadow travels as records labelled with their document.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::time::error::Elapsed;
+use warp::reply::Response;
+use warp::{Filter, Rejection};
+
+use crate::au::body::AuSigned;
+use crate::au::config::AuConfig;
+use crate::au::ingest::{credentials, twin_path};
+use crate::au::model::AuOperator::*;
+use crate::au::model::{AuOperator, AuTelemetry, AuValue};
+use crate::au::tls::AuClientIdentity;
+use crate::au::twins::{twin_reply, AuTwins};
+
+/// A shadow update is not a document of properties, or an ack has no version - 400.
+#[derive(Debug)]
+pub struct AuBadShadow(pub String);
+
+impl warp::reject::Reject for AuBadShadow {}
+
+/// An ack names a version not desired yet - 409.
+#[derive(Debug)]
+pub struct AuShadowConflict(pub String);
+
+impl warp::reject::Reject for AuShadowConflict {}
+
+/// the label naming the document of a record
+const DOCUMENT: &str = "shadow";
+/// the document of the records removing a property
+const REMOVED: &str = "removed";
+/// the record of a document's version - property names may not start with `$`
+const VERSION: &str = "$version";
+/// the record of the desired version acknowledged
+const ACKED: &str = "$acked";
+
+/// A versioned document of properties.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct AuDocument {
+    pub version: u64,
+    pub state: BTreeMap<String, AuValue>,
+    /// when each property was last set
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub updated: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl AuDocument {
+    /// set and remove the properties of the records, advancing the version
+    fn update(&mut self, records: &[AuTelemetry]) {
+        for t in records {
+            if t.labels.get(DOCUMENT).map(String::as_str) == Some(REMOVED) {
+                self.state.remove(&t.name);
+                self.updated.remove(&t.name);
+            } else {
+                self.state.insert(t.name.clone(), t.value.clone());
+                self.updated.insert(t.name.clone(), t.datetime);
+            }
+        }
+        self.version += 1;
+    }
+
+    /// the document as records labelled with its name, its version last
+    fn records(&self, name: &str) -> Vec<AuTelemetry> {
+        let labels: BTreeMap<String, String> = vec![(DOCUMENT.to_string(), name.to_string())]
+            .into_iter()
+            .collect();
+        let mut records: Vec<AuTelemetry> = self
+            .state
+            .iter()
+            .map(|(property, value)| AuTelemetry {
+                datetime: self.updated.get(property).cloned().unwrap_or_else(Utc::now),
+                name: property.clone(),
+                value: value.clone(),
+                labels: labels.clone(),
+                ..Default::default()
+            })
+            .collect();
+        records.push(AuTelemetry {
+            name: VERSION.to_string(),
+            value: AuValue::Integer(self.version as i64),
+            labels,
+            ..Default::default()
+        });
+        records
+    }
+}
+
+/// The desired and reported properties of a twin.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuShadow {
+    pub desired: AuDocument,
+    pub reported: AuDocument,
+    /// the desired version the device last acknowledged
+    pub acked: u64,
+}
+
+/// A shadow as answered, with its delta.
+#[derive(Serialize)]
+pub struct AuShadowDocument {
+    pub desired: AuDocument,
+    pub reported: AuDocument,
+    pub delta: AuDocument,
+    pub acked: u64,
+}
+
+impl AuShadow {
+    /// the records updating a document with the properties of a request body
+    pub fn updates(
+        properties: HashMap<String, Option<AuValue>>,
+    ) -> Result<Vec<AuTelemetry>, String> {
+        let mut records = Vec::new();
+        for (name, value) in properties {
+            if name.is_empty() || name.starts_with('$') {
+                return Err(format!("invalid property name {:?}", name));
+            }
+            let mut record = AuTelemetry {
+                name,
+                ..Default::default()
+            };
+            match value {
+                Some(value) => record.value = value,
+                None => {
+                    record
+                        .labels
+                        .insert(DOCUMENT.to_string(), REMOVED.to_string());
+                }
+            }
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    pub fn desire(&mut self, records: &[AuTelemetry]) {
+        self.desired.update(records);
+    }
+
+    pub fn report(&mut self, records: &[AuTelemetry]) {
+        self.reported.update(records);
+    }
+
+    /// acknowledge a desired version applied, ignoring versions not desired yet or older than
+    /// the one acknowledged
+    pub fn ack(&mut self, version: u64) {
+        if version <= self.desired.version {
+            self.acked = self.acked.max(version);
+        }
+    }
+
+    /// the desired properties the device has not reported, versioned as the desired document
+    pub fn delta(&self) -> AuDocument {
+        let mut delta = AuDocument {
+            version: self.desired.version,
+            ..Default::default()
+        };
+        for (name, value) in self.desired.state.iter() {
+            if self.reported.state.get(name) != Some(value) {
+                delta.state.insert(name.clone(), value.clone());
+                if let Some(updated) = self.desired.updated.get(name) {
+                    delta.updated.insert(name.clone(), *updated);
+                }
+            }
+        }
+        delta
+    }
+
+    pub fn document(self) -> AuShadowDocument {
+        AuShadowDocument {
+            delta: self.delta(),
+            desired: self.desired,
+            reported: self.reported,
+            acked: self.acked,
+        }
+    }
+
+    /// the record acknowledging a desired version
+    pub fn ack_record(version: u64) -> AuTelemetry {
+        AuTelemetry {
+            name: ACKED.to_string(),
+            value: AuValue::Integer(version as i64),
+            ..Default::default()
+        }
+    }
+
+    /// the version of an ack record
+    pub fn acked_version(records: &[AuTelemetry]) -> Option<u64> {
+        records
+            .iter()
+            .find(|t| t.name == ACKED)
+            .and_then(|t| t.value.as_f64())
+            .map(|v| v as u64)
+    }
+
+    /// the shadow as the records an actor answers with
+    pub fn records(&self) -> Vec<AuTelemetry> {
+        let mut records = self.desired.records("desired");
+        records.extend(self.reported.records("reported"));
+        records.push(AuShadow::ack_record(self.acked));
+        records
+    }
+
+    /// the shadow of the records an actor answered with
+    pub fn from_records(records: &[AuTelemetry]) -> AuShadow {
+        let mut shadow = AuShadow::default();
+        for t in records {
+            let document = match t.labels.get(DOCUMENT).map(String::as_str) {
+                Some("desired") => &mut shadow.desired,
+                Some("reported") => &mut shadow.reported,
+                _ => {
+                    if let Some(acked) = AuShadow::acked_version(std::slice::from_ref(t)) {
+                        shadow.acked = acked;
+                    }
+                    continue;
+                }
+            };
+            if t.name == VERSION {
+                document.version = t.value.as_f64().unwrap_or_default() as u64;
+            } else {
+                document.state.insert(t.name.clone(), t.value.clone());
+                document.updated.insert(t.name.clone(), t.datetime);
+            }
+        }
+        shadow
+    }
+}
+
+/// the twin of the `path` query parameter, ie: `thermostat/t1`
+pub fn twin() -> impl Filter<Extract = (Vec<String>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().and_then(|query: HashMap<String, String>| async move {
+        let path = query.get("path").map(String::as_str).unwrap_or_default();
+        twin_path(path)
+            .ok_or_else(|| warp::reject::custom(AuBadShadow(format!("invalid twin path {}", path))))
+    })
+}
+
+/// the desired version of the `version` query parameter of an ack
+pub fn version() -> impl Filter<Extract = (u64,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().and_then(|query: HashMap<String, String>| async move {
+        let version = query.get("version").map(String::as_str).unwrap_or_default();
+        version.parse::<u64>().map_err(|_| {
+            warp::reject::custom(AuBadShadow(format!("invalid version {:?}", version)))
+        })
+    })
+}
+
+/// the records of the properties of a shadow update, its body json, CBOR or MessagePack
+pub fn properties(body: &AuSigned) -> Result<Vec<AuTelemetry>, Rejection> {
+    body.decode::<HashMap<String, Option<AuValue>>>()
+        .and_then(AuShadow::updates)
+        .map_err(|e| warp::reject::custom(AuBadShadow(e)))
+}
+
+/// apply a shadow operation once the client is allowed to and answer the twin's shadow
+fn ask(
+    twins: &AuTwins,
+    path: Vec<String>,
+    op: AuOperator,
+    data: Option<Vec<AuTelemetry>>,
+    credentials: (Option<String>, Option<AuClientIdentity>),
+) -> Result<Result<AuShadow, Elapsed>, Rejection> {
+    twins.authorize(&op, &path, &credentials)?;
+    let answer = twins.ask(path, op, data);
+    Ok(answer.map(|records| AuShadow::from_records(&records)))
+}
+
+/// `GET /shadow`, `GET /shadow/delta`, `PUT /shadow/{desired,reported}` and `POST /shadow/ack`
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let twins_shadow = twins.clone();
+    let shadow = warp::path("shadow")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(twin())
+        .and(credentials())
+        .and_then(
+            move |path: Vec<String>, header: Option<String>, identity: Option<AuClientIdentity>| {
+                let result = ask(&twins_shadow, path, Shadow, None, (header, identity))
+                    .map(|answer| answer.map(AuShadow::document));
+                async move { result }
+            },
+        )
+        .and(crate::au::format::accept())
+        .map(twin_reply);
+
+    let twins_delta = twins.clone();
+    let delta = warp::path("shadow")
+        .and(warp::path("delta"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(twin())
+        .and(credentials())
+        .and_then(
+            move |path: Vec<String>, header: Option<String>, identity: Option<AuClientIdentity>| {
+                let result = ask(&twins_delta, path, Shadow, None, (header, identity))
+                    .map(|answer| answer.map(|shadow| shadow.delta()));
+                async move { result }
+            },
+        )
+        .and(crate::au::format::accept())
+        .map(twin_reply);
+
+    let twins_update = twins.clone();
+    let update = warp::path("shadow")
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(twin())
+        .and(crate::au::body::signed(config.compression))
+        .and(credentials())
+        .and_then(
+            move |document: String,
+                  path: Vec<String>,
+                  body: AuSigned,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let twins = &twins_update;
+                let op = match document.as_str() {
+                    "desired" => Some(Desire),
+                    "reported" => Some(Report),
+                    _ => None,
+                };
+                let result = op.ok_or_else(warp::reject::not_found).and_then(|op| {
+                    twins.authorize(&op, &path, &(header, identity))?;
+                    // the reported properties are the device's, signed like its telemetry
+                    if op == Report {
+                        body.verify(&twins.signing, &path)?;
+                    }
+                    let properties = properties(&body)?;
+                    let answer = twins.ask(path, op, Some(properties));
+                    Ok(answer.map(|records| AuShadow::from_records(&records).document()))
+                });
+                async move { result }
+            },
+        )
+        .and(crate::au::format::accept())
+        .map(twin_reply);
+
+    let twins_ack = twins.clone();
+    let ack = warp::path("shadow")
+        .and(warp::path("ack"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(twin())
+        .and(version())
+        .and(credentials())
+        .and_then(
+            move |path: Vec<String>,
+                  version: u64,
+                  header: Option<String>,
+                  identity: Option<AuClientIdentity>| {
+                let records = vec![AuShadow::ack_record(version)];
+                let result = ask(&twins_ack, path, Ack, Some(records), (header, identity))
+                    .and_then(|answer| match answer {
+                        // a version not desired yet is not acknowledged
+                        Ok(shadow) if shadow.acked < version => {
+                            Err(warp::reject::custom(AuShadowConflict(format!(
+                                "version {} is not desired, the desired version is {}",
+                                version, shadow.desired.version
+                            ))))
+                        }
+                        answer => Ok(answer.map(AuShadow::document)),
+                    });
+                async move { result }
+            },
+        )
+        .and(crate::au::format::accept())
+        .map(twin_reply);
+
+    delta.or(ack).unify().or(update).unify().or(shadow).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::shadow::*;
+
+    fn updates(json: &str) -> Vec<AuTelemetry> {
+        AuShadow::updates(serde_json::from_str(json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn delta_works() {
+        let mut shadow = AuShadow::default();
+        shadow.desire(&updates(r#"{"target": 21, "mode": {"enum": "heat"}}"#));
+        shadow.report(&updates(r#"{"target": 18, "mode": {"enum": "heat"}}"#));
+        let delta = shadow.delta();
+        assert_eq!(delta.version, 1);
+        assert_eq!(delta.state.keys().collect::<Vec<_>>(), vec!["target"]);
+        shadow.report(&updates(r#"{"target": 21}"#));
+        assert!(shadow.delta().state.is_empty());
+        assert_eq!(shadow.reported.version, 2);
+        shadow.desire(&updates(r#"{"mode": null}"#));
+        assert_eq!(shadow.desired.version, 2);
+        assert!(!shadow.desired.state.contains_key("mode"));
+        assert!(AuShadow::updates(serde_json::from_str(r#"{"$version": 1}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn ack_works() {
+        let mut shadow = AuShadow::default();
+        shadow.desire(&updates(r#"{"target": 21}"#));
+        shadow.desire(&updates(r#"{"target": 22}"#));
+        shadow.ack(3);
+        assert_eq!(shadow.acked, 0);
+        shadow.ack(2);
+        shadow.ack(1);
+        assert_eq!(shadow.acked, 2);
+    }
+
+    #[test]
+    fn records_work() {
+        let mut shadow = AuShadow::default();
+        shadow.desire(&updates(r#"{"target": 21, "mode": {"enum": "heat"}}"#));
+        shadow.report(&updates(r#"{"target": 18}"#));
+        shadow.ack(1);
+        assert_eq!(AuShadow::from_records(&shadow.records()), shadow);
+    }
+}
@@ -0,0 +1,455 @@
+//! HMAC-SHA256 signed telemetry for devices that can not present client certificates.
+//!
+//! A secret is configured per twin or per root type - the most specific path prefix wins.  A
+//! POST of telemetry for a twin with a secret must carry three headers:
+//!
+//!   * `x-augorama-timestamp` - unix seconds, at most `window_secs` behind the server clock and
+//!     a few seconds ahead of it.
+//!   * `x-augorama-nonce` - a value never used before with the same secret within the window.
+//!   * `x-augorama-signature` - hex HMAC-SHA256 of `timestamp\nnonce\npath\nbody`.
+//!
+//! The path is the request path and its query, ie: `/actor/device/thermostat-42`, `/batch` or
+//! `/commands/ack?path=device/thermostat-42&id=7`, so that a signed write can not be pointed at
+//! another twin or command by its query.  The body is the body as decoded - a body posted with a
+//! `Content-Encoding` of `gzip` or `zstd` is signed before it is compressed, so the signature
+//! covers the telemetry whatever the transport encoding.  A body addressing many twins, ie: to
+//! `/batch` or `/write`, is verified against the secret of every twin it addresses, so all of them
+//! must share one secret.  Posts addressing no twin with a secret are not checked, and the MQTT
+//! and StatsD bridges, which carry no signature, drop the telemetry of twins with a secret.
+//!
+//! The nonces of each secret are remembered for as long as their timestamps are accepted.  While
+//! `nonce_cache_size` of them are, a signed request is refused with a 503 rather than forgetting a
+//! nonce that could be replayed.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::debug;
+use serde::Deserialize;
+use sha2::Sha256;
+use warp::path::FullPath;
+use warp::{Filter, Rejection};
+
+use crate::au::auth::prefix_matches;
+
+pub const TIMESTAMP_HEADER: &str = "x-augorama-timestamp";
+pub const NONCE_HEADER: &str = "x-augorama-nonce";
+pub const SIGNATURE_HEADER: &str = "x-augorama-signature";
+
+/// seconds a timestamp may be ahead of the server clock, for devices whose clocks run fast
+const MAX_SKEW_SECS: i64 = 5;
+
+/// A shared secret for every twin under a path prefix, ie: `/actor/device` or
+/// `/actor/device/thermostat-42`.
+#[derive(Clone, Deserialize)]
+pub struct AuSecret {
+    pub path: String,
+    pub secret: String,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct SigningConfig {
+    pub secrets: Vec<AuSecret>,
+    /// seconds a signature's timestamp may be behind the server clock
+    pub window_secs: i64,
+    /// most nonces remembered within the window, signed requests are refused once it is reached
+    pub nonce_cache_size: usize,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        SigningConfig {
+            secrets: Vec::new(),
+            window_secs: 300,
+            nonce_cache_size: 100_000,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuSignatureError {
+    /// a signature header is absent
+    Missing,
+    /// the signature does not match the payload
+    Invalid,
+    /// the timestamp is older than the window or ahead of the server clock
+    Expired,
+    /// the nonce was already used
+    Replayed,
+    /// the nonces remembered within the window fill the cache - 503
+    Saturated,
+}
+
+impl warp::reject::Reject for AuSignatureError {}
+
+/// The signature headers of a request and the request path and query they sign, ie: `/batch`.
+#[derive(Clone, Debug, Default)]
+pub struct AuSignature {
+    pub path: String,
+    pub timestamp: Option<String>,
+    pub nonce: Option<String>,
+    pub signature: Option<String>,
+}
+
+impl AuSignature {
+    /// whether the request carries a signature header
+    pub fn present(&self) -> bool {
+        self.timestamp.is_some() || self.nonce.is_some() || self.signature.is_some()
+    }
+}
+
+/// the signature headers of a request
+pub fn signature() -> impl Filter<Extract = (AuSignature,), Error = Rejection> + Clone {
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+    warp::path::full()
+        .and(query)
+        .and(warp::header::optional::<String>(TIMESTAMP_HEADER))
+        .and(warp::header::optional::<String>(NONCE_HEADER))
+        .and(warp::header::optional::<String>(SIGNATURE_HEADER))
+        .map(
+            |path: FullPath,
+             query: String,
+             timestamp: Option<String>,
+             nonce: Option<String>,
+             signature: Option<String>| AuSignature {
+                path: match query.is_empty() {
+                    true => path.as_str().to_string(),
+                    false => format!("{}?{}", path.as_str(), query),
+                },
+                timestamp,
+                nonce,
+                signature,
+            },
+        )
+}
+
+/// The nonces used within the window, each with the path of the secret it was used with.
+struct AuNonces {
+    seen: HashSet<(String, String)>,
+    order: VecDeque<(i64, (String, String))>,
+}
+
+pub struct AuSigning {
+    secrets: Vec<AuSecret>,
+    window_secs: i64,
+    nonce_cache_size: usize,
+    nonces: Mutex<AuNonces>,
+}
+
+/// the hex signature of a payload - what a device puts in `x-augorama-signature`
+pub fn sign(secret: &str, timestamp: &str, nonce: &str, path: &str, body: &[u8]) -> String {
+    hex::encode(
+        mac(secret, timestamp, nonce, path, body)
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+fn mac(secret: &str, timestamp: &str, nonce: &str, path: &str, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b"\n");
+    mac.update(nonce.as_bytes());
+    mac.update(b"\n");
+    mac.update(path.as_bytes());
+    mac.update(b"\n");
+    mac.update(body);
+    mac
+}
+
+impl AuSigning {
+    pub fn new(config: &SigningConfig) -> AuSigning {
+        AuSigning {
+            secrets: config.secrets.clone(),
+            window_secs: config.window_secs,
+            nonce_cache_size: config.nonce_cache_size,
+            nonces: Mutex::new(AuNonces {
+                seen: HashSet::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// the secret with the longest prefix of the actor path
+    fn secret(&self, path: &[String]) -> Option<&AuSecret> {
+        self.secrets
+            .iter()
+            .filter(|s| prefix_matches(&s.path, path))
+            .max_by_key(|s| s.path.split('/').filter(|p| !p.is_empty()).count())
+    }
+
+    /// whether telemetry for the twin at an actor path must be signed
+    pub fn secured(&self, path: &[String]) -> bool {
+        self.secret(path).is_some()
+    }
+
+    /// verify the signature of a body against the secret of every twin it addresses, ie:
+    /// `[device, thermostat-42]`.  twins without a secret are not checked.
+    pub fn verify(
+        &self,
+        signature: &AuSignature,
+        twins: &[Vec<String>],
+        body: &[u8],
+    ) -> Result<(), AuSignatureError> {
+        self.verify_at(Utc::now().timestamp(), signature, twins, body)
+    }
+
+    fn verify_at(
+        &self,
+        now: i64,
+        signature: &AuSignature,
+        twins: &[Vec<String>],
+        body: &[u8],
+    ) -> Result<(), AuSignatureError> {
+        let mut secrets: Vec<&AuSecret> =
+            twins.iter().filter_map(|path| self.secret(path)).collect();
+        if secrets.is_empty() {
+            return Ok(());
+        }
+        secrets.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        secrets.dedup_by(|a, b| a.path == b.path);
+        let (timestamp, nonce, hex_signature) = match (
+            signature.timestamp.as_deref(),
+            signature.nonce.as_deref(),
+            signature.signature.as_deref(),
+        ) {
+            (Some(t), Some(n), Some(s)) => (t, n, s),
+            _ => return Err(AuSignatureError::Missing),
+        };
+        let bytes = hex::decode(hex_signature).map_err(|_| AuSignatureError::Invalid)?;
+        for secret in secrets.iter() {
+            mac(&secret.secret, timestamp, nonce, &signature.path, body)
+                .verify_slice(&bytes)
+                .map_err(|_| {
+                    debug!("bad signature for {}", signature.path);
+                    AuSignatureError::Invalid
+                })?;
+        }
+        let ts: i64 = timestamp.parse().map_err(|_| AuSignatureError::Invalid)?;
+        // the client picks the timestamp, so its distance from the clock may not fit
+        match now.checked_sub(ts) {
+            Some(age) if (-MAX_SKEW_SECS..=self.window_secs).contains(&age) => {}
+            _ => return Err(AuSignatureError::Expired),
+        }
+        let secrets: Vec<&str> = secrets.iter().map(|s| s.path.as_str()).collect();
+        self.remember(now, &secrets, nonce)
+    }
+
+    /// remember a nonce used with each secret, ie: `/actor/device`, forgetting only those that
+    /// arrived before any timestamp still accepted - a timestamp ahead of the clock is accepted
+    /// for up to `MAX_SKEW_SECS` longer than the window
+    fn remember(&self, now: i64, secrets: &[&str], nonce: &str) -> Result<(), AuSignatureError> {
+        let kept = self.window_secs.saturating_add(MAX_SKEW_SECS);
+        let mut nonces = self.nonces.lock().unwrap();
+        while let Some((arrived, _)) = nonces.order.front() {
+            if now.saturating_sub(*arrived) <= kept {
+                break;
+            }
+            let (_, old) = nonces.order.pop_front().unwrap();
+            nonces.seen.remove(&old);
+        }
+        let keys: Vec<(String, String)> = secrets
+            .iter()
+            .map(|secret| (secret.to_string(), nonce.to_string()))
+            .collect();
+        if keys.iter().any(|key| nonces.seen.contains(key)) {
+            return Err(AuSignatureError::Replayed);
+        }
+        if nonces.order.len() + keys.len() > self.nonce_cache_size {
+            debug!("refusing nonce {}: the nonce cache is full", nonce);
+            return Err(AuSignatureError::Saturated);
+        }
+        for key in keys {
+            nonces.seen.insert(key.clone());
+            nonces.order.push_back((now, key));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::path as twin;
+    use crate::au::signing::*;
+
+    fn signing() -> AuSigning {
+        AuSigning::new(&signing_config())
+    }
+
+    fn signing_config() -> SigningConfig {
+        SigningConfig {
+            secrets: vec![
+                AuSecret {
+                    path: "/actor/device".to_string(),
+                    secret: "type-secret".to_string(),
+                },
+                AuSecret {
+                    path: "/actor/device/thermostat-42".to_string(),
+                    secret: "twin-secret".to_string(),
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn signed(path: &str, timestamp: &str, nonce: &str, signature: &str) -> AuSignature {
+        AuSignature {
+            path: path.to_string(),
+            timestamp: Some(timestamp.to_string()),
+            nonce: Some(nonce.to_string()),
+            signature: Some(signature.to_string()),
+        }
+    }
+
+    #[test]
+    fn verify_works() {
+        let s = signing();
+        let path = "/actor/device/thermostat-42";
+        let twins = [twin(&["device", "thermostat-42"])];
+        let body = br#"[{"name": "temp", "value": 21.5}]"#;
+        let sig = sign("twin-secret", "1000", "n1", path, body);
+        assert_eq!(
+            s.verify_at(1010, &signed(path, "1000", "n1", &sig), &twins, body),
+            Ok(())
+        );
+        assert_eq!(
+            s.verify_at(1010, &signed(path, "1000", "n1", &sig), &twins, body),
+            Err(AuSignatureError::Replayed)
+        );
+        let sig = sign("twin-secret", "1000", "n2", path, body);
+        assert_eq!(
+            s.verify_at(2000, &signed(path, "1000", "n2", &sig), &twins, body),
+            Err(AuSignatureError::Expired)
+        );
+        let sig = sign("type-secret", "1000", "n3", path, body);
+        assert_eq!(
+            s.verify_at(1000, &signed(path, "1000", "n3", &sig), &twins, body),
+            Err(AuSignatureError::Invalid)
+        );
+        assert_eq!(
+            s.verify_at(1000, &AuSignature::default(), &twins, body),
+            Err(AuSignatureError::Missing)
+        );
+        assert_eq!(
+            s.verify_at(
+                1000,
+                &AuSignature::default(),
+                &[twin(&["person", "mary"])],
+                body
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn verify_many_works() {
+        let s = signing();
+        let body = br#"{"/actor/device/a": [], "/actor/person/mary": []}"#;
+        let twins = [twin(&["device", "a"]), twin(&["person", "mary"])];
+        let sig = sign("type-secret", "1000", "n1", "/batch", body);
+        assert_eq!(
+            s.verify_at(1000, &signed("/batch", "1000", "n1", &sig), &twins, body),
+            Ok(())
+        );
+        // the body is signed for its request path
+        let sig = sign("type-secret", "1000", "n2", "/write", body);
+        assert_eq!(
+            s.verify_at(1000, &signed("/batch", "1000", "n2", &sig), &twins, body),
+            Err(AuSignatureError::Invalid)
+        );
+        // one signature can not satisfy twins of different secrets
+        let twins = [twin(&["device", "a"]), twin(&["device", "thermostat-42"])];
+        let sig = sign("type-secret", "1000", "n3", "/batch", body);
+        assert_eq!(
+            s.verify_at(1000, &signed("/batch", "1000", "n3", &sig), &twins, body),
+            Err(AuSignatureError::Invalid)
+        );
+        assert!(s.secured(&twin(&["device", "b"])));
+        assert!(!s.secured(&twin(&["person", "mary"])));
+    }
+
+    #[test]
+    fn nonces_work() {
+        let s = AuSigning::new(&SigningConfig {
+            nonce_cache_size: 2,
+            ..signing_config()
+        });
+        let body = b"[]";
+        let verify = |now: i64, path: &str, secret: &str, nonce: &str| {
+            let sig = sign(secret, &now.to_string(), nonce, path, body);
+            let twins = [twin(&path.split('/').skip(2).collect::<Vec<&str>>())];
+            s.verify_at(
+                now,
+                &signed(path, &now.to_string(), nonce, &sig),
+                &twins,
+                body,
+            )
+        };
+        // devices of different secrets may pick the same nonce
+        assert_eq!(verify(1000, "/actor/device/a", "type-secret", "n1"), Ok(()));
+        assert_eq!(
+            verify(1000, "/actor/device/thermostat-42", "twin-secret", "n1"),
+            Ok(())
+        );
+        // a full cache forgets no nonce within the window
+        assert_eq!(
+            verify(1001, "/actor/device/a", "type-secret", "n2"),
+            Err(AuSignatureError::Saturated)
+        );
+        assert_eq!(
+            verify(1001, "/actor/device/a", "type-secret", "n1"),
+            Err(AuSignatureError::Replayed)
+        );
+        assert_eq!(verify(1400, "/actor/device/a", "type-secret", "n2"), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn signature_works() {
+        let filter = signature();
+        let signed = warp::test::request()
+            .path("/actor/device/a")
+            .header(NONCE_HEADER, "n1")
+            .filter(&filter)
+            .await
+            .unwrap();
+        assert_eq!(signed.path, "/actor/device/a");
+        assert_eq!(signed.nonce.as_deref(), Some("n1"));
+        // the query names the twin and command of a write, so it is signed too
+        let signed = warp::test::request()
+            .path("/commands/ack?path=device/a&id=7")
+            .filter(&filter)
+            .await
+            .unwrap();
+        assert_eq!(signed.path, "/commands/ack?path=device/a&id=7");
+    }
+
+    #[test]
+    fn timestamps_work() {
+        let s = signing();
+        let path = "/actor/device/a";
+        let twins = [twin(&["device", "a"])];
+        let body = b"[]";
+        let verify = |now: i64, ts: &str, nonce: &str| {
+            let sig = sign("type-secret", ts, nonce, path, body);
+            s.verify_at(now, &signed(path, ts, nonce, &sig), &twins, body)
+        };
+        // a timestamp a little ahead of the clock is accepted, one further ahead is not
+        assert_eq!(verify(1000, "1005", "n1"), Ok(()));
+        assert_eq!(verify(1000, "1006", "n2"), Err(AuSignatureError::Expired));
+        // its nonce is remembered until the timestamp leaves the window
+        assert_eq!(verify(1305, "1005", "n1"), Err(AuSignatureError::Replayed));
+        assert_eq!(verify(1306, "1005", "n1"), Err(AuSignatureError::Expired));
+        // timestamps too far from the clock to subtract are expired, not a panic
+        assert_eq!(
+            verify(1000, &i64::MIN.to_string(), "n3"),
+            Err(AuSignatureError::Expired)
+        );
+        assert_eq!(
+            verify(-1000, &i64::MAX.to_string(), "n4"),
+            Err(AuSignatureError::Expired)
+        );
+    }
+}
@@ -0,0 +1,489 @@
+//! StatsD ingestion - a UDP listener aggregating `name:value|type[|@rate]` lines.
+//!
+//! A dotted metric name is mapped onto a twin by the first mapping whose pattern it matches - the
+//! segments matched by `*` fill the numbered placeholders of the mapping's `path` and `name`
+//! templates, ie: `device.*.*` with `/actor/device/{1}` and `{2}`.  A name no mapping matches is
+//! dropped unless `fallback` is set, when its last segment names the telemetry and the others are
+//! the twin path: `device.pump-1.rpm` is `rpm` of `/actor/device/pump-1`.  UDP carries no
+//! credentials, so the fallback lets any sender reach any twin and is off by default.
+//!
+//! Metrics are aggregated and Told once per flush interval, one Tell per twin:
+//!
+//!   * gauges (`g`) - the last value, `+n` and `-n` adjusting it.  a gauge not updated for
+//!     `gauge_expiry_ms` is forgotten.
+//!   * counters (`c`) - the sum of the interval, scaled by the sample rate.
+//!   * timers (`ms`, `h`) - `.count`, `.mean`, `.min` and `.max` of the interval.
+//!   * sets (`s`) - the number of distinct values of the interval.
+//!
+//! At most `max_keys` names are aggregated at once and `max_samples` timer values or set members
+//! kept per name and interval, the lines beyond them are dropped and counted.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::au::ingest::{fill, group, twin_path};
+use crate::au::metrics::STATSD_DROPPED;
+use crate::au::model::{AuTelemetry, AuTwin};
+
+/// Maps the metric names matching a pattern onto a twin.
+#[derive(Clone, Deserialize)]
+pub struct AuStatsdMapping {
+    /// dotted pattern, ie: `device.*.*`
+    pub pattern: String,
+    /// template of the twin path, ie: `/actor/device/{1}`
+    pub path: String,
+    /// template of the telemetry name, the last segment by default
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct StatsdConfig {
+    /// udp address listened on
+    pub bind: String,
+    /// milliseconds between the Tells of the aggregates, above 0
+    pub flush_interval_ms: u64,
+    /// milliseconds a gauge not updated keeps its last value before it is forgotten
+    pub gauge_expiry_ms: u64,
+    /// tried in order, the first mapping a name is used
+    pub mappings: Vec<AuStatsdMapping>,
+    /// map the names no mapping matches onto the twin path of their segments
+    pub fallback: bool,
+    /// names aggregated at once, above 0 - a line of another name is dropped
+    pub max_keys: usize,
+    /// timer values or set members kept per name and interval, above 0
+    pub max_samples: usize,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        StatsdConfig {
+            bind: "127.0.0.1:8125".to_string(),
+            flush_interval_ms: 10_000,
+            gauge_expiry_ms: 3_600_000,
+            mappings: Vec::new(),
+            fallback: false,
+            max_keys: 10_000,
+            max_samples: 10_000,
+        }
+    }
+}
+
+impl StatsdConfig {
+    /// refuse settings the listener can not run with
+    pub fn validate(&self) -> Result<(), String> {
+        if self.flush_interval_ms == 0 {
+            return Err("invalid config: statsd flush_interval_ms must be above 0".to_string());
+        }
+        if self.max_keys == 0 || self.max_samples == 0 {
+            return Err(
+                "invalid config: statsd max_keys and max_samples must be above 0".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// a twin path and telemetry name
+type AuKey = (Vec<String>, String);
+
+pub struct AuStatsd {
+    mappings: Vec<AuStatsdMapping>,
+    fallback: bool,
+    gauge_expiry_ms: u64,
+    max_keys: usize,
+    max_samples: usize,
+    /// lines dropped over the limits since last taken
+    dropped: u64,
+    counters: HashMap<AuKey, f64>,
+    /// the last value of every gauge, they persist across flushes until they expire
+    gauges: HashMap<AuKey, f64>,
+    gauges_updated: HashSet<AuKey>,
+    /// when each gauge was last Told
+    gauges_told: HashMap<AuKey, DateTime<Utc>>,
+    timers: HashMap<AuKey, Vec<f64>>,
+    sets: HashMap<AuKey, HashSet<String>>,
+}
+
+/// the segments matched by the `*` of a pattern, `None` if the name does not match
+fn captures(pattern: &str, name: &str) -> Option<Vec<String>> {
+    let segments: Vec<&str> = name.split('.').collect();
+    let patterns: Vec<&str> = pattern.split('.').collect();
+    if segments.len() != patterns.len() {
+        return None;
+    }
+    let mut captured = Vec::new();
+    for (p, s) in patterns.iter().zip(segments.iter()) {
+        match *p {
+            "*" => captured.push(s.to_string()),
+            p if p == *s => {}
+            _ => return None,
+        }
+    }
+    Some(captured)
+}
+
+impl AuStatsd {
+    pub fn new(config: &StatsdConfig) -> AuStatsd {
+        AuStatsd {
+            mappings: config.mappings.clone(),
+            fallback: config.fallback,
+            gauge_expiry_ms: config.gauge_expiry_ms,
+            max_keys: config.max_keys,
+            max_samples: config.max_samples,
+            dropped: 0,
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            gauges_updated: HashSet::new(),
+            gauges_told: HashMap::new(),
+            timers: HashMap::new(),
+            sets: HashMap::new(),
+        }
+    }
+
+    fn map(&self, name: &str) -> Option<AuKey> {
+        let mapped = self.mappings.iter().find_map(|m| {
+            let captured = captures(&m.pattern, name)?;
+            let lookup = |k: &str| {
+                let n: usize = k.parse().ok()?;
+                captured.get(n.checked_sub(1)?).cloned()
+            };
+            let path = twin_path(&fill(&m.path, lookup)?)?;
+            let name = match &m.name {
+                Some(template) => fill(template, lookup)?,
+                None => name.rsplit('.').next().unwrap_or_default().to_string(),
+            };
+            Some((path, name))
+        });
+        if mapped.is_some() || !self.fallback {
+            return mapped;
+        }
+        let (path, last) = name.rsplit_once('.')?;
+        Some((twin_path(&path.replace('.', "/"))?, last.to_string()))
+    }
+
+    /// aggregate a line, ie: `device.pump-1.rpm:1200|g`
+    pub fn add(&mut self, line: &str) -> Result<(), String> {
+        let invalid = || format!("invalid statsd line {}", line);
+        let (name, rest) = line.split_once(':').ok_or_else(invalid)?;
+        let mut parts = rest.split('|');
+        let value = parts.next().ok_or_else(invalid)?;
+        let kind = parts.next().ok_or_else(invalid)?;
+        let rate = match parts.find_map(|p| p.strip_prefix('@')) {
+            Some(r) => r
+                .parse::<f64>()
+                .ok()
+                .filter(|r| *r > 0.0)
+                .ok_or_else(invalid)?,
+            None => 1.0,
+        };
+        let key = self
+            .map(name)
+            .ok_or_else(|| format!("no twin for {}", name))?;
+        if kind == "s" {
+            let known = self.sets.contains_key(&key);
+            self.admit(known, name)?;
+            let set = self.sets.entry(key).or_default();
+            if set.len() >= self.max_samples && !set.contains(value) {
+                return Err(self.refuse("samples", name));
+            }
+            set.insert(value.to_string());
+            return Ok(());
+        }
+        let number: f64 = value.parse().map_err(|_| invalid())?;
+        let known = match kind {
+            "g" => self.gauges.contains_key(&key),
+            "c" => self.counters.contains_key(&key),
+            "ms" | "h" => self.timers.contains_key(&key),
+            _ => return Err(invalid()),
+        };
+        self.admit(known, name)?;
+        match kind {
+            "g" => {
+                // a signed gauge value adjusts the last one
+                let gauge = self.gauges.entry(key.clone()).or_insert(0.0);
+                if value.starts_with('+') || value.starts_with('-') {
+                    *gauge += number;
+                } else {
+                    *gauge = number;
+                }
+                self.gauges_updated.insert(key);
+            }
+            "c" => *self.counters.entry(key).or_insert(0.0) += number / rate,
+            _ => {
+                let values = self.timers.entry(key).or_default();
+                if values.len() >= self.max_samples {
+                    return Err(self.refuse("samples", name));
+                }
+                values.push(number);
+            }
+        }
+        Ok(())
+    }
+
+    /// refuse a name not yet aggregated once `max_keys` are
+    fn admit(&mut self, known: bool, name: &str) -> Result<(), String> {
+        let keys = self.counters.len() + self.gauges.len() + self.timers.len() + self.sets.len();
+        if known || keys < self.max_keys {
+            return Ok(());
+        }
+        Err(self.refuse("keys", name))
+    }
+
+    /// count a line dropped over `max_keys` or `max_samples`
+    fn refuse(&mut self, limit: &str, name: &str) -> String {
+        self.dropped += 1;
+        STATSD_DROPPED.with_label_values(&[limit]).inc();
+        format!("dropped {}, over statsd max_{}", name, limit)
+    }
+
+    /// the lines dropped over the limits since last taken
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// the aggregates of the interval, forgetting them
+    pub fn flush(&mut self, now: DateTime<Utc>) -> Vec<AuTwin> {
+        let mut records = Vec::new();
+        let mut record = |(path, name): AuKey, value: f64| {
+            records.push((
+                path,
+                AuTelemetry {
+                    datetime: now,
+                    name,
+                    value: value.into(),
+                    unit: None,
+                    labels: BTreeMap::new(),
+                    violations: Vec::new(),
+                },
+            ))
+        };
+        for (key, value) in self.counters.drain() {
+            record(key, value);
+        }
+        for key in self.gauges_updated.drain() {
+            let value = self.gauges[&key];
+            self.gauges_told.insert(key.clone(), now);
+            record(key, value);
+        }
+        // a gauge idle beyond the expiry is forgotten so the names ever seen do not pile up
+        let expiry = i64::try_from(self.gauge_expiry_ms)
+            .ok()
+            .map(chrono::Duration::milliseconds);
+        let told = &mut self.gauges_told;
+        told.retain(|_, t| expiry.is_none_or(|e| now.signed_duration_since(*t) <= e));
+        self.gauges.retain(|key, _| told.contains_key(key));
+        for ((path, name), values) in self.timers.drain() {
+            let count = values.len() as f64;
+            let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let mean = values.iter().sum::<f64>() / count;
+            record((path.clone(), format!("{}.count", name)), count);
+            record((path.clone(), format!("{}.mean", name)), mean);
+            record((path.clone(), format!("{}.min", name)), min);
+            record((path, format!("{}.max", name)), max);
+        }
+        for (key, values) in self.sets.drain() {
+            record(key, values.len() as f64);
+        }
+        group(records)
+    }
+}
+
+/// listen for StatsD lines and Tell their aggregates every flush interval
+pub fn start<F>(config: StatsdConfig, tell: F)
+where
+    F: Fn(Vec<AuTwin>) + Send + 'static,
+{
+    use std::time::Duration;
+
+    use log::{debug, error, info, warn};
+    use tokio::net::UdpSocket;
+
+    tokio::spawn(async move {
+        let socket = match UdpSocket::bind(&config.bind).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("can not bind statsd {}: {}", config.bind, e);
+                return;
+            }
+        };
+        info!("listening for statsd on {}", config.bind);
+        let mut statsd = AuStatsd::new(&config);
+        let mut flush = tokio::time::interval(Duration::from_millis(config.flush_interval_ms));
+        let mut buf = vec![0u8; 65_535];
+        loop {
+            tokio::select! {
+                received = socket.recv(&mut buf) => match received {
+                    Ok(n) => {
+                        for line in String::from_utf8_lossy(&buf[..n]).lines() {
+                            if let Err(e) = statsd.add(line.trim()) {
+                                debug!("{}", e);
+                            }
+                        }
+                    }
+                    Err(e) => error!("statsd receive error: {}", e),
+                },
+                _ = flush.tick() => {
+                    let dropped = statsd.take_dropped();
+                    if dropped > 0 {
+                        warn!("dropped {} statsd lines over max_keys or max_samples", dropped);
+                    }
+                    let twins = statsd.flush(Utc::now());
+                    if !twins.is_empty() {
+                        tell(twins);
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::path;
+    use crate::au::statsd::*;
+
+    fn fallback() -> StatsdConfig {
+        StatsdConfig {
+            fallback: true,
+            ..Default::default()
+        }
+    }
+
+    fn value(twins: &[AuTwin], name: &str) -> Option<f64> {
+        twins
+            .iter()
+            .flat_map(|(_, t)| t.iter())
+            .find(|t| t.name == name)
+            .and_then(|t| t.value.as_f64())
+    }
+
+    #[test]
+    fn map_works() {
+        let config: StatsdConfig = serde_json::from_str(
+            r#"{"mappings": [
+                {"pattern": "plant.*.line.*.*", "path": "/actor/line/{2}", "name": "{1}.{3}"}
+            ]}"#,
+        )
+        .unwrap();
+        let statsd = AuStatsd::new(&config);
+        assert_eq!(
+            statsd.map("plant.north.line.l7.speed"),
+            Some((path(&["line", "l7"]), "north.speed".to_string()))
+        );
+        // names no mapping matches are dropped unless the fallback is set
+        assert_eq!(statsd.map("device.pump-1.rpm"), None);
+        let statsd = AuStatsd::new(&StatsdConfig {
+            fallback: true,
+            ..config
+        });
+        assert_eq!(
+            statsd.map("device.pump-1.rpm"),
+            Some((path(&["device", "pump-1"]), "rpm".to_string()))
+        );
+        assert_eq!(statsd.map("device.rpm"), None);
+    }
+
+    #[test]
+    fn flush_works() {
+        let mut statsd = AuStatsd::new(&fallback());
+        for line in [
+            "device.p1.starts:1|c",
+            "device.p1.starts:1|c|@0.5",
+            "device.p1.level:10|g",
+            "device.p1.level:-3|g",
+            "device.p1.latency:10|ms",
+            "device.p1.latency:30|ms",
+            "device.p1.users:a|s",
+            "device.p1.users:a|s",
+            "device.p1.users:b|s",
+        ] {
+            statsd.add(line).unwrap();
+        }
+        assert!(statsd.add("device.p1.x:1|zz").is_err());
+        assert!(statsd.add("garbage").is_err());
+        let twins = statsd.flush(Utc::now());
+        assert_eq!(twins.len(), 1);
+        assert_eq!(value(&twins, "starts"), Some(3.0));
+        assert_eq!(value(&twins, "level"), Some(7.0));
+        assert_eq!(value(&twins, "latency.mean"), Some(20.0));
+        assert_eq!(value(&twins, "latency.max"), Some(30.0));
+        assert_eq!(value(&twins, "users"), Some(2.0));
+
+        statsd.add("device.p1.level:+1|g").unwrap();
+        let twins = statsd.flush(Utc::now());
+        assert_eq!(twins[0].1.len(), 1);
+        assert_eq!(value(&twins, "level"), Some(8.0));
+        assert!(statsd.flush(Utc::now()).is_empty());
+    }
+
+    #[test]
+    fn gauge_expiry_works() {
+        let mut statsd = AuStatsd::new(&StatsdConfig {
+            gauge_expiry_ms: 1_000,
+            ..fallback()
+        });
+        let now = Utc::now();
+        statsd.add("device.p1.level:10|g").unwrap();
+        statsd.flush(now);
+        statsd.flush(now + chrono::Duration::milliseconds(1_000));
+        assert_eq!(statsd.gauges.len(), 1);
+        statsd.flush(now + chrono::Duration::milliseconds(1_001));
+        assert!(statsd.gauges.is_empty());
+        // an adjustment of a forgotten gauge starts from 0
+        statsd.add("device.p1.level:+1|g").unwrap();
+        let twins = statsd.flush(now + chrono::Duration::milliseconds(2_000));
+        assert_eq!(value(&twins, "level"), Some(1.0));
+    }
+
+    #[test]
+    fn limits_work() {
+        let mut statsd = AuStatsd::new(&StatsdConfig {
+            max_keys: 2,
+            max_samples: 2,
+            ..fallback()
+        });
+        for line in [
+            "device.p1.latency:10|ms",
+            "device.p1.latency:20|ms",
+            "device.p1.users:a|s",
+            "device.p1.users:b|s",
+            "device.p1.users:b|s",
+        ] {
+            statsd.add(line).unwrap();
+        }
+        assert!(statsd.add("device.p1.latency:30|ms").is_err());
+        assert!(statsd.add("device.p1.users:c|s").is_err());
+        assert!(statsd.add("device.p1.starts:1|c").is_err());
+        assert_eq!(statsd.take_dropped(), 3);
+        assert_eq!(statsd.take_dropped(), 0);
+        let twins = statsd.flush(Utc::now());
+        assert_eq!(value(&twins, "latency.max"), Some(20.0));
+        assert_eq!(value(&twins, "users"), Some(2.0));
+        // the flush makes room, but for the gauges that persist
+        statsd.add("device.p1.level:1|g").unwrap();
+        statsd.add("device.p1.starts:1|c").unwrap();
+        statsd.flush(Utc::now());
+        statsd.add("device.p1.level:2|g").unwrap();
+        statsd.add("device.p1.starts:1|c").unwrap();
+        assert!(statsd.add("device.p1.errors:1|c").is_err());
+    }
+
+    #[test]
+    fn validate_works() {
+        assert!(StatsdConfig::default().validate().is_ok());
+        let config: StatsdConfig = serde_json::from_str(r#"{"flush_interval_ms": 0}"#).unwrap();
+        assert!(config.validate().is_err());
+        for json in [r#"{"max_keys": 0}"#, r#"{"max_samples": 0}"#] {
+            let config: StatsdConfig = serde_json::from_str(json).unwrap();
+            assert!(config.validate().is_err(), "{}", json);
+        }
+        assert!(
+            crate::au::config::AuConfig::from_json(r#"{"statsd": {"flush_interval_ms": 0}}"#)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,566 @@
+//! Device models as twin types, and Thing Descriptions of live twins.
+//!
+//! The `models` of the schema config name DTDL interfaces and W3C WoT Thing Descriptions, files
+//! or directories of `.json` files, loaded at startup.  The telemetry and properties of a DTDL
+//! interface, and the properties of a Thing Description, become the telemetry of a twin type
+//! schema - their names, value types, ranges and units:
+//!
+//!   `{"schemas": {"models": ["models/refrigerator.json", "models/td"]}}`
+//!
+//! The type of a DTDL interface is the last segment of its id, `dtmi:com:example:Refrigerator;1`
+//! describing `refrigerator` twins, the type of a Thing Description its title.  A type declared
+//! in `types` replaces the model of the same name.
+//!
+//! `GET /thing?path=refrigerator/r1` answers a Thing Description of a live twin, a property for
+//! each telemetry name it reports or its type declares.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+use tokio::time::error::Elapsed;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::auth::AuPermission;
+use crate::au::ingest::twin_path;
+use crate::au::model::AuOperator::Ask;
+use crate::au::model::AuTelemetry;
+use crate::au::schema::{AuTelemetrySchema, AuTypeSchema};
+use crate::au::twins::{timeout_reply, AuTwins};
+use crate::au::units::lookup;
+
+/// the content type of a Thing Description
+pub const CONTENT_TYPE: &str = "application/td+json";
+
+/// The `path` of a Thing Description request is not a twin path - 400.
+#[derive(Debug)]
+pub struct AuBadThing(pub String);
+
+impl warp::reject::Reject for AuBadThing {}
+
+/// DTDL unit names and the UCUM codes they translate to, also matching the unit names of Thing
+/// Descriptions, ie: `om:degree_Celsius`
+const UNIT_NAMES: &[(&str, &str)] = &[
+    ("kelvin", "K"),
+    ("degreeCelsius", "Cel"),
+    ("celsius", "Cel"),
+    ("degreeFahrenheit", "[degF]"),
+    ("fahrenheit", "[degF]"),
+    ("metre", "m"),
+    ("meter", "m"),
+    ("kilometre", "km"),
+    ("kilometer", "km"),
+    ("centimetre", "cm"),
+    ("centimeter", "cm"),
+    ("millimetre", "mm"),
+    ("millimeter", "mm"),
+    ("inch", "[in_i]"),
+    ("foot", "[ft_i]"),
+    ("mile", "[mi_i]"),
+    ("kilogram", "kg"),
+    ("gram", "g"),
+    ("milligram", "mg"),
+    ("tonne", "t"),
+    ("pound", "[lb_av]"),
+    ("second", "s"),
+    ("millisecond", "ms"),
+    ("minute", "min"),
+    ("hour", "h"),
+    ("day", "d"),
+    ("pascal", "Pa"),
+    ("hectopascal", "hPa"),
+    ("kilopascal", "kPa"),
+    ("bar", "bar"),
+    ("millibar", "mbar"),
+    ("poundPerSquareInch", "[psi]"),
+    ("metrePerSecond", "m/s"),
+    ("meterPerSecond", "m/s"),
+    ("kilometrePerHour", "km/h"),
+    ("kilometerPerHour", "km/h"),
+    ("milePerHour", "[mi_i]/h"),
+    ("joule", "J"),
+    ("kilojoule", "kJ"),
+    ("wattHour", "W.h"),
+    ("kilowattHour", "kW.h"),
+    ("watt", "W"),
+    ("kilowatt", "kW"),
+    ("cubicMetre", "m3"),
+    ("cubicMeter", "m3"),
+    ("litre", "l"),
+    ("liter", "l"),
+    ("millilitre", "ml"),
+    ("milliliter", "ml"),
+    ("volt", "V"),
+    ("millivolt", "mV"),
+    ("ampere", "A"),
+    ("milliampere", "mA"),
+    ("hertz", "Hz"),
+    ("kilohertz", "kHz"),
+    ("unity", "1"),
+    ("percent", "%"),
+    ("partsPerMillion", "[ppm]"),
+];
+
+/// the UCUM code of a model's unit - a UCUM code itself or a unit name, ie: `degreeCelsius`
+fn unit_code(unit: &str) -> Option<&'static str> {
+    if let Some(unit) = lookup(unit) {
+        return Some(unit.code);
+    }
+    // names are compared without their prefix, case, spaces or underscores
+    let normalize = |name: &str| -> String {
+        let name = name.rsplit(':').next().unwrap_or(name);
+        name.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect()
+    };
+    let name = normalize(unit);
+    UNIT_NAMES
+        .iter()
+        .find(|(n, _)| normalize(n) == name)
+        .map(|(_, code)| *code)
+}
+
+/// the telemetry schema of a declared unit, warned about and ignored when it is unknown
+fn with_unit(
+    mut schema: AuTelemetrySchema,
+    unit: Option<&Value>,
+    typ: &str,
+    name: &str,
+) -> AuTelemetrySchema {
+    if let Some(unit) = unit.and_then(Value::as_str) {
+        match unit_code(unit) {
+            Some(code) => schema.unit = Some(code.to_string()),
+            None => warn!("ignoring unknown unit {} of {}.{}", unit, typ, name),
+        }
+    }
+    schema
+}
+
+/// the value type of a DTDL schema, any type for dates, durations, objects, maps and arrays
+fn dtdl_schema(schema: &Value) -> AuTelemetrySchema {
+    let value_type = |t: &str| AuTelemetrySchema {
+        value_type: Some(t.to_string()),
+        ..Default::default()
+    };
+    match schema {
+        Value::String(s) => match s.as_str() {
+            "double" | "float" => value_type("number"),
+            "integer" | "long" | "short" | "byte" | "unsignedInteger" | "unsignedLong"
+            | "unsignedShort" | "unsignedByte" => value_type("integer"),
+            "boolean" => value_type("boolean"),
+            "string" => value_type("string"),
+            s if s == "point" || s.starts_with("dtmi:standard:schema:geospatial:point") => {
+                value_type("location")
+            }
+            _ => AuTelemetrySchema::default(),
+        },
+        Value::Object(o) if o.get("@type").and_then(Value::as_str) == Some("Enum") => {
+            // the states of a string enum, an integer enum reports its integers
+            if o.get("valueSchema").and_then(Value::as_str) != Some("string") {
+                return value_type("integer");
+            }
+            let values = o.get("enumValues").and_then(Value::as_array).map(|values| {
+                values
+                    .iter()
+                    .filter_map(|v| v.get("enumValue").and_then(Value::as_str))
+                    .map(str::to_string)
+                    .collect()
+            });
+            AuTelemetrySchema {
+                values,
+                ..value_type("enum")
+            }
+        }
+        _ => AuTelemetrySchema::default(),
+    }
+}
+
+/// whether a DTDL content is of a `@type`, given as a string or a list
+fn is_a(content: &Value, typ: &str) -> bool {
+    match content.get("@type") {
+        Some(Value::String(t)) => t == typ,
+        Some(Value::Array(types)) => types.iter().any(|t| t.as_str() == Some(typ)),
+        _ => false,
+    }
+}
+
+/// the twin type and schema of a DTDL interface
+fn dtdl_interface(interface: &Value) -> Result<(String, AuTypeSchema), String> {
+    let id = interface
+        .get("@id")
+        .and_then(Value::as_str)
+        .ok_or("a DTDL interface without an @id")?;
+    // dtmi:com:example:Refrigerator;1
+    let typ = id
+        .split(';')
+        .next()
+        .and_then(|id| id.rsplit(':').next())
+        .filter(|t| !t.is_empty())
+        .ok_or_else(|| format!("invalid DTDL id {}", id))?
+        .to_lowercase();
+    let mut schema = AuTypeSchema::default();
+    let contents = interface.get("contents").and_then(Value::as_array);
+    for content in contents.into_iter().flatten() {
+        if !is_a(content, "Telemetry") && !is_a(content, "Property") {
+            continue;
+        }
+        let name = content
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("a content of {} without a name", id))?;
+        let telemetry = dtdl_schema(content.get("schema").unwrap_or(&Value::Null));
+        let telemetry = with_unit(telemetry, content.get("unit"), &typ, name);
+        schema.telemetry.insert(name.to_string(), telemetry);
+    }
+    Ok((typ, schema))
+}
+
+/// the telemetry schema of a Thing Description's property
+fn td_property(property: &Value) -> AuTelemetrySchema {
+    let values: Option<Vec<String>> = property.get("enum").and_then(Value::as_array).map(|v| {
+        v.iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect()
+    });
+    let fields = property.get("properties");
+    let value_type = match property.get("type").and_then(Value::as_str) {
+        Some("string") if values.is_some() => Some("enum"),
+        Some(t @ ("number" | "integer" | "boolean" | "string")) => Some(t),
+        Some("object")
+            if fields.and_then(|f| f.get("lat")).is_some()
+                && fields.and_then(|f| f.get("lon")).is_some() =>
+        {
+            Some("location")
+        }
+        _ => None,
+    };
+    AuTelemetrySchema {
+        value_type: value_type.map(str::to_string),
+        min: property.get("minimum").and_then(Value::as_f64),
+        max: property.get("maximum").and_then(Value::as_f64),
+        unit: None,
+        values: values.filter(|_| value_type == Some("enum")),
+    }
+}
+
+/// the twin type and schema of a Thing Description
+fn thing_description(td: &Value) -> Result<(String, AuTypeSchema), String> {
+    let typ = td
+        .get("title")
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|t| !t.is_empty() && !t.contains('/'))
+        .ok_or("a Thing Description without a title naming its type")?
+        .to_lowercase();
+    let mut schema = AuTypeSchema::default();
+    let properties = td.get("properties").and_then(Value::as_object);
+    for (name, property) in properties.into_iter().flatten() {
+        let telemetry = with_unit(td_property(property), property.get("unit"), &typ, name);
+        schema.telemetry.insert(name.clone(), telemetry);
+    }
+    Ok((typ, schema))
+}
+
+/// the twin types of a model document - a DTDL interface, a list of them or a Thing Description
+pub fn translate(model: &Value) -> Result<Vec<(String, AuTypeSchema)>, String> {
+    match model {
+        Value::Array(interfaces) => interfaces.iter().map(dtdl_interface).collect(),
+        m if is_a(m, "Interface") => Ok(vec![dtdl_interface(m)?]),
+        m if m.get("properties").is_some() || m.get("title").is_some() => {
+            Ok(vec![thing_description(m)?])
+        }
+        _ => Err("neither a DTDL interface nor a Thing Description".to_string()),
+    }
+}
+
+/// the model files named, the `.json` files of a directory in order
+fn files(path: &Path) -> Result<Vec<std::path::PathBuf>, String> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let entries =
+        fs::read_dir(path).map_err(|e| format!("can not read models {}: {}", path.display(), e))?;
+    let mut files: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("json"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// the twin types of the model files and directories, each type modelled once
+pub fn load(paths: &[String]) -> Result<HashMap<String, AuTypeSchema>, String> {
+    let mut types = HashMap::new();
+    for path in paths {
+        for file in files(Path::new(path))? {
+            let json = fs::read_to_string(&file)
+                .map_err(|e| format!("can not read model {}: {}", file.display(), e))?;
+            let model: Value = serde_json::from_str(&json)
+                .map_err(|e| format!("invalid model {}: {}", file.display(), e))?;
+            let translated = translate(&model)
+                .map_err(|e| format!("invalid model {}: {}", file.display(), e))?;
+            for (typ, schema) in translated {
+                info!("loaded model of {} from {}", typ, file.display());
+                if types.insert(typ.clone(), schema).is_some() {
+                    return Err(format!("type {} modelled more than once", typ));
+                }
+            }
+        }
+    }
+    Ok(types)
+}
+
+/// the data schema of a Thing Description's property
+fn property(schema: &AuTelemetrySchema, href: &str) -> Value {
+    let mut property = Map::new();
+    match schema.value_type.as_deref() {
+        Some("enum") => {
+            property.insert("type".to_string(), json!("string"));
+        }
+        Some("location") => {
+            property.insert("type".to_string(), json!("object"));
+            property.insert(
+                "properties".to_string(),
+                json!({
+                    "lat": {"type": "number", "minimum": -90, "maximum": 90},
+                    "lon": {"type": "number", "minimum": -180, "maximum": 180},
+                    "alt": {"type": "number"}
+                }),
+            );
+        }
+        Some(t) => {
+            property.insert("type".to_string(), json!(t));
+        }
+        None => {}
+    }
+    if let Some(min) = schema.min {
+        property.insert("minimum".to_string(), json!(min));
+    }
+    if let Some(max) = schema.max {
+        property.insert("maximum".to_string(), json!(max));
+    }
+    if let Some(values) = &schema.values {
+        property.insert("enum".to_string(), json!(values));
+    }
+    if let Some(unit) = &schema.unit {
+        property.insert("unit".to_string(), json!(unit));
+    }
+    property.insert("readOnly".to_string(), json!(true));
+    property.insert(
+        "forms".to_string(),
+        json!([{"href": href, "op": "readproperty", "contentType": "application/json"}]),
+    );
+    Value::Object(property)
+}
+
+/// a Thing Description of a twin, a property for each name it reports or its type declares.
+/// a declared name is described by its schema, a reported one by its latest record.
+pub fn describe(
+    path: &[String],
+    telemetry: &[AuTelemetry],
+    schema: Option<&AuTypeSchema>,
+    secured: bool,
+) -> Value {
+    let href = format!("/actor/{}", path.join("/"));
+    let mut schemas: BTreeMap<String, AuTelemetrySchema> = BTreeMap::new();
+    for t in telemetry {
+        schemas
+            .entry(t.name.clone())
+            .or_insert_with(|| AuTelemetrySchema {
+                value_type: Some(t.value.type_name().to_string()),
+                unit: t.unit.clone(),
+                ..Default::default()
+            });
+    }
+    if let Some(schema) = schema {
+        for (name, declared) in schema.telemetry.iter() {
+            schemas.insert(name.clone(), declared.clone());
+        }
+    }
+    let properties: Map<String, Value> = schemas
+        .iter()
+        .map(|(name, schema)| (name.clone(), property(schema, &href)))
+        .collect();
+    let (scheme, definition) = if secured {
+        (
+            "bearer_sc",
+            json!({"scheme": "bearer", "in": "header", "name": "authorization"}),
+        )
+    } else {
+        ("nosec_sc", json!({"scheme": "nosec"}))
+    };
+    let typ = path.len().checked_sub(2).map(|i| path[i].as_str());
+    json!({
+        "@context": "https://www.w3.org/2022/wot/td/v1.1",
+        "id": format!("urn:augorama:{}", path.join("/")),
+        "title": path.join("/"),
+        "description": format!("twin of type {}", typ.unwrap_or_default()),
+        "securityDefinitions": {scheme: definition},
+        "security": scheme,
+        "properties": properties,
+        "forms": [{"href": href, "op": "readallproperties", "contentType": "application/json"}]
+    })
+}
+
+/// the reply to a Thing Description request - the description or a 504 if the twin's actor did
+/// not answer
+fn thing_reply(result: Result<Value, Elapsed>) -> Response {
+    match result {
+        Ok(td) => warp::reply::with_header(warp::reply::json(&td), "Content-Type", CONTENT_TYPE)
+            .into_response(),
+        Err(_) => timeout_reply(),
+    }
+}
+
+/// `GET /thing?path=`
+pub fn routes(twins: &AuTwins) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let twins = twins.clone();
+    warp::path("thing")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |query: HashMap<String, String>, header: Option<String>| {
+                let path = query.get("path").map(String::as_str).unwrap_or_default();
+                let result = twin_path(path)
+                    .ok_or_else(|| {
+                        warp::reject::custom(AuBadThing(format!("invalid twin path {}", path)))
+                    })
+                    .and_then(|path| {
+                        twins
+                            .auth
+                            .check(header.as_deref(), AuPermission::Ask, &path)
+                            .map(|_| path)
+                            .map_err(warp::reject::custom)
+                    })
+                    .and_then(|path| {
+                        // only a live twin is described, no actor is created for an unknown root
+                        let actor = twins
+                            .live_root(&path[0])
+                            .ok_or_else(warp::reject::not_found)?;
+                        let asked = twins.ask_path(&actor, Ask, path[1..].to_vec());
+                        Ok(asked.map(|msg| {
+                            let typ = &path[path.len() - 2];
+                            describe(
+                                &path,
+                                &msg.data.unwrap_or_default(),
+                                twins.schemas.schema(typ),
+                                twins.auth.enabled(),
+                            )
+                        }))
+                    });
+                async move { result }
+            },
+        )
+        .map(thing_reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::model::AuValue;
+    use crate::au::thing::*;
+
+    #[test]
+    fn dtdl_works() {
+        let model: Value = serde_json::from_str(
+            r#"{"@context": "dtmi:dtdl:context;2", "@id": "dtmi:com:example:Refrigerator;1",
+                "@type": "Interface", "contents": [
+                {"@type": ["Telemetry", "Temperature"], "name": "temp", "schema": "double",
+                 "unit": "degreeCelsius"},
+                {"@type": "Property", "name": "door", "schema": {"@type": "Enum",
+                 "valueSchema": "string", "enumValues": [{"name": "open", "enumValue": "open"},
+                 {"name": "closed", "enumValue": "closed"}]}},
+                {"@type": "Property", "name": "serial", "schema": "string"},
+                {"@type": "Command", "name": "defrost"}
+            ]}"#,
+        )
+        .unwrap();
+        let types = translate(&model).unwrap();
+        let (typ, schema) = &types[0];
+        assert_eq!(typ, "refrigerator");
+        assert_eq!(schema.telemetry.len(), 3);
+        let temp = &schema.telemetry["temp"];
+        assert_eq!(temp.value_type.as_deref(), Some("number"));
+        assert_eq!(temp.unit.as_deref(), Some("Cel"));
+        let door = &schema.telemetry["door"];
+        assert_eq!(door.value_type.as_deref(), Some("enum"));
+        assert_eq!(
+            door.values,
+            Some(vec!["open".to_string(), "closed".to_string()])
+        );
+        assert!(translate(&json!({"name": "nothing"})).is_err());
+    }
+
+    #[test]
+    fn td_works() {
+        let model: Value = serde_json::from_str(
+            r#"{"@context": "https://www.w3.org/2022/wot/td/v1.1", "title": "Fridge",
+                "properties": {
+                "temp": {"type": "number", "minimum": -40, "maximum": 20,
+                         "unit": "om:degree_Celsius"},
+                "door": {"type": "string", "enum": ["open", "closed"]},
+                "where": {"type": "object", "properties": {"lat": {}, "lon": {}}},
+                "rpm": {"type": "integer", "unit": "rpm"}
+            }}"#,
+        )
+        .unwrap();
+        let types = translate(&model).unwrap();
+        let (typ, schema) = &types[0];
+        assert_eq!(typ, "fridge");
+        let temp = &schema.telemetry["temp"];
+        assert_eq!((temp.min, temp.max), (Some(-40.0), Some(20.0)));
+        assert_eq!(temp.unit.as_deref(), Some("Cel"));
+        assert_eq!(schema.telemetry["door"].value_type.as_deref(), Some("enum"));
+        assert_eq!(
+            schema.telemetry["where"].value_type.as_deref(),
+            Some("location")
+        );
+        // units without a UCUM code are left out
+        assert_eq!(schema.telemetry["rpm"].unit, None);
+    }
+
+    #[test]
+    fn describe_works() {
+        let path = vec!["fridge".to_string(), "f1".to_string()];
+        let telemetry = vec![
+            AuTelemetry {
+                name: "temp".to_string(),
+                value: 4.0.into(),
+                unit: Some("Cel".to_string()),
+                ..Default::default()
+            },
+            AuTelemetry {
+                name: "door".to_string(),
+                value: AuValue::Enum("open".to_string()),
+                ..Default::default()
+            },
+        ];
+        let mut schema = AuTypeSchema::default();
+        schema.telemetry.insert(
+            "door".to_string(),
+            AuTelemetrySchema {
+                value_type: Some("enum".to_string()),
+                values: Some(vec!["open".to_string(), "closed".to_string()]),
+                ..Default::default()
+            },
+        );
+        let td = describe(&path, &telemetry, Some(&schema), false);
+        assert_eq!(td["title"], "fridge/f1");
+        assert_eq!(td["properties"]["temp"]["type"], "number");
+        assert_eq!(td["properties"]["temp"]["unit"], "Cel");
+        assert_eq!(td["properties"]["door"]["type"], "string");
+        assert_eq!(td["properties"]["door"]["enum"], json!(["open", "closed"]));
+        assert_eq!(
+            td["properties"]["temp"]["forms"][0]["href"],
+            "/actor/fridge/f1"
+        );
+        assert_eq!(td["security"], "nosec_sc");
+    }
+}
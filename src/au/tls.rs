@@ -0,0 +1,346 @@
+//! HTTPS serving with mutual-TLS device identity.
+//!
+//! When a `tls` section is configured the server terminates TLS itself.  If the section names a
+//! client CA, devices may present a certificate signed by it.  The certificate's common name and
+//! subject alternative names are the device's identity and are matched against the configured
+//! bindings - ie: `{"subject": "{id}", "path": "/actor/device/{id}"}` lets the device with CN
+//! `thermostat-42` Tell `/actor/device/thermostat-42` and its children and nothing else.
+//!
+//! Tells made with a client certificate are authorized by the bindings alone, requests made
+//! without one are left to bearer token authorization.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use log::{debug, error, info};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
+    RootCertStore, ServerConfig, Session,
+};
+use tokio_rustls::TlsAcceptor;
+use warp::http::Method;
+use warp::path::FullPath;
+use warp::{Filter, Rejection, Reply};
+use x509_parser::extensions::GeneralName;
+
+use crate::au::auth::{prefix_matches, AuAuthError};
+
+/// Maps a certificate name onto the actor path prefix the device may Tell.
+#[derive(Clone, Deserialize)]
+pub struct AuBinding {
+    /// pattern for the CN or a DNS/URI SAN, `{name}` captures part of it, ie: `sensor-{id}`
+    pub subject: String,
+    /// actor path prefix with the captures substituted, ie: `/actor/device/{id}`
+    pub path: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct TlsConfig {
+    /// pem file with the server certificate chain
+    pub cert_file: String,
+    /// pem file with the server private key
+    pub key_file: String,
+    /// pem file with the CAs that sign device certificates, client certificates are not
+    /// requested without it
+    pub client_ca_file: Option<String>,
+    /// refuse connections that present no client certificate
+    #[serde(default)]
+    pub client_auth_required: bool,
+    #[serde(default)]
+    pub bindings: Vec<AuBinding>,
+}
+
+/// The remote address of a TLS connection - warp only knows the addresses of the connections it
+/// accepts itself.
+#[derive(Clone, Copy, Debug)]
+pub struct AuPeer(pub SocketAddr);
+
+/// The names a verified client certificate vouches for - the CN first, then the SANs.
+#[derive(Clone, Debug)]
+pub struct AuClientIdentity {
+    pub names: Vec<String>,
+}
+
+enum Token {
+    Literal(String),
+    Capture(String),
+}
+
+fn tokens(pattern: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = pattern;
+    while let Some(start) = rest.find('{') {
+        match rest[start..].find('}') {
+            Some(len) => {
+                if start > 0 {
+                    tokens.push(Token::Literal(rest[..start].to_string()));
+                }
+                tokens.push(Token::Capture(rest[start + 1..start + len].to_string()));
+                rest = &rest[start + len + 1..];
+            }
+            None => break,
+        }
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Literal(rest.to_string()));
+    }
+    tokens
+}
+
+fn capture(tokens: &[Token], value: &str, captures: &mut HashMap<String, String>) -> bool {
+    match tokens.split_first() {
+        None => value.is_empty(),
+        Some((Token::Literal(l), rest)) => {
+            value.starts_with(l.as_str()) && capture(rest, &value[l.len()..], captures)
+        }
+        Some((Token::Capture(name), rest)) => {
+            let ends = value.char_indices().map(|(i, _)| i).skip(1);
+            for end in ends.chain(Some(value.len())).filter(|end| *end > 0) {
+                let v = &value[..end];
+                // a capture must stay a single literal path segment
+                if v.contains(['/', '*']) {
+                    break;
+                }
+                captures.insert(name.clone(), v.to_string());
+                if capture(rest, &value[end..], captures) {
+                    return true;
+                }
+            }
+            captures.remove(name);
+            false
+        }
+    }
+}
+
+impl AuBinding {
+    /// the actor path prefix granted to a certificate name, if the name matches the subject
+    pub fn grant(&self, name: &str) -> Option<String> {
+        let mut captures = HashMap::new();
+        if !capture(&tokens(&self.subject), name, &mut captures) {
+            return None;
+        }
+        let mut path = String::new();
+        for t in tokens(&self.path) {
+            match t {
+                Token::Literal(l) => path.push_str(&l),
+                Token::Capture(c) => path.push_str(captures.get(&c)?),
+            }
+        }
+        Some(path)
+    }
+}
+
+/// the bindings in force, empty when the server does not terminate TLS
+#[derive(Clone, Default)]
+pub struct AuBindings {
+    bindings: Vec<AuBinding>,
+}
+
+impl AuBindings {
+    pub fn new(config: &Option<TlsConfig>) -> AuBindings {
+        AuBindings {
+            bindings: config
+                .as_ref()
+                .map(|c| c.bindings.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// check that one of the identity's names is bound to a prefix of the actor path
+    pub fn check(&self, identity: &AuClientIdentity, path: &[String]) -> Result<(), AuAuthError> {
+        let granted = identity.names.iter().any(|name| {
+            self.bindings.iter().any(|b| match b.grant(name) {
+                Some(prefix) => prefix_matches(&prefix, path),
+                None => false,
+            })
+        });
+        if granted {
+            Ok(())
+        } else {
+            debug!("{:?} may not tell {:?}", identity.names, path);
+            Err(AuAuthError::Forbidden)
+        }
+    }
+}
+
+/// rejects POSTs to `/actor` paths the client certificate is not bound to
+pub fn guard(bindings: Arc<AuBindings>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::ext::optional::<AuClientIdentity>())
+        .and_then(
+            move |method: Method, path: FullPath, identity: Option<AuClientIdentity>| {
+                let bindings = bindings.clone();
+                async move {
+                    let mut segments = path.as_str().split('/').filter(|s| !s.is_empty());
+                    match identity {
+                        Some(identity)
+                            if method == Method::POST && segments.next() == Some("actor") =>
+                        {
+                            let segments: Vec<String> = segments.map(|s| s.to_string()).collect();
+                            bindings
+                                .check(&identity, &segments)
+                                .map_err(warp::reject::custom)
+                        }
+                        _ => Ok(()),
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+fn server_config(config: &TlsConfig) -> Result<ServerConfig, String> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("can not read {}: {}", path, e))
+    };
+    let verifier = match &config.client_ca_file {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            roots
+                .add_pem_file(&mut open(ca)?)
+                .map_err(|_| format!("invalid client ca {}", ca))?;
+            if config.client_auth_required {
+                AllowAnyAuthenticatedClient::new(roots)
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            }
+        }
+        None => NoClientAuth::new(),
+    };
+    let chain = certs(&mut open(&config.cert_file)?)
+        .map_err(|_| format!("invalid certificate {}", config.cert_file))?;
+    let mut keys = pkcs8_private_keys(&mut open(&config.key_file)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(&config.key_file)?).unwrap_or_default();
+    }
+    let key = keys
+        .pop()
+        .ok_or(format!("no private key in {}", config.key_file))?;
+    let mut server = ServerConfig::new(verifier);
+    server
+        .set_single_cert(chain, key)
+        .map_err(|e| format!("invalid certificate {}: {}", config.cert_file, e))?;
+    Ok(server)
+}
+
+fn identity(der: &[u8]) -> Option<AuClientIdentity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string())
+        .collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            match name {
+                GeneralName::DNSName(n) | GeneralName::URI(n) => names.push(n.to_string()),
+                _ => {}
+            }
+        }
+    }
+    Some(AuClientIdentity { names })
+}
+
+/// blocking call serving the routes over TLS, each request carrying the client's identity
+pub async fn serve<F>(routes: F, addr: impl Into<SocketAddr>, config: &TlsConfig)
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let acceptor = TlsAcceptor::from(Arc::new(server_config(config).unwrap()));
+    let listener = TcpListener::bind(addr.into()).await.unwrap();
+    let service = warp::service(routes);
+    info!("serving https on {}", listener.local_addr().unwrap());
+    loop {
+        let (tcp, peer) = match listener.accept().await {
+            Ok((tcp, addr)) => (tcp, AuPeer(addr)),
+            Err(e) => {
+                error!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(tcp).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("tls handshake failed: {}", e);
+                    return;
+                }
+            };
+            let client = stream
+                .get_ref()
+                .1
+                .get_peer_certificates()
+                .and_then(|chain| chain.first().and_then(|c| identity(&c.0)));
+            let handler = service_fn(move |mut req| {
+                req.extensions_mut().insert(peer);
+                if let Some(client) = &client {
+                    req.extensions_mut().insert(client.clone());
+                }
+                service.clone().call(req)
+            });
+            if let Err(e) = Http::new().serve_connection(stream, handler).await {
+                debug!("connection error: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::tls::*;
+
+    fn binding(subject: &str, path: &str) -> AuBinding {
+        AuBinding {
+            subject: subject.to_string(),
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn grant_works() {
+        let b = binding("{id}", "/actor/device/{id}");
+        assert_eq!(
+            b.grant("thermostat-42"),
+            Some("/actor/device/thermostat-42".to_string())
+        );
+        let b = binding("sensor-{n}.example.com", "/actor/site/a/sensor/{n}");
+        assert_eq!(
+            b.grant("sensor-7.example.com"),
+            Some("/actor/site/a/sensor/7".to_string())
+        );
+        assert_eq!(b.grant("pump-7.example.com"), None);
+        assert_eq!(b.grant("sensor-*.example.com"), None);
+    }
+
+    #[test]
+    fn check_works() {
+        let bindings = AuBindings {
+            bindings: vec![binding("{id}", "/actor/device/{id}")],
+        };
+        let thermostat = AuClientIdentity {
+            names: vec!["thermostat-42".to_string()],
+        };
+        let own: Vec<String> = vec!["device".to_string(), "thermostat-42".to_string()];
+        let other: Vec<String> = vec!["device".to_string(), "thermostat-43".to_string()];
+        assert_eq!(bindings.check(&thermostat, &own), Ok(()));
+        assert_eq!(
+            bindings.check(&thermostat, &other),
+            Err(AuAuthError::Forbidden)
+        );
+    }
+}
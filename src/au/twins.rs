@@ -0,0 +1,575 @@
+//! The twins of an actor space and the `/actor` routes that Tell, Ask and Ls them.
+//!
+//! A twin is addressed by the type/id pairs of its path below `/actor`, ie:
+//! `POST /actor/person/mary/phone/p1` Tells the twin its telemetry, `GET` of the same path Asks
+//! for its state and `GET /actor/person/mary/children` lists the children of a twin.  Every other
+//! route reaches the twins through the `AuTwins` shared with it.
+//!
+//! An Ask blocks its worker thread until the actor answers, so the routes need a multi-thread tokio
+//! runtime.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::RemoteHandle;
+use log::{debug, error, warn};
+use riker::actors::*;
+use riker::system::ActorSystem;
+use riker_patterns::ask::*;
+use tokio::time::error::Elapsed;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::au::actor::AugieActor;
+use crate::au::auth::{AuAuth, AuPermission};
+use crate::au::command::CommandConfig;
+use crate::au::config::AuConfig;
+use crate::au::format::AuFormat;
+use crate::au::geo::AuGeoIndex;
+use crate::au::ingest::{twin_path, AuTellError, MAX_SEGMENTS};
+use crate::au::labels::AuSelector;
+use crate::au::load::AuLoad;
+use crate::au::metrics::ASK_TIMEOUTS;
+use crate::au::model::AuOperator::*;
+use crate::au::model::{AuMsg, AuOperator, AuTelemetry, AuTwin};
+use crate::au::ratelimit::AuRateLimiter;
+use crate::au::schema::AuSchemas;
+use crate::au::signing::AuSigning;
+use crate::au::tls::{AuBindings, AuClientIdentity};
+use crate::au::units::AuConversion;
+
+pub type AuActorRef = ActorRef<AuMsg<Vec<AuTelemetry>>>;
+
+/// The actor space and what the routes share to authorize, Tell and Ask its twins.
+#[derive(Clone)]
+pub struct AuTwins {
+    sys: Arc<Mutex<ActorSystem>>,
+    roots: Arc<Mutex<HashMap<String, AuActorRef, RandomState>>>,
+    pub load: Arc<AuLoad>,
+    pub geo: Arc<AuGeoIndex>,
+    pub auth: Arc<AuAuth>,
+    pub bindings: Arc<AuBindings>,
+    pub signing: Arc<AuSigning>,
+    pub limiter: Arc<AuRateLimiter>,
+    pub schemas: Arc<AuSchemas>,
+    pub ask_timeout: Duration,
+    /// the limits of the commands each twin's actor queues
+    commands: CommandConfig,
+}
+
+impl AuTwins {
+    /// a new actor space for the twins of a config
+    pub fn new(config: &AuConfig) -> Result<AuTwins, String> {
+        let sys = ActorSystem::new().map_err(|e| format!("{:?}", e))?;
+        Ok(AuTwins {
+            sys: Arc::new(Mutex::new(sys)),
+            roots: Arc::new(Mutex::new(HashMap::new())),
+            load: Arc::new(AuLoad::new(&config.load)),
+            geo: Arc::new(AuGeoIndex::new(&config.geo)),
+            auth: Arc::new(AuAuth::new(&config.auth)?),
+            bindings: Arc::new(AuBindings::new(&config.tls)),
+            signing: Arc::new(AuSigning::new(&config.signing)),
+            limiter: Arc::new(AuRateLimiter::new(&config.rate_limit)),
+            schemas: Arc::new(AuSchemas::new(&config.schemas, &config.units)?),
+            ask_timeout: Duration::from_millis(config.ask_timeout_ms),
+            commands: config.commands,
+        })
+    }
+
+    /// the actor of a root, created on first use
+    fn root(&self, root: &str) -> Option<AuActorRef> {
+        let sys = self.sys.lock().unwrap();
+        let mut roots = self.roots.lock().unwrap();
+        if let Some(actor) = roots.get(root) {
+            debug!("found existing root {}", root);
+            return Some(actor.clone());
+        }
+        debug!("creating root {}", root);
+        let props = AugieActor::props(
+            self.load.clone(),
+            self.geo.clone(),
+            self.commands,
+            root.to_string(),
+        );
+        match sys.actor_of(props, root) {
+            Ok(actor) => {
+                roots.insert(root.to_string(), actor.clone());
+                Some(actor)
+            }
+            Err(e) => {
+                error!("cannot create root {}: {:?}", root, e);
+                None
+            }
+        }
+    }
+
+    /// the actor of a root if it has one, no actor is created for an unknown root
+    pub fn live_root(&self, root: &str) -> Option<AuActorRef> {
+        self.roots.lock().unwrap().get(root).cloned()
+    }
+
+    /// the names of the roots
+    pub fn root_names(&self) -> Vec<String> {
+        self.roots.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Tell the twin at a full path its telemetry once it meets the schemas
+    fn tell_path(
+        &self,
+        path: Vec<String>,
+        mut data: Vec<AuTelemetry>,
+    ) -> Result<String, AuTellError> {
+        let mut path = safe_path(path);
+        let errors = self.schemas.check(&path, &mut data);
+        if !errors.is_empty() {
+            debug!("refusing {} {:?}: {:?}", Tell, path, errors);
+            return Err(AuTellError::Invalid(errors));
+        }
+        let root = path.remove(0);
+        debug!("handling {} {} {:?}", Tell, root, path);
+        let (actor, _) = self.admit(root, &path)?;
+        let aumsg: AuMsg<Vec<AuTelemetry>> = AuMsg {
+            data: Some(data),
+            op: Tell,
+            path,
+        };
+        actor.tell(aumsg, None);
+        Ok(String::from("Accepted"))
+    }
+
+    /// count a Tell to the twin at a path below a root as pending, answering the root actor to
+    /// send it to and the key to release it by
+    fn admit(&self, root: String, path: &[String]) -> Result<(AuActorRef, String), AuTellError> {
+        let mut key = root.clone();
+        for x in path.iter() {
+            key.push('/');
+            key.push_str(x);
+        }
+        if let Err(e) = self.load.admit(&key) {
+            debug!("refusing {} {}: {:?}", Tell, key, e);
+            return Err(e.into());
+        }
+        match self.root(&root) {
+            Some(actor) => Ok((actor, key)),
+            None => {
+                self.load.done(&key);
+                Err(AuTellError::Failed(format!("no actor for {}", root)))
+            }
+        }
+    }
+
+    /// Tell each twin of a batch its telemetry - one Tell per actor, none unless every record
+    /// of the batch is valid and every twin may take another Tell.  a caller wanting a status per
+    /// twin, ie: `/batch` or a bridge, Tells its twins one by one.
+    pub fn tell_twins(&self, twins: Vec<AuTwin>) -> Result<String, AuTellError> {
+        let mut twins: Vec<AuTwin> = twins
+            .into_iter()
+            .map(|(path, data)| (safe_path(path), data))
+            .collect();
+        let mut errors = Vec::new();
+        for (path, telemetry) in twins.iter_mut() {
+            errors.extend(self.schemas.check(path, telemetry));
+        }
+        if !errors.is_empty() {
+            return Err(AuTellError::Invalid(errors));
+        }
+        let mut admitted = Vec::new();
+        for (mut path, data) in twins {
+            let root = path.remove(0);
+            match self.admit(root, &path) {
+                Ok((actor, key)) => admitted.push((actor, key, path, data)),
+                Err(e) => {
+                    for (_, key, _, _) in admitted.iter() {
+                        self.load.done(key);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        for (actor, _, path, data) in admitted {
+            let aumsg: AuMsg<Vec<AuTelemetry>> = AuMsg {
+                data: Some(data),
+                op: Tell,
+                path,
+            };
+            actor.tell(aumsg, None);
+        }
+        Ok(String::from("Accepted"))
+    }
+
+    /// Tell the twins of a bridge carrying no signature, ie: `mqtt`, dropping the telemetry of
+    /// twins with a secret.  a bridge has no client to retry, so each twin is Told on its own and
+    /// one overloaded or invalid twin drops only its own telemetry.
+    pub fn tell_unsigned(&self, source: &str, twins: Vec<AuTwin>) {
+        for (path, telemetry) in twins {
+            let name = path.join("/");
+            if self.signing.secured(&path) {
+                warn!(
+                    "dropping {} telemetry of /actor/{}: the twin has a secret",
+                    source, name
+                );
+                continue;
+            }
+            if let Err(e) = self.tell_twins(vec![(path, telemetry)]) {
+                warn!("dropping {} telemetry of /actor/{}: {:?}", source, name, e);
+            }
+        }
+    }
+
+    /// Tell each twin of a decoded batch once the client is allowed to Tell them all
+    pub fn tell(
+        &self,
+        twins: Vec<AuTwin>,
+        header: Option<String>,
+        identity: Option<AuClientIdentity>,
+    ) -> Result<Result<String, AuTellError>, Rejection> {
+        crate::au::ingest::authorize(
+            &self.auth,
+            &self.bindings,
+            header.as_deref(),
+            identity.as_ref(),
+            &twins,
+        )
+        .map_err(warp::reject::custom)?;
+        Ok(self.tell_twins(twins))
+    }
+
+    /// Tell a twin, retrying while the actors are overloaded until `wait` has passed
+    pub async fn tell_waiting(&self, twin: AuTwin, wait: Duration) -> Result<String, AuTellError> {
+        let deadline = tokio::time::Instant::now() + wait;
+        let mut backoff = Duration::from_millis(10);
+        loop {
+            let result = self.tell_twins(vec![twin.clone()]);
+            let now = tokio::time::Instant::now();
+            match result {
+                Err(AuTellError::Overloaded(_)) if now < deadline => {
+                    tokio::time::sleep(backoff.min(deadline - now)).await;
+                    backoff = (backoff * 2).min(Duration::from_secs(1));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// check the client may apply an operation to the twin at a full path.  operators set
+    /// desired properties and enqueue commands with a bearer token granting `operate`, which a
+    /// device's `tell` does not.  a device reads and writes the rest with its certificate binding
+    /// or its bearer token, which must grant `ask` for the reads and `tell` for the writes.
+    pub fn authorize(
+        &self,
+        op: &AuOperator,
+        path: &[String],
+        credentials: &(Option<String>, Option<AuClientIdentity>),
+    ) -> Result<(), Rejection> {
+        let (header, identity) = credentials;
+        match (op, identity) {
+            (Desire | Command, _) => {
+                self.auth
+                    .check(header.as_deref(), AuPermission::Operate, path)
+            }
+            (Shadow | Commands, Some(identity)) => self.bindings.check(identity, path),
+            (Shadow | Commands, None) => {
+                self.auth.check(header.as_deref(), AuPermission::Ask, path)
+            }
+            _ => crate::au::ingest::authorize_path(
+                &self.auth,
+                &self.bindings,
+                header.as_deref(),
+                identity.as_ref(),
+                path,
+            ),
+        }
+        .map_err(warp::reject::custom)
+    }
+
+    /// apply an operation to the twin at a full path and answer the records of its actor, or
+    /// `None` if it has no actor
+    pub fn ask_twin(
+        &self,
+        path: Vec<String>,
+        op: AuOperator,
+        data: Option<Vec<AuTelemetry>>,
+    ) -> Result<Option<Vec<AuTelemetry>>, Elapsed> {
+        let mut path = safe_path(path);
+        let root = path.remove(0);
+        debug!("handling {} {} {:?}", op, root, path);
+        let actor = match self.root(&root) {
+            Some(actor) => actor,
+            None => return Ok(None),
+        };
+        let aumsg: AuMsg<Vec<AuTelemetry>> = AuMsg { data, op, path };
+        let res: RemoteHandle<AuMsg<Vec<AuTelemetry>>> =
+            ask(self.sys.lock().unwrap().deref(), &actor, aumsg);
+        Ok(await_answer(res, self.ask_timeout, "ask")?.data)
+    }
+
+    /// apply an operation to the twin at a full path and answer the records of its actor
+    pub fn ask(
+        &self,
+        path: Vec<String>,
+        op: AuOperator,
+        data: Option<Vec<AuTelemetry>>,
+    ) -> Result<Vec<AuTelemetry>, Elapsed> {
+        self.ask_twin(path, op, data).map(Option::unwrap_or_default)
+    }
+
+    /// the names of the children of the twin at a full path, `None` if its actor did not answer
+    pub fn ls(&self, path: Vec<String>) -> Option<Vec<String>> {
+        let mut path = safe_path(path);
+        let root = path.remove(0);
+        debug!("handling {} {} {:?}", Ls, root, path);
+        let actor = self.root(&root)?;
+        self.ask_path(&actor, Ls, path).ok().map(|m| m.path)
+    }
+
+    /// ask the actor at a path below a root actor, creating none on the way
+    pub fn ask_path(
+        &self,
+        actor: &AuActorRef,
+        op: AuOperator,
+        path: Vec<String>,
+    ) -> Result<AuMsg<Vec<AuTelemetry>>, Elapsed> {
+        let label = match op {
+            Ls => "ls",
+            Track => "track",
+            _ => "ask",
+        };
+        let aumsg: AuMsg<Vec<AuTelemetry>> = AuMsg {
+            data: None,
+            op,
+            path,
+        };
+        let res: RemoteHandle<AuMsg<Vec<AuTelemetry>>> =
+            ask(self.sys.lock().unwrap().deref(), actor, aumsg);
+        await_answer(res, self.ask_timeout, label)
+    }
+
+    /// the full path and telemetry of every twin at or below the path, ie: `[person]`.  the
+    /// walk follows the children actors report so no actor is created for an unknown path.
+    pub fn export(&self, prefix: Vec<String>) -> Result<Vec<AuTwin>, Elapsed> {
+        let prefix = safe_path(prefix);
+        let actors: Vec<(String, AuActorRef)> = {
+            let roots = self.roots.lock().unwrap();
+            match prefix.first() {
+                Some(root) => roots
+                    .get(root)
+                    .map(|a| vec![(root.clone(), a.clone())])
+                    .unwrap_or_default(),
+                None => roots.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            }
+        };
+        let mut twins = Vec::new();
+        'roots: for (root, actor) in actors {
+            let mut path: Vec<String> = Vec::new();
+            for segment in prefix.iter().skip(1) {
+                let children = self.ask_path(&actor, Ls, path.clone())?.path;
+                if !children.contains(segment) {
+                    continue 'roots;
+                }
+                path.push(segment.clone());
+            }
+            self.export_walk(&actor, &root, path, &mut twins)?;
+        }
+        Ok(twins)
+    }
+
+    fn export_walk(
+        &self,
+        actor: &AuActorRef,
+        root: &str,
+        path: Vec<String>,
+        twins: &mut Vec<AuTwin>,
+    ) -> Result<(), Elapsed> {
+        // twins are at odd depths below their root with their type actors between them
+        if path.len() % 2 == 1 {
+            let data = self.ask_path(actor, Ask, path.clone())?.data;
+            if let Some(data) = data.filter(|d| !d.is_empty()) {
+                let mut full = vec![root.to_string()];
+                full.extend(path.iter().cloned());
+                twins.push((full, data));
+            }
+        }
+        for child in self.ask_path(actor, Ls, path.clone())?.path {
+            let mut child_path = path.clone();
+            child_path.push(child);
+            self.export_walk(actor, root, child_path, twins)?;
+        }
+        Ok(())
+    }
+}
+
+/// a full path as actors are named, every segment lowercased - its root too, so that a root is
+/// looked up and its Tells counted under one name however it was posted
+fn safe_path(path: Vec<String>) -> Vec<String> {
+    path.into_iter().map(|x| x.to_lowercase()).collect()
+}
+
+/// block until an actor answers or the timeout elapses.  waiting in place panics on a
+/// `current_thread` runtime, so the routes must be served by a multi-thread one, ie:
+/// `#[tokio::main]` or `#[tokio::test(flavor = "multi_thread")]`.
+fn await_answer(
+    res: RemoteHandle<AuMsg<Vec<AuTelemetry>>>,
+    timeout: Duration,
+    op: &str,
+) -> Result<AuMsg<Vec<AuTelemetry>>, Elapsed> {
+    // let the runtime move its other tasks off this worker while it waits
+    let response = tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(tokio::time::timeout(timeout, res))
+    });
+    if response.is_err() {
+        debug!("{} unanswered after {:?}", op, timeout);
+        ASK_TIMEOUTS.with_label_values(&[op]).inc();
+    }
+    response
+}
+
+/// the reply to a Tell - 202 once the Tell is accepted, 503 while the actors are overloaded and
+/// 422 describing the records refused
+pub fn tell_reply(result: Result<String, AuTellError>) -> Response {
+    match result {
+        Ok(reply) => warp::reply::with_status(reply, StatusCode::ACCEPTED).into_response(),
+        Err(AuTellError::Invalid(errors)) => {
+            warp::reply::with_status(warp::reply::json(&errors), StatusCode::UNPROCESSABLE_ENTITY)
+                .into_response()
+        }
+        Err(AuTellError::Failed(e)) => {
+            warp::reply::with_status(e, StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        }
+        Err(AuTellError::Overloaded(e)) => warp::reply::with_header(
+            warp::reply::with_status(
+                format!("Overloaded: {:?}", e),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            "Retry-After",
+            "1",
+        )
+        .into_response(),
+    }
+}
+
+/// the reply to an Ask that timed out - 504
+pub fn timeout_reply() -> Response {
+    warp::reply::with_status("Timeout", StatusCode::GATEWAY_TIMEOUT).into_response()
+}
+
+/// the reply to a shadow or command request - the shadow, its delta or the commands concerned in
+/// the accepted format or a 504 if the twin's actor did not answer
+pub fn twin_reply<T: serde::Serialize>(result: Result<T, Elapsed>, format: AuFormat) -> Response {
+    match result {
+        Ok(reply) => format.reply(&reply),
+        Err(_) => timeout_reply(),
+    }
+}
+
+/// the records of an Ask the label selector matches
+fn select(
+    result: Result<Option<Vec<AuTelemetry>>, Elapsed>,
+    selector: AuSelector,
+) -> Result<Option<Vec<AuTelemetry>>, Elapsed> {
+    result.map(|reply| reply.map(|telemetry| selector.select(telemetry)))
+}
+
+/// the records of an Ask in the requested units
+fn convert(
+    result: Result<Option<Vec<AuTelemetry>>, Elapsed>,
+    conversion: AuConversion,
+) -> Result<Option<Vec<AuTelemetry>>, Elapsed> {
+    result.map(|reply| reply.map(|telemetry| conversion.convert(telemetry)))
+}
+
+/// the reply to an Ask - the twin's telemetry in the accepted format or a 504 if its actor did
+/// not answer in time
+pub fn ask_reply(result: Result<Option<Vec<AuTelemetry>>, Elapsed>, format: AuFormat) -> Response {
+    match result {
+        Ok(reply) => format.reply(&reply),
+        Err(_) => timeout_reply(),
+    }
+}
+
+/// the reply to an Ls - the names of the twin's children in the accepted format or a 504 if its
+/// actor did not answer
+fn ls_reply(result: Option<Vec<String>>, format: AuFormat) -> Response {
+    match result {
+        Some(reply) => format.reply(&reply),
+        None => timeout_reply(),
+    }
+}
+
+/// the segments of a path below `/actor`, at most five type/id pairs
+fn segments() -> impl Filter<Extract = (Vec<String>,), Error = Rejection> + Clone {
+    warp::path("actor")
+        .and(warp::path::tail())
+        .and_then(|tail: warp::path::Tail| async move {
+            let segments: Vec<String> = tail
+                .as_str()
+                .split('/')
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect();
+            if segments.len() > MAX_SEGMENTS + 1 {
+                return Err(warp::reject::not_found());
+            }
+            Ok(segments)
+        })
+}
+
+/// the path of a twin, ie: `person/mary` but not `person`
+fn twin(segments: Vec<String>) -> Result<Vec<String>, Rejection> {
+    twin_path(&format!("/actor/{}", segments.join("/"))).ok_or_else(warp::reject::not_found)
+}
+
+/// the `/actor` routes - Tell and Ask twins and Ls their children
+pub fn routes(
+    twins: &AuTwins,
+    config: &AuConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let twins_tell = twins.clone();
+    let tell = segments()
+        .and_then(|path: Vec<String>| async move { twin(path) })
+        .and(warp::post())
+        .and(crate::au::body::telemetry(
+            twins.signing.clone(),
+            config.compression,
+        ))
+        .map(move |path: Vec<String>, telemetry: Vec<AuTelemetry>| {
+            twins_tell.tell_path(path, telemetry)
+        })
+        .map(tell_reply);
+
+    let twins_ls = twins.clone();
+    let ls = segments()
+        .and_then(|mut path: Vec<String>| async move {
+            match path.pop() {
+                Some(last) if last == "children" => Ok(path),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .and(warp::get())
+        .map(move |path: Vec<String>| {
+            if path.is_empty() {
+                Some(twins_ls.root_names())
+            } else {
+                twins_ls.ls(path)
+            }
+        })
+        .and(crate::au::format::accept())
+        .map(ls_reply);
+
+    let twins_ask = twins.clone();
+    let ask = segments()
+        .and_then(|path: Vec<String>| async move { twin(path) })
+        .and(warp::get())
+        .map(move |path: Vec<String>| twins_ask.ask_twin(path, Ask, None))
+        .and(crate::au::labels::selector())
+        .map(select)
+        .and(crate::au::units::conversion())
+        .map(convert)
+        .and(crate::au::format::accept())
+        .map(ask_reply);
+
+    tell.or(ls).unify().or(ask).unify()
+}
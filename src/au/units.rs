@@ -0,0 +1,310 @@
+//! Units of measure of telemetry, written as UCUM codes, ie: `Cel`, `[degF]`, `kPa` or `km/h`.
+//!
+//! A twin type may declare the unit of its telemetry names:
+//!
+//!   `{"types": {"refrigerator": {"temp": "Cel"}}}`
+//!
+//! A Told record of a declared name is given the declared unit when it has none, and is refused
+//! when its unit measures another dimension - a temperature posted in `kPa`.  Records keep the
+//! value and unit they were posted with.  An Ask converts each numeric record to the unit of
+//! its dimension listed in the `unit` parameter, ie: `GET /actor/refrigerator/r1?unit=[degF]`.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use warp::{Filter, Rejection};
+
+use crate::au::ingest::AuRecordError;
+use crate::au::model::{AuTelemetry, AuValue};
+
+/// The `unit` parameter of an Ask names an unknown unit - 400.
+#[derive(Debug)]
+pub struct AuBadUnit(pub String);
+
+impl warp::reject::Reject for AuBadUnit {}
+
+/// A unit measures a dimension, its values `factor * value + offset` in the base unit.
+#[derive(Debug, PartialEq)]
+pub struct AuUnit {
+    pub code: &'static str,
+    pub dimension: &'static str,
+    factor: f64,
+    offset: f64,
+}
+
+const fn unit(code: &'static str, dimension: &'static str, factor: f64) -> AuUnit {
+    AuUnit {
+        code,
+        dimension,
+        factor,
+        offset: 0.0,
+    }
+}
+
+/// the units known, the first of each dimension its base
+const UNITS: &[AuUnit] = &[
+    unit("K", "temperature", 1.0),
+    AuUnit {
+        code: "Cel",
+        dimension: "temperature",
+        factor: 1.0,
+        offset: 273.15,
+    },
+    AuUnit {
+        code: "[degF]",
+        dimension: "temperature",
+        factor: 5.0 / 9.0,
+        offset: 273.15 - 32.0 * 5.0 / 9.0,
+    },
+    unit("m", "length", 1.0),
+    unit("km", "length", 1_000.0),
+    unit("cm", "length", 0.01),
+    unit("mm", "length", 0.001),
+    unit("[in_i]", "length", 0.0254),
+    unit("[ft_i]", "length", 0.3048),
+    unit("[mi_i]", "length", 1_609.344),
+    unit("kg", "mass", 1.0),
+    unit("g", "mass", 0.001),
+    unit("mg", "mass", 0.000_001),
+    unit("t", "mass", 1_000.0),
+    unit("[lb_av]", "mass", 0.453_592_37),
+    unit("s", "time", 1.0),
+    unit("ms", "time", 0.001),
+    unit("min", "time", 60.0),
+    unit("h", "time", 3_600.0),
+    unit("d", "time", 86_400.0),
+    unit("Pa", "pressure", 1.0),
+    unit("hPa", "pressure", 100.0),
+    unit("kPa", "pressure", 1_000.0),
+    unit("bar", "pressure", 100_000.0),
+    unit("mbar", "pressure", 100.0),
+    unit("[psi]", "pressure", 6_894.757_293_168),
+    unit("m/s", "speed", 1.0),
+    unit("km/h", "speed", 1_000.0 / 3_600.0),
+    unit("[mi_i]/h", "speed", 1_609.344 / 3_600.0),
+    unit("J", "energy", 1.0),
+    unit("kJ", "energy", 1_000.0),
+    unit("W.h", "energy", 3_600.0),
+    unit("kW.h", "energy", 3_600_000.0),
+    unit("W", "power", 1.0),
+    unit("kW", "power", 1_000.0),
+    unit("m3", "volume", 1.0),
+    unit("l", "volume", 0.001),
+    unit("L", "volume", 0.001),
+    unit("ml", "volume", 0.000_001),
+    unit("mL", "volume", 0.000_001),
+    unit("V", "voltage", 1.0),
+    unit("mV", "voltage", 0.001),
+    unit("A", "current", 1.0),
+    unit("mA", "current", 0.001),
+    unit("Hz", "frequency", 1.0),
+    unit("kHz", "frequency", 1_000.0),
+    unit("1", "ratio", 1.0),
+    unit("%", "ratio", 0.01),
+    unit("[ppm]", "ratio", 0.000_001),
+];
+
+/// the unit of a UCUM code - codes are case sensitive
+pub fn lookup(code: &str) -> Option<&'static AuUnit> {
+    UNITS.iter().find(|u| u.code == code)
+}
+
+/// a value in one unit expressed in another of the same dimension
+pub fn convert(value: f64, from: &AuUnit, to: &AuUnit) -> Result<f64, String> {
+    if from.dimension != to.dimension {
+        return Err(format!(
+            "{} is a {}, {} a {}",
+            from.code, from.dimension, to.code, to.dimension
+        ));
+    }
+    Ok((value * from.factor + from.offset - to.offset) / to.factor)
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UnitsConfig {
+    /// twin types and the units of their telemetry names, ie: `refrigerator: {temp: Cel}`
+    pub types: HashMap<String, HashMap<String, String>>,
+}
+
+/// The units declared for each twin type.
+pub struct AuUnits {
+    types: HashMap<String, HashMap<String, &'static AuUnit>>,
+}
+
+impl AuUnits {
+    pub fn new(config: &UnitsConfig) -> Result<AuUnits, String> {
+        let mut types = HashMap::new();
+        for (typ, names) in config.types.iter() {
+            let mut units = HashMap::new();
+            for (name, code) in names.iter() {
+                let unit = lookup(code).ok_or_else(|| {
+                    format!("unknown unit {} declared for {}.{}", code, typ, name)
+                })?;
+                units.insert(name.clone(), unit);
+            }
+            types.insert(typ.to_lowercase(), units);
+        }
+        Ok(AuUnits { types })
+    }
+
+    /// give the records of a twin their declared units, describing those whose unit is unknown,
+    /// of another dimension or attached to a value that is not a number
+    pub fn check(&self, path: &[String], telemetry: &mut [AuTelemetry]) -> Vec<AuRecordError> {
+        // a twin's type precedes its id
+        let declared = path
+            .len()
+            .checked_sub(2)
+            .and_then(|i| self.types.get(&path[i]));
+        let mut errors = Vec::new();
+        for (index, t) in telemetry.iter_mut().enumerate() {
+            let expected = declared.and_then(|units| units.get(&t.name));
+            let error = match (t.unit.as_deref(), expected) {
+                (None, Some(expected)) => {
+                    t.unit = Some(expected.code.to_string());
+                    None
+                }
+                (None, None) => None,
+                (Some(code), expected) => match lookup(code) {
+                    None => Some(format!("unknown unit {}", code)),
+                    Some(_) if t.value.as_f64().is_none() => {
+                        Some(format!("a {} has no unit", t.value.type_name()))
+                    }
+                    Some(unit) => expected
+                        .filter(|e| e.dimension != unit.dimension)
+                        .map(|e| format!("{} is not a {} like {}", code, e.dimension, e.code)),
+                },
+            };
+            if let Some(error) = error {
+                errors.push(AuRecordError::new(path, index, &t.name, error));
+            }
+        }
+        errors
+    }
+}
+
+/// The units an Ask's numeric records are converted to, one per dimension.
+#[derive(Clone, Debug, Default)]
+pub struct AuConversion {
+    units: Vec<&'static AuUnit>,
+}
+
+impl AuConversion {
+    pub fn parse(units: &str) -> Result<AuConversion, String> {
+        let mut conversion = AuConversion::default();
+        for code in units.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            let unit = lookup(code).ok_or_else(|| format!("unknown unit {}", code))?;
+            if conversion
+                .units
+                .iter()
+                .any(|u| u.dimension == unit.dimension)
+            {
+                return Err(format!("more than one {} unit", unit.dimension));
+            }
+            conversion.units.push(unit);
+        }
+        Ok(conversion)
+    }
+
+    /// the records in the requested units, those of other dimensions as they are
+    pub fn convert(&self, telemetry: Vec<AuTelemetry>) -> Vec<AuTelemetry> {
+        telemetry
+            .into_iter()
+            .map(|mut t| {
+                let from = t.unit.as_deref().and_then(lookup);
+                let to = from.and_then(|f| self.units.iter().find(|u| u.dimension == f.dimension));
+                if let (Some(from), Some(to), Some(value)) = (from, to, t.value.as_f64()) {
+                    if from != *to {
+                        if let Ok(converted) = convert(value, from, to) {
+                            t.value = AuValue::Number(converted);
+                            t.unit = Some(to.code.to_string());
+                        }
+                    }
+                }
+                t
+            })
+            .collect()
+    }
+}
+
+/// the conversion of the `unit` query parameter, converting nothing without one
+pub fn conversion() -> impl Filter<Extract = (AuConversion,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().and_then(|query: HashMap<String, String>| async move {
+        AuConversion::parse(query.get("unit").map(String::as_str).unwrap_or_default())
+            .map_err(|e| warp::reject::custom(AuBadUnit(e)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::au::units::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn convert_works() {
+        let celsius = lookup("Cel").unwrap();
+        let fahrenheit = lookup("[degF]").unwrap();
+        assert!(close(convert(100.0, celsius, fahrenheit).unwrap(), 212.0));
+        assert!(close(convert(32.0, fahrenheit, celsius).unwrap(), 0.0));
+        assert!(close(
+            convert(90.0, lookup("km/h").unwrap(), lookup("m/s").unwrap()).unwrap(),
+            25.0
+        ));
+        assert!(convert(1.0, celsius, lookup("kPa").unwrap()).is_err());
+        assert!(lookup("cel").is_none());
+    }
+
+    #[test]
+    fn check_works() {
+        let config: UnitsConfig =
+            serde_json::from_str(r#"{"types": {"fridge": {"temp": "Cel"}}}"#).unwrap();
+        let units = AuUnits::new(&config).unwrap();
+        let path = vec!["fridge".to_string(), "f1".to_string()];
+        let at = |name: &str, value: AuValue, unit: Option<&str>| AuTelemetry {
+            name: name.to_string(),
+            value,
+            unit: unit.map(str::to_string),
+            ..Default::default()
+        };
+        let mut telemetry = vec![
+            at("temp", 4.0.into(), None),
+            at("temp", 39.0.into(), Some("[degF]")),
+            at("temp", 1.0.into(), Some("kPa")),
+            at("door", AuValue::Enum("open".to_string()), Some("m")),
+            at("rpm", 1.0.into(), Some("rpm")),
+        ];
+        let errors = units.check(&path, &mut telemetry);
+        assert_eq!(telemetry[0].unit.as_deref(), Some("Cel"));
+        let indices: Vec<usize> = errors.iter().map(|e| e.index).collect();
+        assert_eq!(indices, vec![2, 3, 4]);
+        assert!(AuUnits::new(
+            &serde_json::from_str(r#"{"types": {"fridge": {"temp": "C"}}}"#).unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn conversion_works() {
+        let conversion = AuConversion::parse("[degF], kPa").unwrap();
+        let telemetry = conversion.convert(vec![
+            AuTelemetry {
+                value: 100.0.into(),
+                unit: Some("Cel".to_string()),
+                ..Default::default()
+            },
+            AuTelemetry {
+                value: 5.0.into(),
+                unit: Some("m".to_string()),
+                ..Default::default()
+            },
+        ]);
+        assert!(close(telemetry[0].value.as_f64().unwrap(), 212.0));
+        assert_eq!(telemetry[0].unit.as_deref(), Some("[degF]"));
+        assert_eq!(telemetry[1].value, 5.0);
+        assert!(AuConversion::parse("Cel,K").is_err());
+        assert!(AuConversion::parse("furlong").is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! # Authorization Tests
+//!
+//! Starts up an Augorama space with bearer tokens configured and checks that `/actor` requests
+//! are only accepted when a rule grants them.
+//!
+extern crate augorama;
+
+use std::{env, fs, thread, time};
+
+use reqwest::StatusCode;
+
+#[test]
+fn actor_auth_works() {
+    let config = env::temp_dir().join("augorama_auth_test.json");
+    fs::write(
+        &config,
+        r#"{"auth": {
+            "tokens": {"device-token": "device"},
+            "rules": [{"principal": "device", "path": "/actor/person/*", "ops": ["tell"]}]
+        }}"#,
+    )
+    .unwrap();
+    env::set_var("AUGORAMA_CONFIG", &config);
+
+    thread::spawn(augorama::serve);
+    let one_second = time::Duration::from_millis(1000);
+    thread::sleep(one_second);
+
+    let client = reqwest::Client::new();
+    let body = r#"[{"name": "my.name", "value": 1.3, "datetime": "2019-10-06T13:20:16Z"}]"#;
+
+    let anonymous = client
+        .post("http://localhost:3030/actor/person/Mary")
+        .body(body)
+        .send()
+        .unwrap();
+    assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+
+    let granted = client
+        .post("http://localhost:3030/actor/person/Mary")
+        .header("Authorization", "Bearer device-token")
+        .body(body)
+        .send()
+        .unwrap();
+    assert_eq!(granted.status(), StatusCode::ACCEPTED);
+
+    let denied = client
+        .get("http://localhost:3030/actor/person/Mary")
+        .header("Authorization", "Bearer device-token")
+        .send()
+        .unwrap();
+    assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+}
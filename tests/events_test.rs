@@ -0,0 +1,62 @@
+//! # Command Event Tests
+//!
+//! Subscribes to the commands of a twin with `Accept-Encoding` set and checks that each event is
+//! streamed as it is enqueued rather than held back to be compressed.
+//!
+extern crate augorama;
+
+use std::time::Duration;
+
+use augorama::au::config::AuConfig;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// read from the stream until `needle` was read, failing after a few seconds
+async fn read_until(stream: &mut TcpStream, read: &mut String, needle: &str) {
+    let reading = async {
+        let mut buf = [0u8; 4096];
+        while !read.contains(needle) {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "stream closed");
+            read.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+    };
+    if tokio::time::timeout(Duration::from_secs(5), reading)
+        .await
+        .is_err()
+    {
+        panic!("{:?} not streamed, read {:?}", needle, read);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn command_events_stream_uncompressed() {
+    let routes = augorama::routes(&AuConfig::default()).unwrap();
+    let (addr, server) = warp::serve(routes.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            b"GET /commands/events?path=/actor/device/d1 HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Accept-Encoding: gzip, zstd\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut read = String::new();
+    read_until(&mut stream, &mut read, "\r\n\r\n").await;
+    assert!(read.starts_with("HTTP/1.1 200"));
+    assert!(read.contains("text/event-stream"));
+    assert!(!read.to_lowercase().contains("content-encoding"));
+
+    let enqueued = warp::test::request()
+        .method("POST")
+        .path("/commands?path=/actor/device/d1")
+        .body(r#"{"name": "reboot"}"#)
+        .reply(&routes)
+        .await;
+    assert_eq!(enqueued.status(), 201);
+    read_until(&mut stream, &mut read, "event:command").await;
+    assert!(read.contains("reboot"));
+}
@@ -0,0 +1,166 @@
+//! # MQTT Bridge Tests
+//!
+//! Publishes to a minimal in-process MQTT 3.1.1 broker bridged onto an actor space and Asks the
+//! twin the topic is routed to.  Run with `cargo test --features mqtt`.
+//!
+#![cfg(feature = "mqtt")]
+
+extern crate augorama;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use augorama::au::config::AuConfig;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+type Subscribers = Arc<Mutex<Vec<UnboundedSender<Vec<u8>>>>>;
+
+/// an mqtt packet of its first byte and body
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+/// a publish of qos 0
+fn publish(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(payload);
+    packet(0x30, &body)
+}
+
+/// the first byte and body of the next packet of a client, `None` once it hangs up
+async fn read_packet(reader: &mut OwnedReadHalf) -> Option<(u8, Vec<u8>)> {
+    let header = reader.read_u8().await.ok()?;
+    let mut len = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = reader.read_u8().await.ok()?;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await.ok()?;
+    Some((header, body))
+}
+
+/// serve a client of the broker - acknowledge its connect, subscribes and pings and forward each
+/// of its publishes to every subscriber
+async fn serve_client(stream: TcpStream, subscribers: Subscribers) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut outgoing) = unbounded_channel::<Vec<u8>>();
+    tokio::spawn(async move {
+        while let Some(packet) = outgoing.recv().await {
+            if writer.write_all(&packet).await.is_err() {
+                break;
+            }
+        }
+    });
+    while let Some((header, body)) = read_packet(&mut reader).await {
+        match header >> 4 {
+            // connect
+            1 => {
+                let _ = sender.send(packet(0x20, &[0, 0]));
+            }
+            // publish
+            3 => {
+                for subscriber in subscribers.lock().unwrap().iter() {
+                    let _ = subscriber.send(packet(header, &body));
+                }
+            }
+            // subscribe - grant qos 0 to each filter
+            8 => {
+                let mut suback = body[..2].to_vec();
+                let mut rest = &body[2..];
+                while rest.len() > 2 {
+                    let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    suback.push(0);
+                    rest = &rest[(2 + len + 1).min(rest.len())..];
+                }
+                subscribers.lock().unwrap().push(sender.clone());
+                let _ = sender.send(packet(0x90, &suback));
+            }
+            // pingreq
+            12 => {
+                let _ = sender.send(packet(0xd0, &[]));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mqtt_bridge_works() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let subscribers: Subscribers = Arc::new(Mutex::new(Vec::new()));
+    let broker = subscribers.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve_client(stream, broker.clone()));
+        }
+    });
+
+    let config = AuConfig::from_json(&format!(
+        r#"{{"mqtt": {{
+            "host": "127.0.0.1",
+            "port": {},
+            "routes": [{{"filter": "site/+/temp", "path": "/actor/site/{{1}}"}}]
+        }}}}"#,
+        port
+    ))
+    .unwrap();
+    let routes = augorama::routes(&config).unwrap();
+
+    let subscribed = async {
+        while subscribers.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), subscribed)
+        .await
+        .expect("the bridge did not subscribe");
+
+    let mut publisher = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    publisher
+        .write_all(&publish("site/north/temp", b"21.5"))
+        .await
+        .unwrap();
+
+    let told = async {
+        loop {
+            let answer = warp::test::request()
+                .path("/actor/site/north")
+                .reply(&routes)
+                .await;
+            let body = String::from_utf8_lossy(answer.body()).to_string();
+            if body.contains(r#""name":"temp""#) {
+                return body;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    let body = tokio::time::timeout(Duration::from_secs(5), told)
+        .await
+        .expect("the twin was not Told the published temp");
+    assert!(body.contains("21.5"));
+}